@@ -0,0 +1,295 @@
+use crate::listener::Listener;
+use socket2::Socket;
+use std::env;
+use std::ffi::OsString;
+use std::io;
+use std::ops::Range;
+use std::os::fd::OwnedFd;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::UnixListener;
+use std::os::unix::process::CommandExt;
+use std::process::{Child, Command};
+
+// First inherited descriptor, see sd_listen_fds(3)
+pub const LISTEN_FDS_START: RawFd = 3;
+
+// Takes ownership of the listening sockets passed in by systemd socket
+// activation or by a parent doing a handoff. Empty when started normally
+// or when the variables were meant for another process.
+//
+// The variables are left in place rather than removed, changing the
+// environment isn't safe once other threads run. Processes spawned later
+// ignore them as LISTEN_PID names this one.
+pub fn listen_fds() -> io::Result<Vec<Listener>> {
+    let (Ok(count), Ok(pid)) = (env::var("LISTEN_FDS"), env::var("LISTEN_PID")) else {
+        return Ok(Vec::new());
+    };
+
+    let pid = pid
+        .parse::<u32>()
+        .map_err(|e| invalid_input(format!("Invalid LISTEN_PID {}: {}", pid, e)))?;
+
+    if pid != std::process::id() {
+        return Ok(Vec::new());
+    }
+
+    listen_fd_range(&count)?.map(adopt_fd).collect()
+}
+
+// The descriptors a LISTEN_FDS value covers, none for a negative count
+fn listen_fd_range(count: &str) -> io::Result<Range<RawFd>> {
+    let count = count
+        .parse::<RawFd>()
+        .map_err(|e| invalid_input(format!("Invalid LISTEN_FDS {}: {}", count, e)))?;
+
+    if count < 0 {
+        return Ok(LISTEN_FDS_START..LISTEN_FDS_START);
+    }
+
+    let end = LISTEN_FDS_START
+        .checked_add(count)
+        .ok_or_else(|| invalid_input(format!("LISTEN_FDS {} is too large", count)))?;
+    Ok(LISTEN_FDS_START..end)
+}
+
+fn adopt_fd(fd: RawFd) -> io::Result<Listener> {
+    // Checked before taking ownership, so a descriptor that turns out to be
+    // something else is left open for whoever owns it
+    let mut kind: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    // SAFETY: `kind` and `len` are valid for writes of the given size
+    let result = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_TYPE,
+            &mut kind as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if result < 0 {
+        let e = io::Error::last_os_error();
+        return Err(invalid_input(format!(
+            "Inherited descriptor {} is not a socket: {}",
+            fd, e
+        )));
+    }
+    if kind != libc::SOCK_STREAM {
+        return Err(invalid_input(format!(
+            "Inherited descriptor {} is not a stream socket",
+            fd
+        )));
+    }
+
+    // SAFETY: the activation protocol hands these descriptors to this process
+    // and nothing else in it owns them
+    let socket = unsafe { Socket::from_raw_fd(fd) };
+    socket.set_cloexec(true)?;
+
+    let addr = socket.local_addr()?;
+
+    if addr.as_socket().is_some() {
+        Ok(Listener::Tcp(socket.into()))
+    } else if addr.is_unix() {
+        Ok(Listener::Unix(UnixListener::from(OwnedFd::from(socket))))
+    } else {
+        Err(invalid_input(format!(
+            "Inherited descriptor {} has an unsupported address family",
+            fd
+        )))
+    }
+}
+
+// Starts `command` with the listeners as descriptors 3, 4, ... and LISTEN_FDS
+// and LISTEN_PID set, so a new version of the server can adopt them through
+// listen_fds() and start accepting before this one exits. The child gets the
+// environment of this process with the changes made through `command.env`
+// and `env_remove`; `env_clear` and `arg0` aren't supported.
+pub fn spawn_with_listeners(mut command: Command, listeners: &[&Listener]) -> io::Result<Child> {
+    let fds: Vec<RawFd> = listeners.iter().map(|l| l.as_raw_fd()).collect();
+    let count = fds.len() as RawFd;
+    // Allocated up front, the child must not allocate before exec
+    let mut moved: Vec<RawFd> = vec![-1; fds.len()];
+
+    command.env("LISTEN_FDS", count.to_string());
+    command.env_remove("LISTEN_PID");
+    command.env_remove("LISTEN_FDNAMES");
+    let mut exec = Exec::new(&command)?;
+
+    // SAFETY: only async-signal-safe calls (fcntl, dup2, getpid, execvp) run
+    // between fork and exec
+    unsafe {
+        command.pre_exec(move || {
+            // Move every source out of the 3..3+n range first so the dup2
+            // calls below can't clobber a descriptor that is still needed
+            for (i, fd) in fds.iter().enumerate() {
+                moved[i] = libc::fcntl(*fd, libc::F_DUPFD_CLOEXEC, LISTEN_FDS_START + count);
+                if moved[i] < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+
+            for (i, fd) in moved.iter().enumerate() {
+                // dup2 clears FD_CLOEXEC on the target, so it survives exec
+                if libc::dup2(*fd, LISTEN_FDS_START + i as RawFd) < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+
+            Err(exec.run())
+        });
+    }
+
+    command.spawn()
+}
+
+extern "C" {
+    static mut environ: *const *const libc::c_char;
+}
+
+// Wide enough for any pid, leading zeros parse fine
+const PID_PLACEHOLDER: &[u8] = b"LISTEN_PID=0000000000\0";
+
+// The exec of a child that has to name its own pid in LISTEN_PID, which is
+// only known after the fork. Command switches to the environment it was
+// given after running pre_exec, too late to add the pid to it, so the child
+// execs itself with the environment and arguments prepared here.
+struct Exec {
+    argv: Vec<Vec<u8>>,
+    // Ends with the LISTEN_PID placeholder
+    env: Vec<Vec<u8>>,
+    // Addresses of the NUL terminated strings above plus a null pointer,
+    // kept as usize as raw pointers can't be moved into pre_exec
+    argv_ptrs: Vec<usize>,
+    env_ptrs: Vec<usize>,
+}
+
+impl Exec {
+    fn new(command: &Command) -> io::Result<Self> {
+        let mut vars: Vec<(OsString, OsString)> = env::vars_os().collect();
+        for (key, value) in command.get_envs() {
+            vars.retain(|(it, _)| it != key);
+            if let Some(value) = value {
+                vars.push((key.to_os_string(), value.to_os_string()));
+            }
+        }
+
+        let mut env = vars
+            .into_iter()
+            .map(|(key, value)| {
+                let mut var = key.into_vec();
+                var.push(b'=');
+                var.extend(value.into_vec());
+                nul_terminated(var)
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        let argv = std::iter::once(command.get_program())
+            .chain(command.get_args())
+            .map(|arg| nul_terminated(arg.as_bytes().to_vec()))
+            .collect::<io::Result<Vec<_>>>()?;
+        env.push(PID_PLACEHOLDER.to_vec());
+
+        let pointers = |strings: &[Vec<u8>]| {
+            strings
+                .iter()
+                .map(|it| it.as_ptr() as usize)
+                .chain([0])
+                .collect::<Vec<usize>>()
+        };
+        Ok(Self {
+            argv_ptrs: pointers(&argv),
+            env_ptrs: pointers(&env),
+            argv,
+            env,
+        })
+    }
+
+    // Returns only if the exec failed
+    unsafe fn run(&mut self) -> io::Error {
+        let Some(placeholder) = self.env.last_mut() else {
+            return io::Error::from_raw_os_error(libc::EINVAL);
+        };
+        let digits = b"LISTEN_PID=".len()..placeholder.len() - 1;
+        let mut pid = libc::getpid() as u32;
+        for digit in placeholder[digits].iter_mut().rev() {
+            *digit = b'0' + (pid % 10) as u8;
+            pid /= 10;
+        }
+
+        // execvp looks the program up in the PATH of the new environment,
+        // like Command does
+        environ = self.env_ptrs.as_ptr() as *const *const libc::c_char;
+        libc::execvp(
+            self.argv[0].as_ptr() as *const libc::c_char,
+            self.argv_ptrs.as_ptr() as *const *const libc::c_char,
+        );
+        io::Error::last_os_error()
+    }
+}
+
+fn nul_terminated(mut bytes: Vec<u8>) -> io::Result<Vec<u8>> {
+    if bytes.contains(&0) {
+        return Err(invalid_input(
+            "Arguments and environment can't contain NUL".to_string(),
+        ));
+    }
+    bytes.push(0);
+    Ok(bytes)
+}
+
+fn invalid_input(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use std::net::{TcpListener, UdpSocket};
+    use std::process::Stdio;
+
+    #[test]
+    fn leaves_other_descriptors_open() {
+        let file = File::open("Cargo.toml").unwrap();
+        assert!(adopt_fd(file.as_raw_fd()).is_err());
+        assert!(file.metadata().is_ok());
+
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        assert!(adopt_fd(socket.as_raw_fd()).is_err());
+        assert!(socket.local_addr().is_ok());
+    }
+
+    #[test]
+    fn counts_descriptors_from_3() {
+        assert_eq!(listen_fd_range("2").unwrap(), 3..5);
+        assert!(listen_fd_range("0").unwrap().is_empty());
+        assert!(listen_fd_range("-1").unwrap().is_empty());
+
+        for count in ["", "two", "2147483647", "99999999999"] {
+            let error = listen_fd_range(count).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidInput, "{}", count);
+        }
+    }
+
+    #[test]
+    fn tells_the_child_its_pid() {
+        let listener = Listener::Tcp(TcpListener::bind("127.0.0.1:0").unwrap());
+
+        let mut command = Command::new("/bin/sh");
+        command
+            .args(["-c", "echo $$ $LISTEN_PID $LISTEN_FDS $GREETING"])
+            .env("GREETING", "hello")
+            .stdout(Stdio::piped());
+        let output = spawn_with_listeners(command, &[&listener])
+            .unwrap()
+            .wait_with_output()
+            .unwrap();
+
+        let output = String::from_utf8(output.stdout).unwrap();
+        let fields: Vec<&str> = output.split_whitespace().collect();
+        assert_eq!(fields.len(), 4, "{}", output);
+        assert_eq!(fields[0].parse::<u32>(), fields[1].parse::<u32>());
+        assert_eq!(fields[2..], ["1", "hello"]);
+    }
+}
@@ -0,0 +1,477 @@
+use crate::error::ParseError;
+use crate::models::read_line_limited;
+use crate::response::Status;
+use std::fmt;
+use std::io::{self, BufRead, Read, Write};
+use std::sync::{Arc, Mutex};
+
+// Longest chunk size line, chunk extensions included
+const MAX_CHUNK_SIZE_LINE: u64 = 1024;
+// Combined size of all trailer lines after the last chunk
+const MAX_TRAILERS_SIZE: u64 = 16 * 1024;
+
+// How the end of a request body is found, see RFC 9112 6.3
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Framing {
+    Length(u64),
+    Chunked,
+}
+
+#[derive(Debug)]
+enum State {
+    Remaining(u64),
+    ChunkSize,
+    ChunkData(u64),
+    ChunkEnd,
+    Done,
+    // A read failed, the connection is out of step with the framing
+    Failed,
+}
+
+// Reads a single request body off a connection and stops where the next
+// request starts
+pub struct BodyReader<R> {
+    reader: R,
+    state: State,
+    // Where to send 100 Continue before the first read, see expect_continue
+    continue_to: Option<Box<dyn Write + Send>>,
+}
+
+impl<R: BufRead> BodyReader<R> {
+    pub fn new(reader: R, framing: Framing) -> Self {
+        let state = match framing {
+            Framing::Length(0) => State::Done,
+            Framing::Length(length) => State::Remaining(length),
+            Framing::Chunked => State::ChunkSize,
+        };
+
+        Self {
+            reader,
+            state,
+            continue_to: None,
+        }
+    }
+
+    // The client sent Expect: 100-continue and waits for the go ahead before
+    // sending the body. It is given on the first read, so handlers that
+    // answer without reading the body never ask for it.
+    pub fn expect_continue<W: Write + Send + 'static>(mut self, writer: W) -> Self {
+        if !self.is_done() {
+            self.continue_to = Some(Box::new(writer));
+        }
+        self
+    }
+
+    // Still waiting to send 100 Continue, the client may not have sent
+    // any of the body
+    pub fn awaiting_continue(&self) -> bool {
+        self.continue_to.is_some()
+    }
+
+    pub fn is_done(&self) -> bool {
+        matches!(self.state, State::Done)
+    }
+
+    // Skips over what is left of the body. Gives up once more than `limit`
+    // bytes were thrown away, the connection can't be reused then.
+    pub fn drain(&mut self, limit: u64) -> io::Result<bool> {
+        let mut skipped = 0;
+        let mut buf = [0; 8192];
+
+        while !self.is_done() {
+            if skipped > limit {
+                return Ok(false);
+            }
+            skipped += self.read(&mut buf)? as u64;
+        }
+
+        Ok(true)
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+
+    fn read_chunk_size(&mut self) -> io::Result<u64> {
+        let line = read_line_limited(&mut self.reader, MAX_CHUNK_SIZE_LINE)
+            .map_err(invalid_data)?
+            .ok_or_else(|| {
+                invalid_data(ParseError::Malformed("Chunk size too long".to_string()))
+            })?;
+
+        // Chunk extensions after ';' carry nothing we act on. Only hex
+        // digits are a size, from_str_radix alone would take "+5" too.
+        let size = line.split(';').next().unwrap_or_default().trim_end();
+        if size.is_empty() || !size.bytes().all(|it| it.is_ascii_hexdigit()) {
+            return Err(invalid_data(ParseError::Malformed(format!(
+                "Invalid chunk size: {}",
+                size
+            ))));
+        }
+
+        u64::from_str_radix(size, 16).map_err(|_| {
+            invalid_data(ParseError::Malformed(format!(
+                "Invalid chunk size: {}",
+                size
+            )))
+        })
+    }
+
+    fn read_crlf(&mut self) -> io::Result<()> {
+        match read_line_limited(&mut self.reader, 2).map_err(invalid_data)? {
+            Some(line) if line.trim().is_empty() => Ok(()),
+            _ => Err(invalid_data(ParseError::Malformed(
+                "Expecting CRLF after chunk data".to_string(),
+            ))),
+        }
+    }
+
+    // Trailer fields are read past and dropped
+    fn skip_trailers(&mut self) -> io::Result<()> {
+        let mut budget = MAX_TRAILERS_SIZE;
+
+        loop {
+            let line = read_line_limited(&mut self.reader, budget)
+                .map_err(invalid_data)?
+                .ok_or_else(|| invalid_data(ParseError::HeadersTooLarge))?;
+            budget -= line.len() as u64;
+
+            if line.trim().is_empty() {
+                return Ok(());
+            }
+        }
+    }
+}
+
+impl<R: BufRead> Read for BodyReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if let Some(mut writer) = self.continue_to.take() {
+            write!(writer, "HTTP/1.1 {}\r\n\r\n", Status::Continue)?;
+            writer.flush()?;
+        }
+
+        let read = self.read_framed(buf);
+        if read.is_err() {
+            self.state = State::Failed;
+        }
+        read
+    }
+}
+
+impl<R: BufRead> BodyReader<R> {
+    fn read_framed(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match self.state {
+                State::Done => return Ok(0),
+                State::Failed => {
+                    return Err(io::Error::other("Request body failed to read earlier"));
+                }
+                State::Remaining(remaining) | State::ChunkData(remaining) => {
+                    if buf.is_empty() {
+                        return Ok(0);
+                    }
+
+                    let max = buf.len().min(remaining.min(usize::MAX as u64) as usize);
+                    let read = self.reader.read(&mut buf[..max])?;
+                    if read == 0 {
+                        return Err(io::Error::new(
+                            io::ErrorKind::UnexpectedEof,
+                            ParseError::Incomplete.to_string(),
+                        ));
+                    }
+
+                    let remaining = remaining - read as u64;
+                    self.state = match self.state {
+                        State::Remaining(_) if remaining == 0 => State::Done,
+                        State::Remaining(_) => State::Remaining(remaining),
+                        _ if remaining == 0 => State::ChunkEnd,
+                        _ => State::ChunkData(remaining),
+                    };
+                    return Ok(read);
+                }
+                State::ChunkSize => {
+                    let size = self.read_chunk_size()?;
+                    if size == 0 {
+                        self.skip_trailers()?;
+                        self.state = State::Done;
+                    } else {
+                        self.state = State::ChunkData(size);
+                    }
+                }
+                State::ChunkEnd => {
+                    self.read_crlf()?;
+                    self.state = State::ChunkSize;
+                }
+            }
+        }
+    }
+}
+
+fn invalid_data(e: ParseError) -> io::Error {
+    match e {
+        ParseError::Io(e) => e,
+        ParseError::Incomplete => io::Error::new(io::ErrorKind::UnexpectedEof, e.to_string()),
+        e => io::Error::new(io::ErrorKind::InvalidData, e.to_string()),
+    }
+}
+
+// The body of an HttpRequest. Handlers read it as a stream, the server
+// skips whatever they leave unread before the next request on the connection.
+pub struct RequestBody {
+    reader: Box<dyn Read + Send>,
+    length: Option<u64>,
+}
+
+// The error a limited body fails with once it grows past its limit, the
+// body extractors answer it with 413
+#[derive(Debug)]
+pub struct TooLarge {
+    pub limit: u64,
+}
+
+impl fmt::Display for TooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Request body is larger than {} bytes", self.limit)
+    }
+}
+
+impl std::error::Error for TooLarge {}
+
+impl TooLarge {
+    // Whether `error` was caused by a body exceeding its limit
+    pub fn caused(error: &io::Error) -> bool {
+        error.get_ref().is_some_and(|it| it.is::<TooLarge>())
+    }
+}
+
+struct Limited {
+    reader: Box<dyn Read + Send>,
+    remaining: u64,
+    limit: u64,
+}
+
+impl Read for Limited {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // Reading one byte past the limit tells a body that ends right at
+        // it apart from a longer one
+        let max = buf.len().min(self.remaining.saturating_add(1) as usize);
+        let read = self.reader.read(&mut buf[..max])?;
+
+        if read as u64 > self.remaining {
+            self.remaining = 0;
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                TooLarge { limit: self.limit },
+            ));
+        }
+        self.remaining -= read as u64;
+        Ok(read)
+    }
+}
+
+impl RequestBody {
+    pub fn new<R: Read + Send + 'static>(reader: R) -> Self {
+        Self {
+            reader: Box::new(reader),
+            length: None,
+        }
+    }
+
+    // Body length announced up front, None for chunked bodies
+    pub fn length(&self) -> Option<u64> {
+        self.length
+    }
+
+    pub fn with_length(mut self, length: Option<u64>) -> Self {
+        self.length = length;
+        self
+    }
+
+    // Fails reads with TooLarge once more than `limit` bytes were read
+    pub fn with_limit(self, limit: u64) -> Self {
+        Self {
+            reader: Box::new(Limited {
+                reader: self.reader,
+                remaining: limit,
+                limit,
+            }),
+            length: self.length,
+        }
+    }
+}
+
+impl Read for RequestBody {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reader.read(buf)
+    }
+}
+
+impl From<Vec<u8>> for RequestBody {
+    fn from(bytes: Vec<u8>) -> Self {
+        let length = bytes.len() as u64;
+        Self::new(io::Cursor::new(bytes)).with_length(Some(length))
+    }
+}
+
+impl fmt::Debug for RequestBody {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("RequestBody")
+            .field("length", &self.length)
+            .finish_non_exhaustive()
+    }
+}
+
+// The connection reader lent to a request body for the duration of one
+// request. The server takes it back once the response is written.
+pub(crate) type SharedBody<R> = Arc<Mutex<Option<BodyReader<R>>>>;
+
+pub(crate) struct Lent<R>(pub SharedBody<R>);
+
+impl<R: BufRead> Read for Lent<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut body = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        match body.as_mut() {
+            Some(body) => body.read(buf),
+            None => Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "Request body read after the request finished",
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn reader(input: &str, framing: Framing) -> BodyReader<Cursor<Vec<u8>>> {
+        BodyReader::new(Cursor::new(input.as_bytes().to_vec()), framing)
+    }
+
+    fn read_all(body: &mut BodyReader<Cursor<Vec<u8>>>) -> io::Result<String> {
+        let mut read = String::new();
+        body.read_to_string(&mut read)?;
+        Ok(read)
+    }
+
+    // What is left on the connection for the next request
+    fn rest(body: BodyReader<Cursor<Vec<u8>>>) -> String {
+        let mut rest = String::new();
+        body.into_inner().read_to_string(&mut rest).unwrap();
+        rest
+    }
+
+    #[test]
+    fn stops_at_content_length() {
+        let mut body = reader("helloGET / HTTP/1.1", Framing::Length(5));
+
+        assert_eq!(read_all(&mut body).unwrap(), "hello");
+        assert!(body.is_done());
+        assert_eq!(rest(body), "GET / HTTP/1.1");
+    }
+
+    #[test]
+    fn short_body_is_unexpected_eof() {
+        let mut body = reader("short", Framing::Length(10));
+
+        let error = read_all(&mut body).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn decodes_chunks_and_skips_trailers() {
+        let input = "5\r\nhello\r\n6;name=value\r\n world\r\n0\r\nX-Sum: 1\r\n\r\nNEXT";
+        let mut body = reader(input, Framing::Chunked);
+
+        assert_eq!(read_all(&mut body).unwrap(), "hello world");
+        assert_eq!(rest(body), "NEXT");
+    }
+
+    #[test]
+    fn rejects_malformed_chunk_sizes() {
+        for size in [
+            "zz",
+            "",
+            "+5",
+            "-5",
+            " 5",
+            "0x5",
+            "5 5",
+            "10000000000000000",
+        ] {
+            let input = format!("{}\r\nhello\r\n0\r\n\r\n", size);
+            let mut body = reader(&input, Framing::Chunked);
+
+            let error = read_all(&mut body).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData, "size {:?}", size);
+            // The connection is out of step, later reads fail too
+            assert!(body.read(&mut [0; 8]).is_err());
+            assert!(!body.is_done());
+        }
+    }
+
+    #[test]
+    fn accepts_whitespace_before_chunk_extensions() {
+        let mut body = reader("5 ;ext\r\nhello\r\n0\r\n\r\n", Framing::Chunked);
+        assert_eq!(read_all(&mut body).unwrap(), "hello");
+    }
+
+    #[test]
+    fn limits_the_chunk_size_line() {
+        let input = format!("5;{}\r\nhello\r\n0\r\n\r\n", "x".repeat(2000));
+        let mut body = reader(&input, Framing::Chunked);
+
+        assert_eq!(
+            read_all(&mut body).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+
+    #[test]
+    fn requires_crlf_after_chunk_data() {
+        let mut body = reader("5\r\nhelloXX0\r\n\r\n", Framing::Chunked);
+        assert!(read_all(&mut body).is_err());
+    }
+
+    #[test]
+    fn limits_trailers() {
+        let one_large = format!("0\r\nX-Big: {}\r\n\r\n", "a".repeat(20 * 1024));
+        let many_small = format!("0\r\n{}\r\n", "X-Small: aaaaaaaa\r\n".repeat(1024));
+
+        for input in [one_large, many_small] {
+            let mut body = reader(&input, Framing::Chunked);
+            assert_eq!(
+                read_all(&mut body).unwrap_err().kind(),
+                io::ErrorKind::InvalidData
+            );
+        }
+    }
+
+    #[test]
+    fn drains_unread_bodies() {
+        let mut body = reader("helloNEXT", Framing::Length(5));
+        assert!(body.drain(1024).unwrap());
+        assert_eq!(rest(body), "NEXT");
+
+        let mut body = reader("5\r\nhello\r\n0\r\n\r\nNEXT", Framing::Chunked);
+        body.read_exact(&mut [0; 2]).unwrap();
+        assert!(body.drain(1024).unwrap());
+        assert_eq!(rest(body), "NEXT");
+    }
+
+    #[test]
+    fn drain_gives_up_past_the_limit() {
+        let input = "a".repeat(100_000);
+        let mut body = reader(&input, Framing::Length(100_000));
+
+        assert!(!body.drain(1000).unwrap());
+        assert!(!body.is_done());
+    }
+
+    #[test]
+    fn drain_fails_on_malformed_chunks() {
+        let mut body = reader("zz\r\nhello\r\n0\r\n\r\n", Framing::Chunked);
+        assert!(body.drain(1024).is_err());
+    }
+}
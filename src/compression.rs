@@ -0,0 +1,629 @@
+use crate::body::RequestBody;
+use crate::conditional::EntityTag;
+use crate::extract::MAX_BUFFERED_BODY;
+use crate::middleware::Middleware;
+use crate::models::{EntityHeader, HttpRequest, RequestHeader, ResponseHeader};
+use crate::negotiate::parse_weighted;
+use crate::response::{Body, HttpResponse, Status};
+use flate2::Compression as Level;
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Mutex};
+
+// A content coding, see RFC 9110 8.4.1
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Coding {
+    Identity,
+    Gzip,
+    Deflate,
+    #[cfg(feature = "brotli")]
+    Brotli,
+}
+
+impl Coding {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Coding::Identity => "identity",
+            Coding::Gzip => "gzip",
+            Coding::Deflate => "deflate",
+            #[cfg(feature = "brotli")]
+            Coding::Brotli => "br",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Some(match name.trim().to_ascii_lowercase().as_str() {
+            "identity" => Coding::Identity,
+            "gzip" | "x-gzip" => Coding::Gzip,
+            "deflate" => Coding::Deflate,
+            #[cfg(feature = "brotli")]
+            "br" => Coding::Brotli,
+            _ => return None,
+        })
+    }
+
+    // Every coding this build can produce, best first
+    pub fn supported() -> Vec<Coding> {
+        vec![
+            #[cfg(feature = "brotli")]
+            Coding::Brotli,
+            Coding::Gzip,
+            Coding::Deflate,
+        ]
+    }
+}
+
+// Picks the content coding to send out of `available` (best first), e.g.
+// ["br", "gzip"], given the Accept-Encoding header. "identity" when the
+// client accepts none of them, None when it also ruled out identity with
+// identity;q=0 or *;q=0, see RFC 9110 12.5.3.
+pub fn preferred_encoding<'a>(
+    accept_encoding: Option<&str>,
+    available: &[&'a str],
+) -> Option<&'a str> {
+    let Some(accept_encoding) = accept_encoding else {
+        return Some("identity");
+    };
+
+    let accepted = parse_weighted(accept_encoding);
+    let weight = |name: &str| {
+        // x-gzip is the same coding by another name
+        let is_gzip =
+            |it: &str| it.eq_ignore_ascii_case("gzip") || it.eq_ignore_ascii_case("x-gzip");
+        accepted
+            .iter()
+            .find(|it| it.value.eq_ignore_ascii_case(name) || (is_gzip(name) && is_gzip(&it.value)))
+            .or_else(|| accepted.iter().find(|it| it.value == "*"))
+            .map(|it| it.q)
+    };
+
+    let mut best: Option<(&str, u16)> = None;
+    for &name in available {
+        if let Some(q) = weight(name).filter(|it| *it > 0) {
+            if best.is_none_or(|(_, best_q)| q > best_q) {
+                best = Some((name, q));
+            }
+        }
+    }
+
+    if let Some((name, _)) = best {
+        return Some(name);
+    }
+
+    // identity is acceptable unless excluded explicitly or through *
+    match weight("identity") {
+        Some(0) => None,
+        _ => Some("identity"),
+    }
+}
+
+// preferred_encoding for the codings this build can produce
+pub fn preferred_coding(accept_encoding: Option<&str>, supported: &[Coding]) -> Option<Coding> {
+    let names: Vec<&str> = supported.iter().map(Coding::name).collect();
+    preferred_encoding(accept_encoding, &names).and_then(Coding::from_name)
+}
+
+// Media types that are compressed already or don't shrink
+fn is_compressible(content_type: &str) -> bool {
+    let essence = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+
+    if essence == "image/svg+xml" {
+        return true;
+    }
+
+    let (kind, subtype) = essence.split_once('/').unwrap_or((&essence, ""));
+    !matches!(kind, "image" | "audio" | "video")
+        && !matches!(
+            subtype,
+            "zip"
+                | "gzip"
+                | "x-gzip"
+                | "x-bzip2"
+                | "x-xz"
+                | "zstd"
+                | "x-7z-compressed"
+                | "x-rar-compressed"
+                | "vnd.rar"
+                | "woff"
+                | "woff2"
+                | "pdf"
+        )
+}
+
+// Compresses responses with the best coding the client accepts and marks
+// them with Content-Encoding and Vary: Accept-Encoding. Responses that are
+// encoded already, partial, compressed media types or smaller than
+// `min_size` are left alone.
+pub struct Compression {
+    codings: Vec<Coding>,
+    min_size: u64,
+    level: u32,
+}
+
+impl Compression {
+    pub fn new() -> Self {
+        Self {
+            codings: Coding::supported(),
+            min_size: 1024,
+            level: 6,
+        }
+    }
+
+    // Codings to offer, best first
+    pub fn codings(mut self, codings: Vec<Coding>) -> Self {
+        self.codings = codings;
+        self
+    }
+
+    // Bodies of known length below this are sent as they are
+    pub fn min_size(mut self, min_size: u64) -> Self {
+        self.min_size = min_size;
+        self
+    }
+
+    // 0 (fastest) to 9 (smallest), brotli maps it onto its 0 to 11 scale
+    pub fn level(mut self, level: u32) -> Self {
+        self.level = level.min(9);
+        self
+    }
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Middleware for Compression {
+    fn after(&self, request: &HttpRequest, response: &mut HttpResponse) {
+        if !response.status.allows_body()
+            || response.status == Status::PartialContent
+            || response
+                .entity_headers
+                .get(EntityHeader::ContentEncoding)
+                .is_some()
+            || matches!(response.body, Body::Empty)
+        {
+            return;
+        }
+
+        let content_type = response.get_header("Content-Type").unwrap_or_default();
+        if !is_compressible(&content_type) {
+            return;
+        }
+
+        // Caches have to keep the variants apart even when this one isn't encoded
+        add_vary(response, "Accept-Encoding");
+
+        if response.body.length().is_some_and(|it| it < self.min_size) {
+            return;
+        }
+
+        let accept_encoding = request.request_headers.get(RequestHeader::AcceptEncoding);
+        let coding = match preferred_coding(accept_encoding.as_deref(), &self.codings) {
+            Some(Coding::Identity) => return,
+            Some(coding) => coding,
+            // Nothing acceptable, not even identity. Sending it unencoded
+            // beats replacing the response with a 406, RFC 9110 12.5.3.
+            None => return,
+        };
+
+        let body = std::mem::take(&mut response.body);
+        response.body = match encode_body(body, coding, self.level) {
+            Ok(body) => body,
+            Err(msg) => {
+                eprintln!("Failed to compress response. Reason: {}", msg);
+                *response = HttpResponse::error(Status::InternalServerError);
+                return;
+            }
+        };
+
+        let _ = response
+            .entity_headers
+            .insert(EntityHeader::ContentEncoding, coding.name());
+
+        // The encoded bytes differ, so a strong validator of the original
+        // would be wrong for them, see RFC 9110 8.8.1
+        if let Some(etag) = response.response_headers.get(ResponseHeader::ETag) {
+            if let Ok(EntityTag { tag, .. }) = EntityTag::parse(&etag) {
+                let _ = response
+                    .response_headers
+                    .insert(ResponseHeader::ETag, &EntityTag::weak(tag).to_string());
+            }
+        }
+    }
+}
+
+// Decodes request bodies sent with a Content-Encoding before handlers see
+// them, removing Content-Encoding and Content-Length as the body no longer
+// matches them. Bodies growing past `max_size` once decoded fail with
+// TooLarge, so a small zip bomb can't fill the memory, and unknown codings
+// are answered with 415.
+pub struct Decompression {
+    max_size: u64,
+}
+
+impl Decompression {
+    pub fn new() -> Self {
+        Self {
+            max_size: MAX_BUFFERED_BODY,
+        }
+    }
+
+    // Most bytes a body may decode to
+    pub fn max_size(mut self, max_size: u64) -> Self {
+        self.max_size = max_size;
+        self
+    }
+}
+
+impl Default for Decompression {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Middleware for Decompression {
+    fn before(&self, request: &mut HttpRequest) -> Option<HttpResponse> {
+        let encoding = request.entity_headers.get(EntityHeader::ContentEncoding)?;
+
+        let mut codings = Vec::new();
+        for name in encoding
+            .split(',')
+            .map(str::trim)
+            .filter(|it| !it.is_empty())
+        {
+            match Coding::from_name(name) {
+                Some(Coding::Identity) => {}
+                Some(coding) => codings.push(coding),
+                None => {
+                    // Tells the client what it could have used, see RFC 9110 12.5.3
+                    let supported: Vec<&str> =
+                        Coding::supported().iter().map(Coding::name).collect();
+                    return Some(
+                        HttpResponse::error(Status::UnsupportedMediaType)
+                            .header("Accept-Encoding", &supported.join(", ")),
+                    );
+                }
+            }
+        }
+
+        let _ = request.entity_headers.remove(EntityHeader::ContentEncoding);
+        if codings.is_empty() {
+            return None;
+        }
+
+        let body = request.body.take()?;
+        let _ = request.entity_headers.remove(EntityHeader::ContentLength);
+
+        // Codings are listed in the order they were applied
+        let mut reader: Box<dyn Read + Send> = Box::new(body);
+        for &coding in codings.iter().rev() {
+            reader = decoding_reader(reader, coding);
+        }
+        request.body = Some(RequestBody::new(reader).with_limit(self.max_size));
+
+        None
+    }
+}
+
+fn decoding_reader(reader: Box<dyn Read + Send>, coding: Coding) -> Box<dyn Read + Send> {
+    match coding {
+        Coding::Identity => reader,
+        // Members concatenated into one gzip body all belong to it
+        Coding::Gzip => Box::new(flate2::read::MultiGzDecoder::new(reader)),
+        Coding::Deflate => Box::new(flate2::read::ZlibDecoder::new(reader)),
+        #[cfg(feature = "brotli")]
+        Coding::Brotli => Box::new(brotli::Decompressor::new(reader, BROTLI_BUFFER)),
+    }
+}
+
+// Appends `name` to the Vary header unless it is listed already
+pub fn add_vary(response: &mut HttpResponse, name: &str) {
+    let vary = response
+        .response_headers
+        .get(ResponseHeader::Vary)
+        .unwrap_or_default();
+
+    if vary
+        .split(',')
+        .any(|it| it.trim() == "*" || it.trim().eq_ignore_ascii_case(name))
+    {
+        return;
+    }
+
+    let vary = if vary.trim().is_empty() {
+        name.to_string()
+    } else {
+        format!("{}, {}", vary, name)
+    };
+    let _ = response
+        .response_headers
+        .insert(ResponseHeader::Vary, &vary);
+}
+
+fn encode_body(body: Body, coding: Coding, level: u32) -> io::Result<Body> {
+    Ok(match body {
+        Body::Empty => Body::Empty,
+        Body::Bytes(bytes) => {
+            let output = SharedBuf::default();
+            let mut encoder = encoder(coding, level, output.clone());
+            encoder.write_all(&bytes)?;
+            encoder.finish()?;
+            Body::Bytes(output.take())
+        }
+        Body::Reader { reader, .. } => Body::reader(encoding_reader(reader, coding, level), None),
+        Body::Seekable { reader, .. } => {
+            Body::reader(encoding_reader(Box::new(reader), coding, level), None)
+        }
+        Body::Chunks(chunks) => Body::Chunks(Box::new(EncodedChunks::new(chunks, coding, level))),
+    })
+}
+
+// deflate in HTTP means the zlib format, not raw deflate, see RFC 9110 8.4.1.2
+fn encoding_reader(
+    reader: Box<dyn Read + Send>,
+    coding: Coding,
+    level: u32,
+) -> Box<dyn Read + Send> {
+    match coding {
+        Coding::Identity => reader,
+        Coding::Gzip => Box::new(flate2::read::GzEncoder::new(reader, Level::new(level))),
+        Coding::Deflate => Box::new(flate2::read::ZlibEncoder::new(reader, Level::new(level))),
+        #[cfg(feature = "brotli")]
+        Coding::Brotli => Box::new(brotli::CompressorReader::new(
+            reader,
+            BROTLI_BUFFER,
+            brotli_quality(level),
+            BROTLI_WINDOW,
+        )),
+    }
+}
+
+// An encoder writing into a SharedBuf, finish writes what is left
+trait Encoder: Write + Send {
+    fn finish(self: Box<Self>) -> io::Result<()>;
+}
+
+impl Encoder for flate2::write::GzEncoder<SharedBuf> {
+    fn finish(self: Box<Self>) -> io::Result<()> {
+        flate2::write::GzEncoder::finish(*self).map(|_| ())
+    }
+}
+
+impl Encoder for flate2::write::ZlibEncoder<SharedBuf> {
+    fn finish(self: Box<Self>) -> io::Result<()> {
+        flate2::write::ZlibEncoder::finish(*self).map(|_| ())
+    }
+}
+
+#[cfg(feature = "brotli")]
+impl Encoder for brotli::CompressorWriter<SharedBuf> {
+    fn finish(self: Box<Self>) -> io::Result<()> {
+        // Ends the brotli stream
+        self.into_inner();
+        Ok(())
+    }
+}
+
+impl Encoder for SharedBuf {
+    fn finish(self: Box<Self>) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn encoder(coding: Coding, level: u32, output: SharedBuf) -> Box<dyn Encoder> {
+    match coding {
+        Coding::Identity => Box::new(output),
+        Coding::Gzip => Box::new(flate2::write::GzEncoder::new(output, Level::new(level))),
+        Coding::Deflate => Box::new(flate2::write::ZlibEncoder::new(output, Level::new(level))),
+        #[cfg(feature = "brotli")]
+        Coding::Brotli => Box::new(brotli::CompressorWriter::new(
+            output,
+            BROTLI_BUFFER,
+            brotli_quality(level),
+            BROTLI_WINDOW,
+        )),
+    }
+}
+
+#[cfg(feature = "brotli")]
+const BROTLI_BUFFER: usize = 8192;
+#[cfg(feature = "brotli")]
+const BROTLI_WINDOW: u32 = 22;
+
+#[cfg(feature = "brotli")]
+fn brotli_quality(level: u32) -> u32 {
+    (level * 11).div_ceil(9)
+}
+
+// A Vec the encoder writes into while EncodedChunks takes the output out
+// after every chunk
+#[derive(Clone, Default)]
+struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+impl SharedBuf {
+    fn take(&self) -> Vec<u8> {
+        std::mem::take(&mut *self.0.lock().unwrap_or_else(|e| e.into_inner()))
+    }
+}
+
+impl Write for SharedBuf {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Encodes a chunked body chunk by chunk, flushing the encoder after each so
+// clients still see every chunk as soon as it is produced
+struct EncodedChunks {
+    chunks: Box<dyn Iterator<Item = Vec<u8>> + Send>,
+    encoder: Option<Box<dyn Encoder>>,
+    output: SharedBuf,
+}
+
+impl EncodedChunks {
+    fn new(chunks: Box<dyn Iterator<Item = Vec<u8>> + Send>, coding: Coding, level: u32) -> Self {
+        let output = SharedBuf::default();
+        Self {
+            chunks,
+            encoder: Some(encoder(coding, level, output.clone())),
+            output,
+        }
+    }
+}
+
+impl Iterator for EncodedChunks {
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Vec<u8>> {
+        loop {
+            let encoder = self.encoder.as_mut()?;
+
+            let written = match self.chunks.next() {
+                Some(chunk) => encoder.write_all(&chunk).and_then(|_| encoder.flush()),
+                None => {
+                    let encoder = self.encoder.take()?;
+                    encoder.finish()
+                }
+            };
+
+            // The body can't report errors, end it and let the client notice
+            if let Err(msg) = written {
+                eprintln!("Failed to compress response chunk. Reason: {}", msg);
+                self.encoder = None;
+                return None;
+            }
+
+            let output = self.output.take();
+            if !output.is_empty() {
+                return Some(output);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::extract::{Bytes, FromRequest};
+    use crate::models::{test_request, test_request_with_body};
+    use flate2::write::{GzEncoder, ZlibEncoder};
+
+    fn compress(accept_encoding: &str) -> HttpResponse {
+        let request = test_request("GET", "/", &[("Accept-Encoding", accept_encoding)]);
+
+        let mut response = HttpResponse::text(Status::Ok, "a".repeat(2048));
+        Compression::new().after(&request, &mut response);
+        response
+    }
+
+    #[test]
+    fn compresses_with_an_accepted_coding() {
+        let response = compress("gzip");
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(
+            response.get_header("Content-Encoding").as_deref(),
+            Some("gzip")
+        );
+        assert_eq!(
+            response.get_header("Vary").as_deref(),
+            Some("Accept-Encoding")
+        );
+    }
+
+    #[test]
+    fn sends_identity_when_nothing_is_acceptable() {
+        let response = compress("identity;q=0, compress");
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(response.get_header("Content-Encoding"), None);
+        assert_eq!(
+            response.get_header("Vary").as_deref(),
+            Some("Accept-Encoding")
+        );
+        assert_eq!(response.body.length(), Some(2048));
+    }
+
+    fn gzip(bytes: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Level::default());
+        encoder.write_all(bytes).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn deflate(bytes: &[u8]) -> Vec<u8> {
+        let mut encoder = ZlibEncoder::new(Vec::new(), Level::default());
+        encoder.write_all(bytes).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn upload(content_encoding: &str, body: &[u8]) -> HttpRequest {
+        test_request_with_body("POST", "/", &[("Content-Encoding", content_encoding)], body)
+    }
+
+    fn decompressed(decompression: &Decompression, request: &mut HttpRequest) -> Vec<u8> {
+        assert!(decompression.before(request).is_none());
+        assert_eq!(request.header("Content-Encoding"), None);
+
+        Bytes::from_request(request).unwrap().0
+    }
+
+    #[test]
+    fn decodes_gzip_and_deflate_bodies() {
+        let decompression = Decompression::new();
+
+        let mut request = upload("gzip", &gzip(b"hello"));
+        assert_eq!(decompressed(&decompression, &mut request), b"hello");
+        assert_eq!(request.header("Content-Length"), None);
+
+        let mut request = upload("deflate", &deflate(b"hello"));
+        assert_eq!(decompressed(&decompression, &mut request), b"hello");
+    }
+
+    #[test]
+    fn undoes_stacked_codings_last_first() {
+        // gzip was applied first, deflate on top of it
+        let mut request = upload("gzip, deflate", &deflate(&gzip(b"hello")));
+        assert_eq!(decompressed(&Decompression::new(), &mut request), b"hello");
+    }
+
+    #[test]
+    fn passes_identity_through() {
+        let mut request = upload("identity", b"hello");
+        assert_eq!(decompressed(&Decompression::new(), &mut request), b"hello");
+    }
+
+    #[test]
+    fn stops_bodies_inflating_past_max_size() {
+        let bomb = gzip(&vec![0; 1024 * 1024]);
+        assert!(bomb.len() < 4096);
+
+        let mut request = upload("gzip", &bomb);
+        let decompression = Decompression::new().max_size(4096);
+        assert!(decompression.before(&mut request).is_none());
+
+        let rejection = Bytes::from_request(&mut request).unwrap_err();
+        assert_eq!(rejection.status, Status::ContentTooLarge);
+    }
+
+    #[test]
+    fn refuses_unknown_codings() {
+        let mut request = upload("gzip, zstd", b"hello");
+        let response = Decompression::new().before(&mut request).unwrap();
+
+        assert_eq!(response.status, Status::UnsupportedMediaType);
+        let accepted = response.get_header("Accept-Encoding").unwrap();
+        assert!(accepted.contains("gzip") && accepted.contains("deflate"));
+    }
+}
@@ -0,0 +1,366 @@
+use crate::date::HttpDate;
+use crate::middleware::Middleware;
+use crate::models::{EntityHeader, HttpRequest, Method, RequestHeader, ResponseHeader};
+use crate::response::{Body, HttpResponse, Status};
+use std::collections::hash_map::DefaultHasher;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+
+// An entity tag such as "xyzzy" or W/"xyzzy", see RFC 9110 8.8.3
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct EntityTag {
+    pub weak: bool,
+    // The opaque tag without quotes
+    pub tag: String,
+}
+
+impl EntityTag {
+    pub fn strong<S: Into<String>>(tag: S) -> Self {
+        Self {
+            weak: false,
+            tag: tag.into(),
+        }
+    }
+
+    pub fn weak<S: Into<String>>(tag: S) -> Self {
+        Self {
+            weak: true,
+            tag: tag.into(),
+        }
+    }
+
+    // A strong tag derived from the content itself
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        let mut hasher = DefaultHasher::new();
+        bytes.hash(&mut hasher);
+        Self::strong(format!("{:016x}", hasher.finish()))
+    }
+
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let value = value.trim();
+        let (weak, quoted) = match value.strip_prefix("W/") {
+            Some(quoted) => (true, quoted),
+            None => (false, value),
+        };
+
+        let tag = quoted
+            .strip_prefix('"')
+            .and_then(|it| it.strip_suffix('"'))
+            .ok_or_else(|| format!("Entity tag must be quoted: {}", value))?;
+
+        // etagc is any visible character but '"'
+        if tag.bytes().any(|it| it == b'"' || it < 0x21 || it == 0x7f) {
+            return Err(format!("Invalid entity tag: {}", value));
+        }
+
+        Ok(Self {
+            weak,
+            tag: tag.to_string(),
+        })
+    }
+
+    // Both strong and identical, used by If-Match and If-Range
+    pub fn strong_eq(&self, other: &EntityTag) -> bool {
+        !self.weak && !other.weak && self.tag == other.tag
+    }
+
+    // Identical tags regardless of weakness, used by If-None-Match
+    pub fn weak_eq(&self, other: &EntityTag) -> bool {
+        self.tag == other.tag
+    }
+}
+
+impl fmt::Display for EntityTag {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.weak {
+            write!(f, "W/")?;
+        }
+        write!(f, "\"{}\"", self.tag)
+    }
+}
+
+// The value of If-Match and If-None-Match
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TagList {
+    // "*", any current representation
+    Any,
+    Tags(Vec<EntityTag>),
+}
+
+impl TagList {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        if value.trim() == "*" {
+            return Ok(TagList::Any);
+        }
+
+        split_tags(value)
+            .into_iter()
+            .map(EntityTag::parse)
+            .collect::<Result<Vec<_>, _>>()
+            .map(TagList::Tags)
+    }
+}
+
+// Splits a comma separated list of entity tags, which may contain commas
+// themselves inside the quotes
+fn split_tags(value: &str) -> Vec<&str> {
+    let mut tags = Vec::new();
+    let mut quoted = false;
+    let mut start = 0;
+
+    for (i, c) in value.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            ',' if !quoted => {
+                tags.push(value[start..i].trim());
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    tags.push(value[start..].trim());
+
+    tags.into_iter().filter(|it| !it.is_empty()).collect()
+}
+
+// What the handler knows about the current representation, empty when
+// there is none yet, e.g. for a PUT creating it
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Validators {
+    pub etag: Option<EntityTag>,
+    pub last_modified: Option<HttpDate>,
+}
+
+impl Validators {
+    pub fn new() -> Self {
+        Self {
+            etag: None,
+            last_modified: None,
+        }
+    }
+
+    pub fn etag(mut self, etag: EntityTag) -> Self {
+        self.etag = Some(etag);
+        self
+    }
+
+    // Takes a SystemTime too, e.g. file metadata
+    pub fn last_modified<T: Into<HttpDate>>(mut self, time: T) -> Self {
+        self.last_modified = Some(time.into());
+        self
+    }
+
+    // The validators a response carries in its ETag and Last-Modified headers
+    pub fn from_response(response: &HttpResponse) -> Self {
+        Self {
+            etag: response
+                .response_headers
+                .get(ResponseHeader::ETag)
+                .and_then(|it| EntityTag::parse(&it).ok()),
+            last_modified: response.entity_headers.last_modified(),
+        }
+    }
+
+    // Sets the ETag and Last-Modified headers of `response`
+    pub fn apply(&self, response: &mut HttpResponse) {
+        if let Some(etag) = &self.etag {
+            let _ = response
+                .response_headers
+                .insert(ResponseHeader::ETag, &etag.to_string());
+        }
+        if let Some(time) = self.last_modified {
+            let _ = response
+                .entity_headers
+                .insert(EntityHeader::LastModified, &time.to_string());
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Precondition {
+    // Perform the request as usual
+    Proceed,
+    // Answer 304, the client's cached copy is current
+    NotModified,
+    // Answer 412 without performing the request
+    Failed,
+}
+
+// Evaluates the conditional headers of `request` against the current
+// representation in the order of RFC 9110 13.2.2. There is a current
+// representation when `validators` has an ETag or a Last-Modified date,
+// which is what "*" matches. A malformed If-Match fails, since the client
+// asked not to proceed unless it holds; other malformed headers are
+// ignored, as are If-Modified-Since and If-Unmodified-Since without a
+// Last-Modified date to compare with.
+pub fn evaluate(request: &HttpRequest, validators: &Validators) -> Precondition {
+    let exists = validators.etag.is_some() || validators.last_modified.is_some();
+    evaluate_with(request, validators, exists)
+}
+
+// As evaluate, with whether there is a current representation known from
+// elsewhere, e.g. a successful response without validators
+fn evaluate_with(request: &HttpRequest, validators: &Validators, exists: bool) -> Precondition {
+    let headers = &request.request_headers;
+    let method = &request.request_line.method;
+    let get_or_head = matches!(method, Method::GET | Method::HEAD);
+    let last_modified = validators.last_modified;
+
+    // 1. and 2.
+    if let Some(if_match) = headers.get(RequestHeader::IfMatch) {
+        let matches = match (TagList::parse(&if_match), &validators.etag) {
+            (Ok(TagList::Any), _) => exists,
+            (Ok(TagList::Tags(tags)), Some(etag)) => tags.iter().any(|it| it.strong_eq(etag)),
+            (Ok(TagList::Tags(_)), None) | (Err(_), _) => false,
+        };
+        if !matches {
+            return Precondition::Failed;
+        }
+    } else if let (Some(since), Some(modified)) = (headers.if_unmodified_since(), last_modified) {
+        if modified > since {
+            return Precondition::Failed;
+        }
+    }
+
+    // 3. and 4.
+    if let Some(if_none_match) = headers.get(RequestHeader::IfNoneMatch) {
+        if let Ok(tags) = TagList::parse(&if_none_match) {
+            let matches = match (&tags, &validators.etag) {
+                (TagList::Any, _) => exists,
+                (TagList::Tags(tags), Some(etag)) => tags.iter().any(|it| it.weak_eq(etag)),
+                (TagList::Tags(_), None) => false,
+            };
+            if matches {
+                return if get_or_head {
+                    Precondition::NotModified
+                } else {
+                    Precondition::Failed
+                };
+            }
+        }
+    } else if let (true, Some(since), Some(modified)) =
+        (get_or_head, headers.if_modified_since(), last_modified)
+    {
+        if modified <= since {
+            return Precondition::NotModified;
+        }
+    }
+
+    Precondition::Proceed
+}
+
+// For handlers to call before acting on a request, e.g. a PUT that must
+// not overwrite a newer version. Some response means answer with it
+// instead of performing the request.
+pub fn check_preconditions(request: &HttpRequest, validators: &Validators) -> Option<HttpResponse> {
+    match evaluate(request, validators) {
+        Precondition::Proceed => None,
+        Precondition::NotModified => {
+            let mut response = HttpResponse::new(Status::NotModified);
+            validators.apply(&mut response);
+            Some(response)
+        }
+        Precondition::Failed => Some(HttpResponse::error(Status::PreconditionFailed)),
+    }
+}
+
+// Turns 200 responses to GET and HEAD into 304 or 412 based on the ETag and
+// Last-Modified headers the handler set. Unsafe methods have to be checked
+// before they take effect, with check_preconditions in the handler.
+pub struct ConditionalRequests;
+
+impl Middleware for ConditionalRequests {
+    fn after(&self, request: &HttpRequest, response: &mut HttpResponse) {
+        let method = &request.request_line.method;
+        if !matches!(method, Method::GET | Method::HEAD) || response.status != Status::Ok {
+            return;
+        }
+
+        // A 200 is the current representation, whatever validators it has
+        match evaluate_with(request, &Validators::from_response(response), true) {
+            Precondition::Proceed => {}
+            Precondition::NotModified => {
+                // Keep the headers a 200 would have had, see RFC 9110 15.4.5
+                response.status = Status::NotModified;
+                response.body = Body::Empty;
+            }
+            Precondition::Failed => *response = HttpResponse::error(Status::PreconditionFailed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::test_request;
+
+    fn current() -> Validators {
+        Validators::new().etag(EntityTag::strong("v1"))
+    }
+
+    #[test]
+    fn if_match_compares_strong_tags() {
+        let listed = test_request("PUT", "/", &[("If-Match", "\"v0\", \"v1\"")]);
+        assert_eq!(evaluate(&listed, &current()), Precondition::Proceed);
+
+        let weak = test_request("PUT", "/", &[("If-Match", "W/\"v1\"")]);
+        assert_eq!(evaluate(&weak, &current()), Precondition::Failed);
+    }
+
+    #[test]
+    fn fails_malformed_if_match() {
+        for value in ["v1", "\"v1", "\"v1\", v2", "W/v1"] {
+            let request = test_request("PUT", "/", &[("If-Match", value)]);
+            assert_eq!(
+                evaluate(&request, &current()),
+                Precondition::Failed,
+                "{}",
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn any_tag_needs_a_current_representation() {
+        let if_match = test_request("PUT", "/", &[("If-Match", "*")]);
+        assert_eq!(evaluate(&if_match, &current()), Precondition::Proceed);
+        assert_eq!(
+            evaluate(&if_match, &Validators::new()),
+            Precondition::Failed
+        );
+
+        let if_none_match = test_request("PUT", "/", &[("If-None-Match", "*")]);
+        assert_eq!(evaluate(&if_none_match, &current()), Precondition::Failed);
+        assert_eq!(
+            evaluate(&if_none_match, &Validators::new()),
+            Precondition::Proceed
+        );
+    }
+
+    #[test]
+    fn middleware_leaves_missing_resources_alone() {
+        let request = test_request("GET", "/", &[("If-Match", "*")]);
+        let mut response = HttpResponse::error(Status::NotFound);
+        ConditionalRequests.after(&request, &mut response);
+        assert_eq!(response.status, Status::NotFound);
+
+        let mut response = HttpResponse::text(Status::Ok, "a");
+        current().apply(&mut response);
+        ConditionalRequests.after(&request, &mut response);
+        assert_eq!(response.status, Status::Ok);
+    }
+
+    #[test]
+    fn middleware_takes_any_200_as_existing() {
+        let request = test_request("GET", "/", &[("If-Match", "*")]);
+        let mut response = HttpResponse::text(Status::Ok, "a");
+        ConditionalRequests.after(&request, &mut response);
+        assert_eq!(response.status, Status::Ok);
+
+        let request = test_request("GET", "/", &[("If-None-Match", "*")]);
+        let mut response = HttpResponse::text(Status::Ok, "a");
+        ConditionalRequests.after(&request, &mut response);
+        assert_eq!(response.status, Status::NotModified);
+        assert_eq!(response.body.length(), Some(0));
+    }
+}
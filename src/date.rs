@@ -0,0 +1,285 @@
+use std::cell::RefCell;
+use std::fmt;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const DAYS: [&str; 7] = ["Mon", "Tue", "Wed", "Thu", "Fri", "Sat", "Sun"];
+const LONG_DAYS: [&str; 7] = [
+    "Monday",
+    "Tuesday",
+    "Wednesday",
+    "Thursday",
+    "Friday",
+    "Saturday",
+    "Sunday",
+];
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+// A point in time with the one second precision of HTTP dates, see
+// RFC 9110 5.6.7. Dates before 1970 are not representable.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct HttpDate {
+    secs: u64,
+}
+
+impl HttpDate {
+    pub fn now() -> Self {
+        Self::from(SystemTime::now())
+    }
+
+    pub fn from_secs(secs: u64) -> Self {
+        Self { secs }
+    }
+
+    // Seconds since the Unix epoch
+    pub fn secs(&self) -> u64 {
+        self.secs
+    }
+
+    // Accepts IMF-fixdate ("Sun, 06 Nov 1994 08:49:37 GMT") and the obsolete
+    // RFC 850 ("Sunday, 06-Nov-94 08:49:37 GMT") and asctime
+    // ("Sun Nov  6 08:49:37 1994") formats recipients have to understand
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let parts: Vec<&str> = value.split_whitespace().collect();
+
+        let parsed = match parts[..] {
+            [weekday, day, month, year, time, "GMT"]
+                if DAYS.contains(&weekday.trim_end_matches(',')) =>
+            {
+                parse_fields(day, month, year, time)
+            }
+            [weekday, date, time, "GMT"] if LONG_DAYS.contains(&weekday.trim_end_matches(',')) => {
+                parse_rfc850(date, time)
+            }
+            [weekday, month, day, time, year] if DAYS.contains(&weekday) => {
+                parse_fields(day, month, year, time)
+            }
+            _ => None,
+        };
+
+        parsed.ok_or_else(|| format!("Invalid HTTP date: {}", value))
+    }
+}
+
+// RFC 850 dates have two digit years, which RFC 9110 5.6.7 says to read as
+// the most recent year with those digits that isn't over 50 years ahead
+fn parse_rfc850(date: &str, time: &str) -> Option<HttpDate> {
+    let [day, month, year] = date.split('-').collect::<Vec<_>>()[..] else {
+        return None;
+    };
+
+    if year.len() != 2 {
+        return None;
+    }
+
+    let year: i64 = year.parse().ok()?;
+    let (current, _, _) = civil_from_days((HttpDate::now().secs / 86400) as i64);
+    let mut year = current - current.rem_euclid(100) + year;
+    if year > current + 50 {
+        year -= 100;
+    }
+
+    parse_fields(day, month, &year.to_string(), time)
+}
+
+fn parse_fields(day: &str, month: &str, year: &str, time: &str) -> Option<HttpDate> {
+    // All formats carry a four digit year, which also keeps the arithmetic
+    // below far from overflowing
+    if year.len() != 4 || !year.bytes().all(|it| it.is_ascii_digit()) {
+        return None;
+    }
+
+    let day: u32 = day.parse().ok()?;
+    let month = MONTHS.iter().position(|it| *it == month)? as u32 + 1;
+    let year: i64 = year.parse().ok()?;
+
+    let time: Vec<u64> = time
+        .split(':')
+        .map(|it| it.parse().ok())
+        .collect::<Option<_>>()?;
+    let [hour, minute, second] = time[..] else {
+        return None;
+    };
+
+    if day == 0
+        || day > days_in_month(year, month)
+        || hour > 23
+        || minute > 59
+        || second > 60
+        || year < 1970
+    {
+        return None;
+    }
+
+    let days = u64::try_from(days_from_civil(year, month, day)).ok()?;
+    days.checked_mul(86400)?
+        .checked_add(hour * 3600 + minute * 60 + second)
+        .map(HttpDate::from_secs)
+}
+
+fn days_in_month(year: i64, month: u32) -> u32 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+// Always the IMF-fixdate format, the only one senders may generate
+impl fmt::Display for HttpDate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let days = self.secs / 86400;
+        let (year, month, day) = civil_from_days(days as i64);
+        let time_of_day = self.secs % 86400;
+
+        write!(
+            f,
+            "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+            // 1970-01-01 was a Thursday
+            DAYS[((days + 3) % 7) as usize],
+            day,
+            MONTHS[month as usize - 1],
+            year,
+            time_of_day / 3600,
+            time_of_day % 3600 / 60,
+            time_of_day % 60
+        )
+    }
+}
+
+impl FromStr for HttpDate {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        Self::parse(value)
+    }
+}
+
+// Sub-second precision is dropped, times before 1970 become 1970
+impl From<SystemTime> for HttpDate {
+    fn from(time: SystemTime) -> Self {
+        Self::from_secs(time.duration_since(UNIX_EPOCH).map_or(0, |it| it.as_secs()))
+    }
+}
+
+impl From<HttpDate> for SystemTime {
+    fn from(date: HttpDate) -> Self {
+        UNIX_EPOCH + Duration::from_secs(date.secs)
+    }
+}
+
+// The current time formatted for the Date header, reformatted at most once
+// a second per thread
+pub fn date_header() -> String {
+    thread_local! {
+        static CACHED: RefCell<(u64, String)> = const { RefCell::new((u64::MAX, String::new())) };
+    }
+
+    let now = HttpDate::now();
+    CACHED.with(|cached| {
+        let mut cached = cached.borrow_mut();
+        if cached.0 != now.secs {
+            *cached = (now.secs, now.to_string());
+        }
+        cached.1.clone()
+    })
+}
+
+// Days since 1970-01-01 to a proleptic Gregorian date and back, see
+// http://howardhinnant.github.io/date_algorithms.html
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month = month as i64;
+    let day_of_year =
+        (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 1994-11-06 08:49:37 UTC, the example of RFC 9110 5.6.7
+    const EXAMPLE: u64 = 784111777;
+
+    #[test]
+    fn parses_all_three_formats() {
+        let imf = HttpDate::parse("Sun, 06 Nov 1994 08:49:37 GMT").unwrap();
+        let asctime = HttpDate::parse("Sun Nov  6 08:49:37 1994").unwrap();
+
+        assert_eq!(imf.secs(), EXAMPLE);
+        assert_eq!(asctime.secs(), EXAMPLE);
+        // Two digit years read as the closest century that isn't over 50
+        // years ahead, 94 is 1994 until 2044
+        assert_eq!(
+            HttpDate::parse("Sunday, 06-Nov-94 08:49:37 GMT")
+                .unwrap()
+                .secs(),
+            EXAMPLE
+        );
+    }
+
+    #[test]
+    fn formats_imf_fixdate() {
+        let date = HttpDate::from_secs(EXAMPLE);
+        assert_eq!(date.to_string(), "Sun, 06 Nov 1994 08:49:37 GMT");
+        assert_eq!(HttpDate::parse(&date.to_string()), Ok(date));
+    }
+
+    #[test]
+    fn rejects_years_that_are_not_four_digits() {
+        assert!(HttpDate::parse("Sun, 06 Nov 99999999999999 08:49:37 GMT").is_err());
+        assert!(HttpDate::parse("Sun Nov  6 08:49:37 600000000000").is_err());
+        assert!(HttpDate::parse("Sun, 06 Nov 10000 08:49:37 GMT").is_err());
+        assert!(HttpDate::parse("Sun, 06 Nov 994 08:49:37 GMT").is_err());
+        assert!(HttpDate::parse("Sun, 06 Nov +994 08:49:37 GMT").is_err());
+        assert!(HttpDate::parse("Sunday, 06-Nov-1994 08:49:37 GMT").is_err());
+    }
+
+    #[test]
+    fn accepts_the_last_representable_year() {
+        let date = HttpDate::parse("Fri, 31 Dec 9999 23:59:59 GMT").unwrap();
+        assert_eq!(date.to_string(), "Fri, 31 Dec 9999 23:59:59 GMT");
+    }
+
+    #[test]
+    fn rejects_days_past_the_end_of_the_month() {
+        assert!(HttpDate::parse("Sat, 31 Feb 2024 00:00:00 GMT").is_err());
+        assert!(HttpDate::parse("Thu, 31 Apr 2024 00:00:00 GMT").is_err());
+        assert!(HttpDate::parse("Sun, 29 Feb 2023 00:00:00 GMT").is_err());
+        assert!(HttpDate::parse("Tue, 29 Feb 2000 00:00:00 GMT").is_ok());
+        assert!(HttpDate::parse("Thu, 29 Feb 2024 00:00:00 GMT").is_ok());
+        assert!(HttpDate::parse("Mon, 29 Feb 2100 00:00:00 GMT").is_err());
+        assert!(HttpDate::parse("Mon, 00 Jan 2024 00:00:00 GMT").is_err());
+    }
+
+    #[test]
+    fn rejects_malformed_dates() {
+        assert!(HttpDate::parse("").is_err());
+        assert!(HttpDate::parse("Sun, 06 Nov 1994 08:49:37 UTC").is_err());
+        assert!(HttpDate::parse("Sun, 06 Foo 1994 08:49:37 GMT").is_err());
+        assert!(HttpDate::parse("Sun, 06 Nov 1994 24:00:00 GMT").is_err());
+        assert!(HttpDate::parse("Sun, 06 Nov 1994 08:49 GMT").is_err());
+        assert!(HttpDate::parse("Sun, 06 Nov 1969 08:49:37 GMT").is_err());
+    }
+}
@@ -0,0 +1,375 @@
+use crate::compression::add_vary;
+use crate::models::HttpRequest;
+use crate::negotiate::{media_quality, parse_accept, MediaRange};
+use crate::response::{HttpResponse, Status};
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+
+// Why a request could not be read off the connection
+#[derive(Debug)]
+pub enum ParseError {
+    Io(io::Error),
+    // The connection closed before a full request arrived
+    Incomplete,
+    Malformed(String),
+    UriTooLong,
+    HeadersTooLarge,
+    UnsupportedVersion(u32, u32),
+    NotImplemented(String),
+}
+
+impl ParseError {
+    pub fn status(&self) -> Status {
+        match self {
+            ParseError::Io(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                Status::RequestTimeout
+            }
+            ParseError::Io(_) | ParseError::Incomplete | ParseError::Malformed(_) => {
+                Status::BadRequest
+            }
+            ParseError::UriTooLong => Status::UriTooLong,
+            ParseError::HeadersTooLarge => Status::RequestHeaderFieldsTooLarge,
+            ParseError::UnsupportedVersion(_, _) => Status::HttpVersionNotSupported,
+            ParseError::NotImplemented(_) => Status::NotImplemented,
+        }
+    }
+
+    // Whether the client can still be sent a response
+    pub fn can_respond(&self) -> bool {
+        match self {
+            ParseError::Io(_) => self.status() == Status::RequestTimeout,
+            ParseError::Incomplete => false,
+            _ => true,
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseError::Io(e) => write!(f, "{}", e),
+            ParseError::Incomplete => write!(f, "Connection closed mid request"),
+            ParseError::Malformed(msg) => write!(f, "{}", msg),
+            ParseError::UriTooLong => write!(f, "Request line too long"),
+            ParseError::HeadersTooLarge => write!(f, "Request headers too large"),
+            ParseError::UnsupportedVersion(major, minor) => {
+                write!(f, "Unsupported version HTTP/{}.{}", major, minor)
+            }
+            ParseError::NotImplemented(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl From<io::Error> for ParseError {
+    fn from(e: io::Error) -> Self {
+        ParseError::Io(e)
+    }
+}
+
+// An error response that error handlers get to render, see
+// HttpResponse::error
+#[derive(Clone, Debug)]
+pub struct HttpError {
+    pub status: Status,
+    pub message: String,
+    // The Accept header of the request, set before the error handlers run
+    // so they can pick a format
+    pub accept: Option<String>,
+}
+
+impl HttpError {
+    pub fn new<S: Into<String>>(status: Status, message: S) -> Self {
+        Self {
+            status,
+            message: message.into(),
+            accept: None,
+        }
+    }
+}
+
+impl From<&ParseError> for HttpError {
+    fn from(e: &ParseError) -> Self {
+        HttpError::new(e.status(), e.to_string())
+    }
+}
+
+impl fmt::Display for HttpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.status, self.message)
+    }
+}
+
+// Fills in the body of an error response. The response arrives with the
+// default plain text body and any headers the error needs (e.g. Allow).
+pub type ErrorHandler = Box<dyn Fn(&HttpError, &mut HttpResponse) + Send + Sync>;
+
+#[derive(Default)]
+pub struct ErrorHandlers {
+    by_status: HashMap<u16, ErrorHandler>,
+    fallback: Option<ErrorHandler>,
+}
+
+impl ErrorHandlers {
+    pub fn new() -> Self {
+        Self {
+            by_status: HashMap::new(),
+            fallback: None,
+        }
+    }
+
+    pub fn insert<F>(&mut self, status: Status, handler: F)
+    where
+        F: Fn(&HttpError, &mut HttpResponse) + Send + Sync + 'static,
+    {
+        self.by_status.insert(status.code(), Box::new(handler));
+    }
+
+    pub fn set_fallback<F>(&mut self, handler: F)
+    where
+        F: Fn(&HttpError, &mut HttpResponse) + Send + Sync + 'static,
+    {
+        self.fallback = Some(Box::new(handler));
+    }
+
+    // Renders `response` if it is an unrendered error one of the handlers
+    // covers. Rendered responses are left alone by handlers further out.
+    // `request` is None when the request failed to parse.
+    pub fn apply(&self, request: Option<&HttpRequest>, response: &mut HttpResponse) {
+        let Some(error) = &response.error else {
+            return;
+        };
+
+        let handler = match self.by_status.get(&error.status.code()) {
+            Some(handler) => handler,
+            None => match &self.fallback {
+                Some(handler) => handler,
+                None => return,
+            },
+        };
+
+        if let Some(mut error) = response.error.take() {
+            error.accept = request.and_then(|it| it.header("Accept"));
+            handler(&error, response);
+        }
+    }
+}
+
+// Ready made error handlers, e.g. router.fallback_error_handler(html_page)
+pub fn html_page(error: &HttpError, response: &mut HttpResponse) {
+    let status = escape_html(&error.status.to_string());
+    let body = format!(
+        "<!DOCTYPE html>\n<html>\n<head><title>{}</title></head>\n<body>\n<h1>{}</h1>\n<p>{}</p>\n</body>\n</html>\n",
+        status,
+        status,
+        escape_html(&error.message)
+    );
+
+    let _ = response.insert_header("Content-Type", "text/html; charset=utf-8");
+    response.body = body.into();
+}
+
+pub fn json_body(error: &HttpError, response: &mut HttpResponse) {
+    let body = format!(
+        "{{\"status\":{},\"error\":\"{}\",\"message\":\"{}\"}}\n",
+        error.status.code(),
+        escape_json(error.status.reason()),
+        escape_json(&error.message)
+    );
+
+    let _ = response.insert_header("Content-Type", "application/json");
+    response.body = body.into();
+}
+
+// html_page or json_body, whichever the request's Accept header prefers.
+// HTML wins ties and requests without one.
+pub fn html_or_json(error: &HttpError, response: &mut HttpResponse) {
+    let accept = parse_accept(error.accept.as_deref().unwrap_or("*/*"));
+    let quality = |media: &str| {
+        MediaRange::parse(media)
+            .and_then(|it| media_quality(&accept, &it))
+            .unwrap_or(0)
+    };
+
+    if quality("application/json") > quality("text/html") {
+        json_body(error, response);
+    } else {
+        html_page(error, response);
+    }
+    add_vary(response, "Accept");
+}
+
+pub fn escape_html(content: &str) -> String {
+    let mut escaped = String::with_capacity(content.len());
+    for c in content.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+pub fn escape_json(content: &str) -> String {
+    let mut escaped = String::with_capacity(content.len());
+    for c in content.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::test_request;
+    use crate::response::Body;
+
+    fn body(response: &HttpResponse) -> String {
+        match &response.body {
+            Body::Bytes(bytes) => String::from_utf8(bytes.clone()).unwrap(),
+            _ => panic!("Expected a body of bytes"),
+        }
+    }
+
+    fn render(
+        errors: &ErrorHandlers,
+        request: Option<&HttpRequest>,
+        status: Status,
+    ) -> HttpResponse {
+        let mut response = HttpResponse::error(status);
+        errors.apply(request, &mut response);
+        response
+    }
+
+    #[test]
+    fn maps_parse_errors_to_statuses() {
+        let timeout = ParseError::Io(io::ErrorKind::TimedOut.into());
+        assert_eq!(timeout.status(), Status::RequestTimeout);
+        assert!(timeout.can_respond());
+
+        let reset = ParseError::Io(io::ErrorKind::ConnectionReset.into());
+        assert_eq!(reset.status(), Status::BadRequest);
+        assert!(!reset.can_respond());
+
+        assert_eq!(ParseError::Incomplete.status(), Status::BadRequest);
+        assert!(!ParseError::Incomplete.can_respond());
+
+        for (error, status) in [
+            (ParseError::Malformed("x".into()), Status::BadRequest),
+            (ParseError::UriTooLong, Status::UriTooLong),
+            (
+                ParseError::HeadersTooLarge,
+                Status::RequestHeaderFieldsTooLarge,
+            ),
+            (
+                ParseError::UnsupportedVersion(2, 0),
+                Status::HttpVersionNotSupported,
+            ),
+            (
+                ParseError::NotImplemented("x".into()),
+                Status::NotImplemented,
+            ),
+        ] {
+            assert_eq!(error.status(), status, "{}", error);
+            assert!(error.can_respond(), "{}", error);
+        }
+    }
+
+    #[test]
+    fn prefers_handlers_for_the_status_over_the_fallback() {
+        let mut errors = ErrorHandlers::new();
+        errors.insert(Status::NotFound, |_, response| {
+            response.body = "missing".into()
+        });
+        errors.set_fallback(|error, response| {
+            response.body = format!("fallback {}", error.status.code()).into()
+        });
+
+        assert_eq!(body(&render(&errors, None, Status::NotFound)), "missing");
+        assert_eq!(
+            body(&render(&errors, None, Status::BadRequest)),
+            "fallback 400"
+        );
+
+        // Only once, handlers further out see a rendered response
+        let mut response = render(&errors, None, Status::NotFound);
+        ErrorHandlers::new().apply(None, &mut response);
+        errors.apply(None, &mut response);
+        assert_eq!(body(&response), "missing");
+        assert!(response.error.is_none());
+    }
+
+    #[test]
+    fn leaves_errors_without_a_handler_alone() {
+        let response = render(&ErrorHandlers::new(), None, Status::NotFound);
+        assert_eq!(body(&response), "404 Not Found\n");
+        assert!(response.error.is_some());
+
+        let mut response = HttpResponse::text(Status::Ok, "fine");
+        let mut errors = ErrorHandlers::new();
+        errors.set_fallback(html_page);
+        errors.apply(None, &mut response);
+        assert_eq!(body(&response), "fine");
+    }
+
+    #[test]
+    fn picks_html_or_json_by_accept() {
+        let mut errors = ErrorHandlers::new();
+        errors.set_fallback(html_or_json);
+        let content_type = |accept: Option<&str>| {
+            let headers: Vec<_> = accept.map(|it| ("Accept", it)).into_iter().collect();
+            let request = test_request("GET", "/", &headers);
+            let response = render(&errors, Some(&request), Status::NotFound);
+            assert_eq!(response.get_header("Vary").as_deref(), Some("Accept"));
+            response.get_header("Content-Type").unwrap()
+        };
+
+        assert_eq!(content_type(Some("application/json")), "application/json");
+        assert_eq!(
+            content_type(Some("text/html;q=0.5, application/*")),
+            "application/json"
+        );
+        assert_eq!(
+            content_type(Some("text/html, application/json")),
+            "text/html; charset=utf-8"
+        );
+        assert_eq!(content_type(None), "text/html; charset=utf-8");
+        assert_eq!(content_type(Some("image/png")), "text/html; charset=utf-8");
+    }
+
+    #[test]
+    fn escapes_messages() {
+        let error = HttpError::new(Status::BadRequest, "<script>\"\\'&</script>\n");
+
+        let mut response = HttpResponse::from_error(error.clone());
+        html_page(&error, &mut response);
+        let html = body(&response);
+        assert!(html.contains("<p>&lt;script&gt;&quot;\\&#39;&amp;&lt;/script&gt;\n</p>"));
+        assert!(!html.contains("<script>"));
+
+        json_body(&error, &mut response);
+        assert_eq!(
+            body(&response),
+            "{\"status\":400,\"error\":\"Bad Request\",\
+             \"message\":\"<script>\\\"\\\\'&</script>\\n\"}\n"
+        );
+        assert_eq!(escape_json("\u{1}\t\r"), "\\u0001\\t\\r");
+    }
+}
@@ -0,0 +1,141 @@
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+// Per-request values keyed by their type, e.g. the authenticated user or a
+// request id that middleware attaches for the handlers further in
+#[derive(Default)]
+pub struct Extensions {
+    map: HashMap<TypeId, Box<dyn Any + Send + Sync>>,
+}
+
+impl Extensions {
+    pub fn new() -> Self {
+        Self {
+            map: HashMap::new(),
+        }
+    }
+
+    pub fn insert<T: Send + Sync + 'static>(&mut self, value: T) -> Option<T> {
+        self.map
+            .insert(TypeId::of::<T>(), Box::new(value))
+            .and_then(|old| old.downcast().ok().map(|it| *it))
+    }
+
+    pub fn get<T: 'static>(&self) -> Option<&T> {
+        self.map.get(&TypeId::of::<T>())?.downcast_ref()
+    }
+
+    pub fn get_mut<T: 'static>(&mut self) -> Option<&mut T> {
+        self.map.get_mut(&TypeId::of::<T>())?.downcast_mut()
+    }
+
+    pub fn remove<T: 'static>(&mut self) -> Option<T> {
+        self.map
+            .remove(&TypeId::of::<T>())?
+            .downcast()
+            .ok()
+            .map(|it| *it)
+    }
+
+    pub fn contains<T: 'static>(&self) -> bool {
+        self.map.contains_key(&TypeId::of::<T>())
+    }
+}
+
+impl fmt::Debug for Extensions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Extensions({} entries)", self.map.len())
+    }
+}
+
+// Application state shared by every request, keyed by type. Cloning only
+// bumps reference counts.
+#[derive(Clone, Default)]
+pub struct StateMap {
+    map: HashMap<TypeId, Arc<dyn Any + Send + Sync>>,
+}
+
+impl StateMap {
+    pub fn new() -> Self {
+        Self {
+            map: HashMap::new(),
+        }
+    }
+
+    pub fn insert<T: Send + Sync + 'static>(&mut self, value: T) {
+        self.map.insert(TypeId::of::<T>(), Arc::new(value));
+    }
+
+    pub fn get<T: Send + Sync + 'static>(&self) -> Option<Arc<T>> {
+        self.map.get(&TypeId::of::<T>())?.clone().downcast().ok()
+    }
+
+    // Entries of `other` replace entries of the same type here
+    pub fn extend(&mut self, other: &StateMap) {
+        for (key, value) in &other.map {
+            self.map.insert(*key, Arc::clone(value));
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+}
+
+impl fmt::Debug for StateMap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "StateMap({} entries)", self.map.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq)]
+    struct User(&'static str);
+
+    #[test]
+    fn extensions_are_keyed_by_type() {
+        let mut extensions = Extensions::new();
+        assert_eq!(extensions.insert(User("ann")), None);
+        assert_eq!(extensions.insert(7u32), None);
+
+        assert_eq!(extensions.get::<User>(), Some(&User("ann")));
+        assert_eq!(extensions.get::<u32>(), Some(&7));
+        assert_eq!(extensions.get::<u64>(), None);
+
+        assert_eq!(extensions.insert(User("bob")), Some(User("ann")));
+        *extensions.get_mut::<u32>().unwrap() += 1;
+        assert_eq!(extensions.get::<u32>(), Some(&8));
+
+        assert_eq!(extensions.remove::<User>(), Some(User("bob")));
+        assert!(!extensions.contains::<User>());
+        assert!(extensions.contains::<u32>());
+    }
+
+    #[test]
+    fn state_is_shared_and_replaced_by_type() {
+        let mut state = StateMap::new();
+        assert!(state.is_empty());
+        state.insert(User("ann"));
+        state.insert(1u32);
+
+        let user = state.get::<User>().unwrap();
+        assert!(Arc::ptr_eq(&user, &state.clone().get::<User>().unwrap()));
+
+        let mut inner = StateMap::new();
+        inner.insert(2u32);
+        state.extend(&inner);
+        assert_eq!(*state.get::<u32>().unwrap(), 2);
+        assert_eq!(*state.get::<User>().unwrap(), User("ann"));
+        assert!(state.get::<u64>().is_none());
+
+        // Earlier handles keep the value they got
+        state.insert(User("bob"));
+        assert_eq!(*user, User("ann"));
+        assert_eq!(*state.get::<User>().unwrap(), User("bob"));
+    }
+}
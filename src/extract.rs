@@ -0,0 +1,492 @@
+use crate::body::{RequestBody, TooLarge};
+use crate::date::HttpDate;
+use crate::error::HttpError;
+use crate::models::{parse_host_from_wire, EntityHeader, Host, HttpRequest, Method};
+use crate::parsing::urlencoded::parse_urlencoded;
+use crate::response::{HttpResponse, Interim, IntoResponse, Status};
+use crate::router::LastParam;
+use std::collections::HashMap;
+use std::io::Read;
+use std::ops::Deref;
+use std::str::FromStr;
+use std::sync::Arc;
+
+// Why a request could not be turned into a handler argument
+#[derive(Debug)]
+pub struct Rejection {
+    pub status: Status,
+    pub message: String,
+}
+
+impl Rejection {
+    pub fn new<S: Into<String>>(status: Status, message: S) -> Self {
+        Self {
+            status,
+            message: message.into(),
+        }
+    }
+
+    pub fn bad_request<S: Into<String>>(message: S) -> Self {
+        Self::new(Status::BadRequest, message)
+    }
+}
+
+impl IntoResponse for Rejection {
+    fn into_response(self) -> HttpResponse {
+        let mut response =
+            HttpResponse::text(self.status, format!("{}: {}\n", self.status, self.message));
+        response.error = Some(HttpError::new(self.status, self.message));
+        response
+    }
+}
+
+pub trait FromRequest: Sized {
+    fn from_request(request: &mut HttpRequest) -> Result<Self, Rejection>;
+}
+
+// Missing or malformed values become None instead of failing the request
+impl<T: FromRequest> FromRequest for Option<T> {
+    fn from_request(request: &mut HttpRequest) -> Result<Self, Rejection> {
+        Ok(T::from_request(request).ok())
+    }
+}
+
+impl<T: FromRequest> FromRequest for Result<T, Rejection> {
+    fn from_request(request: &mut HttpRequest) -> Result<Self, Rejection> {
+        Ok(T::from_request(request))
+    }
+}
+
+impl FromRequest for Method {
+    fn from_request(request: &mut HttpRequest) -> Result<Self, Rejection> {
+        Ok(request.request_line.method.clone())
+    }
+}
+
+// Sends 1xx responses such as 103 Early Hints before the final response
+impl FromRequest for Interim {
+    fn from_request(request: &mut HttpRequest) -> Result<Self, Rejection> {
+        request.extensions.get::<Interim>().cloned().ok_or_else(|| {
+            Rejection::new(
+                Status::InternalServerError,
+                "Interim responses are only available when served by Server",
+            )
+        })
+    }
+}
+
+// Shared state registered with Router::state or Server::state
+#[derive(Debug)]
+pub struct State<T>(pub Arc<T>);
+
+impl<T: Send + Sync + 'static> FromRequest for State<T> {
+    fn from_request(request: &mut HttpRequest) -> Result<Self, Rejection> {
+        request.state.get::<T>().map(State).ok_or_else(|| {
+            Rejection::new(
+                Status::InternalServerError,
+                format!("No state of type {} registered", std::any::type_name::<T>()),
+            )
+        })
+    }
+}
+
+impl<T> Deref for State<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+// A clone of a value middleware put into the request extensions
+#[derive(Debug)]
+pub struct Extension<T>(pub T);
+
+impl<T: Clone + Send + Sync + 'static> FromRequest for Extension<T> {
+    fn from_request(request: &mut HttpRequest) -> Result<Self, Rejection> {
+        request
+            .extensions
+            .get::<T>()
+            .cloned()
+            .map(Extension)
+            .ok_or_else(|| {
+                Rejection::new(
+                    Status::InternalServerError,
+                    format!("No extension of type {} set", std::any::type_name::<T>()),
+                )
+            })
+    }
+}
+
+// The last parameter of the matched route parsed as T, e.g. `id` for
+// /users/:id. A route without parameters takes the last one of the prefix
+// it is nested under. Routes with more parameters read the others by name
+// with Params::parse.
+#[derive(Debug)]
+pub struct Path<T>(pub T);
+
+impl<T: FromStr> FromRequest for Path<T> {
+    fn from_request(request: &mut HttpRequest) -> Result<Self, Rejection> {
+        // Neither the route nor the prefixes it is nested under have one
+        let LastParam(name) = request.extensions.get::<LastParam>().ok_or_else(|| {
+            Rejection::new(
+                Status::InternalServerError,
+                "Path used on a route without parameters".to_string(),
+            )
+        })?;
+        let value = request
+            .params
+            .get(name)
+            .map(String::as_str)
+            .unwrap_or_default();
+
+        value.parse::<T>().map(Path).map_err(|_| {
+            Rejection::bad_request(format!("Invalid path parameter {}: {}", name, value))
+        })
+    }
+}
+
+// Every route parameter by name
+#[derive(Debug)]
+pub struct Params(pub HashMap<String, String>);
+
+impl FromRequest for Params {
+    fn from_request(request: &mut HttpRequest) -> Result<Self, Rejection> {
+        Ok(Params(request.params.clone()))
+    }
+}
+
+impl Params {
+    // The parameter `name` parsed as T, e.g. `team` for /teams/:team/:id
+    pub fn parse<T: FromStr>(&self, name: &str) -> Result<T, Rejection> {
+        let value = self
+            .0
+            .get(name)
+            .ok_or_else(|| Rejection::bad_request(format!("Missing path parameter {}", name)))?;
+
+        value.parse::<T>().map_err(|_| {
+            Rejection::bad_request(format!("Invalid path parameter {}: {}", name, value))
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct Query(pub HashMap<String, String>);
+
+impl FromRequest for Query {
+    fn from_request(request: &mut HttpRequest) -> Result<Self, Rejection> {
+        let query = request.request_line.query().unwrap_or_default();
+        parse_urlencoded(query)
+            .map(Query)
+            .map_err(Rejection::bad_request)
+    }
+}
+
+// A header value with a known name and wire format
+pub trait TypedHeader: Sized {
+    fn name() -> &'static str;
+    fn decode(value: &str) -> Result<Self, String>;
+}
+
+#[derive(Debug)]
+pub struct Header<H>(pub H);
+
+impl<H: TypedHeader> FromRequest for Header<H> {
+    fn from_request(request: &mut HttpRequest) -> Result<Self, Rejection> {
+        let value = request
+            .header(H::name())
+            .ok_or_else(|| Rejection::bad_request(format!("Missing header {}", H::name())))?;
+
+        H::decode(&value)
+            .map(Header)
+            .map_err(|e| Rejection::bad_request(format!("Invalid header {}: {}", H::name(), e)))
+    }
+}
+
+impl TypedHeader for Host {
+    fn name() -> &'static str {
+        "Host"
+    }
+
+    fn decode(value: &str) -> Result<Self, String> {
+        parse_host_from_wire(value)
+    }
+}
+
+macro_rules! string_header {
+    ($name:ident, $wire:expr) => {
+        #[derive(Debug, Clone, PartialEq, Eq)]
+        pub struct $name(pub String);
+
+        impl TypedHeader for $name {
+            fn name() -> &'static str {
+                $wire
+            }
+
+            fn decode(value: &str) -> Result<Self, String> {
+                Ok($name(value.to_string()))
+            }
+        }
+    };
+}
+
+string_header!(Authorization, "Authorization");
+string_header!(UserAgent, "User-Agent");
+string_header!(Referer, "Referer");
+string_header!(ContentType, "Content-Type");
+
+macro_rules! date_header {
+    ($name:ident, $wire:expr) => {
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        pub struct $name(pub HttpDate);
+
+        impl TypedHeader for $name {
+            fn name() -> &'static str {
+                $wire
+            }
+
+            fn decode(value: &str) -> Result<Self, String> {
+                HttpDate::parse(value).map($name)
+            }
+        }
+    };
+}
+
+date_header!(Date, "Date");
+date_header!(IfModifiedSince, "If-Modified-Since");
+date_header!(IfUnmodifiedSince, "If-Unmodified-Since");
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ContentLength(pub usize);
+
+impl TypedHeader for ContentLength {
+    fn name() -> &'static str {
+        "Content-Length"
+    }
+
+    fn decode(value: &str) -> Result<Self, String> {
+        value
+            .parse::<usize>()
+            .map(ContentLength)
+            .map_err(|e| e.to_string())
+    }
+}
+
+// Largest body the buffering extractors read into memory, take the
+// RequestBody itself to stream anything bigger
+pub const MAX_BUFFERED_BODY: u64 = 16 * 1024 * 1024;
+
+fn take_stream(request: &mut HttpRequest) -> Result<RequestBody, Rejection> {
+    request
+        .body
+        .take()
+        .ok_or_else(|| Rejection::bad_request("Request body is missing or was already read"))
+}
+
+fn take_body(request: &mut HttpRequest) -> Result<Vec<u8>, Rejection> {
+    let too_large = || {
+        Rejection::new(
+            Status::ContentTooLarge,
+            format!("Request body is larger than {} bytes", MAX_BUFFERED_BODY),
+        )
+    };
+
+    let body = take_stream(request)?;
+    if body.length().is_some_and(|it| it > MAX_BUFFERED_BODY) {
+        return Err(too_large());
+    }
+
+    let mut bytes = Vec::new();
+    body.take(MAX_BUFFERED_BODY + 1)
+        .read_to_end(&mut bytes)
+        .map_err(|e| {
+            if TooLarge::caused(&e) {
+                Rejection::new(Status::ContentTooLarge, e.to_string())
+            } else {
+                Rejection::bad_request(format!("Failed to read request body: {}", e))
+            }
+        })?;
+
+    if bytes.len() as u64 > MAX_BUFFERED_BODY {
+        return Err(too_large());
+    }
+
+    Ok(bytes)
+}
+
+fn take_body_text(request: &mut HttpRequest) -> Result<String, Rejection> {
+    String::from_utf8(take_body(request)?)
+        .map_err(|_| Rejection::bad_request("Request body is not valid UTF-8"))
+}
+
+fn expect_content_type(request: &HttpRequest, expected: &str) -> Result<(), Rejection> {
+    let content_type = request
+        .entity_headers
+        .get(EntityHeader::ContentType)
+        .unwrap_or_default();
+    let essence = content_type.split(';').next().unwrap_or_default().trim();
+
+    if essence.eq_ignore_ascii_case(expected) {
+        Ok(())
+    } else {
+        Err(Rejection::new(
+            Status::UnsupportedMediaType,
+            format!("Expected Content-Type {}", expected),
+        ))
+    }
+}
+
+// The body extractors consume the body, so a handler gets at most one of them
+impl FromRequest for RequestBody {
+    fn from_request(request: &mut HttpRequest) -> Result<Self, Rejection> {
+        take_stream(request)
+    }
+}
+
+impl FromRequest for String {
+    fn from_request(request: &mut HttpRequest) -> Result<Self, Rejection> {
+        take_body_text(request)
+    }
+}
+
+#[derive(Debug)]
+pub struct Bytes(pub Vec<u8>);
+
+impl FromRequest for Bytes {
+    fn from_request(request: &mut HttpRequest) -> Result<Self, Rejection> {
+        take_body(request).map(Bytes)
+    }
+}
+
+#[derive(Debug)]
+pub struct Form(pub HashMap<String, String>);
+
+impl FromRequest for Form {
+    fn from_request(request: &mut HttpRequest) -> Result<Self, Rejection> {
+        expect_content_type(request, "application/x-www-form-urlencoded")?;
+        let body = take_body_text(request)?;
+        parse_urlencoded(&body)
+            .map(Form)
+            .map_err(Rejection::bad_request)
+    }
+}
+
+#[cfg(feature = "json")]
+#[derive(Debug)]
+pub struct Json<T>(pub T);
+
+#[cfg(feature = "json")]
+impl<T: serde::de::DeserializeOwned> FromRequest for Json<T> {
+    fn from_request(request: &mut HttpRequest) -> Result<Self, Rejection> {
+        expect_content_type(request, "application/json")?;
+        let body = take_body(request)?;
+        serde_json::from_slice(&body)
+            .map(Json)
+            .map_err(|e| Rejection::bad_request(format!("Invalid JSON body: {}", e)))
+    }
+}
+
+#[cfg(feature = "json")]
+impl<T: serde::Serialize> IntoResponse for Json<T> {
+    fn into_response(self) -> HttpResponse {
+        match serde_json::to_vec(&self.0) {
+            Ok(body) => HttpResponse::new(Status::Ok)
+                .header("Content-Type", "application/json")
+                .body(body),
+            Err(_) => HttpResponse::error(Status::InternalServerError),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{test_request, test_request_with_body};
+    use std::io;
+
+    fn form(content_type: &str, body: &[u8]) -> HttpRequest {
+        test_request_with_body("POST", "/", &[("Content-Type", content_type)], body)
+    }
+
+    #[test]
+    fn query_decodes_the_query_string() {
+        let mut request = test_request("GET", "/search?q=a+b%21&page=2", &[]);
+        let Query(query) = Query::from_request(&mut request).unwrap();
+        assert_eq!(query["q"], "a b!");
+        assert_eq!(query["page"], "2");
+
+        let mut request = test_request("GET", "/search", &[]);
+        assert!(Query::from_request(&mut request).unwrap().0.is_empty());
+
+        let mut request = test_request("GET", "/search?q=%+f", &[]);
+        let rejection = Query::from_request(&mut request).unwrap_err();
+        assert_eq!(rejection.status, Status::BadRequest);
+    }
+
+    #[test]
+    fn form_needs_its_content_type() {
+        let mut request = form(
+            "application/x-www-form-urlencoded; charset=utf-8",
+            b"a=1&b=x+y",
+        );
+        let Form(fields) = Form::from_request(&mut request).unwrap();
+        assert_eq!(fields["a"], "1");
+        assert_eq!(fields["b"], "x y");
+
+        let mut request = form("text/plain", b"a=1");
+        let rejection = Form::from_request(&mut request).unwrap_err();
+        assert_eq!(rejection.status, Status::UnsupportedMediaType);
+        // Rejected before reading, so the body is still there
+        assert!(request.body.is_some());
+    }
+
+    #[test]
+    fn body_extractors_take_the_body_once() {
+        let mut request = form("application/octet-stream", b"\x00\xff");
+        let Bytes(bytes) = Bytes::from_request(&mut request).unwrap();
+        assert_eq!(bytes, b"\x00\xff");
+
+        let rejection = Bytes::from_request(&mut request).unwrap_err();
+        assert_eq!(rejection.status, Status::BadRequest);
+    }
+
+    #[test]
+    fn string_needs_utf8() {
+        let mut request = form("text/plain", "héllo".as_bytes());
+        assert_eq!(String::from_request(&mut request).unwrap(), "héllo");
+
+        let mut request = form("text/plain", b"\xff\xfe");
+        let rejection = String::from_request(&mut request).unwrap_err();
+        assert_eq!(rejection.status, Status::BadRequest);
+    }
+
+    #[test]
+    fn rejects_bodies_over_the_limit() {
+        let mut request = test_request("POST", "/", &[]);
+        request.body = Some(RequestBody::new(io::empty()).with_length(Some(MAX_BUFFERED_BODY + 1)));
+        let rejection = Bytes::from_request(&mut request).unwrap_err();
+        assert_eq!(rejection.status, Status::ContentTooLarge);
+
+        // Found out while reading, e.g. a chunked or decompressed body
+        request.body = Some(RequestBody::from(vec![b'a'; 10]).with_limit(4));
+        let rejection = String::from_request(&mut request).unwrap_err();
+        assert_eq!(rejection.status, Status::ContentTooLarge);
+        assert_eq!(rejection.into_response().status, Status::ContentTooLarge);
+    }
+
+    #[test]
+    fn params_parse_by_name() {
+        let mut request = test_request("GET", "/", &[]);
+        request.params.insert("team".to_string(), "7".to_string());
+        request.params.insert("id".to_string(), "x".to_string());
+        let params = Params::from_request(&mut request).unwrap();
+
+        assert_eq!(params.parse::<u32>("team").unwrap(), 7);
+        assert_eq!(params.parse::<String>("id").unwrap(), "x");
+        assert_eq!(
+            params.parse::<u32>("id").unwrap_err().status,
+            Status::BadRequest
+        );
+        assert!(params.parse::<u32>("other").is_err());
+    }
+}
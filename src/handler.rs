@@ -0,0 +1,95 @@
+use crate::extract::FromRequest;
+use crate::models::HttpRequest;
+use crate::response::{HttpResponse, IntoResponse};
+use std::marker::PhantomData;
+use std::sync::Arc;
+
+pub trait Handler: Send + Sync + 'static {
+    fn call(&self, request: &mut HttpRequest) -> HttpResponse;
+}
+
+impl<F> Handler for F
+where
+    F: Fn(&mut HttpRequest) -> HttpResponse + Send + Sync + 'static,
+{
+    fn call(&self, request: &mut HttpRequest) -> HttpResponse {
+        self(request)
+    }
+}
+
+impl<H: Handler + ?Sized> Handler for Arc<H> {
+    fn call(&self, request: &mut HttpRequest) -> HttpResponse {
+        (**self).call(request)
+    }
+}
+
+impl Handler for Box<dyn Handler> {
+    fn call(&self, request: &mut HttpRequest) -> HttpResponse {
+        (**self).call(request)
+    }
+}
+
+// Marks handlers that take the raw request, see IntoHandler
+pub struct Direct;
+
+// Turns a function into a Handler. Besides plain handlers this accepts any
+// function whose arguments implement FromRequest and whose return value
+// implements IntoResponse; a failed extraction answers with its Rejection.
+pub trait IntoHandler<Args>: Send + Sync + 'static {
+    fn into_handler(self) -> Box<dyn Handler>;
+}
+
+impl<H: Handler> IntoHandler<Direct> for H {
+    fn into_handler(self) -> Box<dyn Handler> {
+        Box::new(self)
+    }
+}
+
+struct Extract<F, Args> {
+    f: F,
+    _args: PhantomData<fn() -> Args>,
+}
+
+macro_rules! extract_handler {
+    ($($arg:ident),*) => {
+        impl<F, R, $($arg,)*> Handler for Extract<F, ($($arg,)*)>
+        where
+            F: Fn($($arg),*) -> R + Send + Sync + 'static,
+            R: IntoResponse,
+            $($arg: FromRequest + 'static,)*
+        {
+            #[allow(non_snake_case, unused_variables)]
+            fn call(&self, request: &mut HttpRequest) -> HttpResponse {
+                $(
+                    let $arg = match $arg::from_request(request) {
+                        Ok(it) => it,
+                        Err(rejection) => return rejection.into_response(),
+                    };
+                )*
+                (self.f)($($arg),*).into_response()
+            }
+        }
+
+        impl<F, R, $($arg,)*> IntoHandler<($($arg,)*)> for F
+        where
+            F: Fn($($arg),*) -> R + Send + Sync + 'static,
+            R: IntoResponse + 'static,
+            $($arg: FromRequest + 'static,)*
+        {
+            fn into_handler(self) -> Box<dyn Handler> {
+                Box::new(Extract {
+                    f: self,
+                    _args: PhantomData,
+                })
+            }
+        }
+    };
+}
+
+extract_handler!();
+extract_handler!(A);
+extract_handler!(A, B);
+extract_handler!(A, B, C);
+extract_handler!(A, B, C, D);
+extract_handler!(A, B, C, D, E);
+extract_handler!(A, B, C, D, E, G);
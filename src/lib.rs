@@ -0,0 +1,21 @@
+#[cfg(unix)]
+pub mod activation;
+pub mod body;
+pub mod compression;
+pub mod conditional;
+pub mod date;
+pub mod error;
+pub mod extensions;
+pub mod extract;
+pub mod handler;
+pub mod listener;
+pub mod middleware;
+pub mod mime;
+pub mod models;
+pub mod negotiate;
+pub mod parsing;
+pub mod range;
+pub mod response;
+pub mod router;
+pub mod server;
+pub mod static_files;
@@ -0,0 +1,287 @@
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::time::Duration;
+
+#[cfg(unix)]
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, RawFd};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(unix)]
+use std::{fs, path::Path};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ListenAddr::Tcp(addr) => write!(f, "{}", addr),
+            ListenAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+impl From<SocketAddr> for ListenAddr {
+    fn from(addr: SocketAddr) -> Self {
+        ListenAddr::Tcp(addr)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct UnixOptions {
+    // File mode applied to the socket file after binding, e.g. 0o660
+    pub mode: Option<u32>,
+    // Remove a leftover socket file nobody is accepting on before binding
+    pub remove_stale: bool,
+}
+
+impl UnixOptions {
+    pub fn new() -> Self {
+        Self {
+            mode: None,
+            remove_stale: false,
+        }
+    }
+}
+
+impl Default for UnixOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug)]
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+impl Listener {
+    #[cfg(unix)]
+    pub fn bind_unix(path: &Path, options: &UnixOptions) -> io::Result<Self> {
+        if options.remove_stale {
+            remove_stale_socket(path)?;
+        }
+
+        let Some(mode) = options.mode else {
+            return UnixListener::bind(path).map(Listener::Unix);
+        };
+
+        // Created with no more than `mode` allows, so nobody can connect in
+        // between bind and chmod. The umask is process wide, files other
+        // threads create meanwhile only end up more restricted.
+        // SAFETY: umask has no preconditions
+        let umask = unsafe { libc::umask(!mode as libc::mode_t & 0o777) };
+        let bound = UnixListener::bind(path);
+        // SAFETY: as above, this puts back the mask umask returned
+        unsafe { libc::umask(umask) };
+        let listener = bound?;
+
+        // Bits like setgid aren't covered by the umask
+        if let Err(e) = fs::set_permissions(path, fs::Permissions::from_mode(mode)) {
+            let _ = fs::remove_file(path);
+            return Err(e);
+        }
+
+        Ok(Listener::Unix(listener))
+    }
+
+    pub fn accept(&self) -> io::Result<Stream> {
+        match self {
+            Listener::Tcp(listener) => listener.accept().map(|(s, _)| Stream::Tcp(s)),
+            #[cfg(unix)]
+            Listener::Unix(listener) => listener.accept().map(|(s, _)| Stream::Unix(s)),
+        }
+    }
+
+    pub fn local_addr(&self) -> io::Result<ListenAddr> {
+        match self {
+            Listener::Tcp(listener) => listener.local_addr().map(ListenAddr::Tcp),
+            #[cfg(unix)]
+            Listener::Unix(listener) => {
+                let addr = listener.local_addr()?;
+                match addr.as_pathname() {
+                    Some(path) => Ok(ListenAddr::Unix(path.to_path_buf())),
+                    None => Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "Unix listener is not bound to a path",
+                    )),
+                }
+            }
+        }
+    }
+
+    pub fn try_clone(&self) -> io::Result<Self> {
+        match self {
+            Listener::Tcp(listener) => listener.try_clone().map(Listener::Tcp),
+            #[cfg(unix)]
+            Listener::Unix(listener) => listener.try_clone().map(Listener::Unix),
+        }
+    }
+}
+
+#[cfg(unix)]
+impl AsRawFd for Listener {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            Listener::Tcp(listener) => listener.as_raw_fd(),
+            Listener::Unix(listener) => listener.as_raw_fd(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Stream {
+    pub fn try_clone(&self) -> io::Result<Self> {
+        match self {
+            Stream::Tcp(stream) => stream.try_clone().map(Stream::Tcp),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.try_clone().map(Stream::Unix),
+        }
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_read_timeout(timeout),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.set_read_timeout(timeout),
+        }
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.shutdown(how),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.shutdown(how),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.read(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.write(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.flush(),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+
+    // Never delete something that isn't a socket, that's a config mistake
+    if !metadata.file_type().is_socket() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} exists and is not a socket", path.display()),
+        ));
+    }
+
+    // A live server still answers connects, only a dead one refuses them
+    match UnixStream::connect(path) {
+        Ok(_) => Err(io::Error::new(
+            io::ErrorKind::AddrInUse,
+            format!("{} is in use by another process", path.display()),
+        )),
+        Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => fs::remove_file(path),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    // An empty directory for the test `name`
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("listener-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn binds_unix_sockets_with_the_given_mode() {
+        let dir = scratch("mode");
+        let path = dir.join("http.sock");
+
+        let mut options = UnixOptions::new();
+        options.mode = Some(0o600);
+        let listener = Listener::bind_unix(&path, &options).unwrap();
+
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert_eq!(
+            listener.local_addr().unwrap(),
+            ListenAddr::Unix(path.clone())
+        );
+
+        drop(listener);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn removes_stale_sockets_only() {
+        let dir = scratch("stale");
+        let path = dir.join("http.sock");
+
+        // Nobody accepts on it once the listener is gone
+        drop(UnixListener::bind(&path).unwrap());
+        assert!(path.exists());
+        remove_stale_socket(&path).unwrap();
+        assert!(!path.exists());
+        remove_stale_socket(&path).unwrap();
+
+        let live = UnixListener::bind(&path).unwrap();
+        let error = remove_stale_socket(&path).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::AddrInUse);
+        assert!(path.exists());
+        drop(live);
+
+        let file = dir.join("config.txt");
+        fs::write(&file, "keep me").unwrap();
+        let error = remove_stale_socket(&file).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(fs::read_to_string(&file).unwrap(), "keep me");
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -1,44 +1,257 @@
-mod parsing;
-mod models;
+use std::env;
+use std::io;
+use std::net::ToSocketAddrs;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::Duration;
 
-use std::io::BufReader;
-use std::net::{TcpListener, TcpStream};
-use models::{Host, parse_http_request};
+use rust_http::body::{RequestBody, TooLarge};
+use rust_http::compression::{Compression, Decompression};
+use rust_http::conditional::{ConditionalRequests, EntityTag, Validators};
+use rust_http::error::{html_or_json, json_body};
+use rust_http::extract::{Extension, Path, Query, State};
+use rust_http::middleware::{Logger, RequestId, RequestIds};
+use rust_http::mime::MimeTypes;
+use rust_http::models::HttpRequest;
+use rust_http::negotiate::{negotiate, negotiate_language};
+use rust_http::range::RangeRequests;
+use rust_http::response::{Body, HttpResponse, Interim, IntoResponse, Status};
+use rust_http::router::{OriginalUri, Router};
+use rust_http::server::{Server, ServerConfig};
+use rust_http::static_files::StaticFiles;
 
 fn main() {
-    let bind_addr = Host {
-        hostname: "127.0.0.1".to_string(),
-        port: 8080,
-    };
+    let mut config = ServerConfig::new();
+    let mut addrs: Vec<String> = Vec::new();
+
+    for arg in env::args().skip(1) {
+        if arg == "--reuse-port" {
+            config = config.reuse_port(true);
+        } else if arg == "--remove-stale-socket" {
+            config = config.remove_stale_socket(true);
+        } else if let Some(mode) = arg.strip_prefix("--socket-mode=") {
+            match u32::from_str_radix(mode, 8) {
+                Ok(mode) => config = config.unix_mode(mode),
+                Err(msg) => {
+                    eprintln!("Invalid socket mode {}. Reason: {}", mode, msg);
+                    return;
+                }
+            }
+        } else if let Some(n) = arg.strip_prefix("--acceptors=") {
+            match n.parse::<usize>() {
+                Ok(n) => config = config.acceptors(n),
+                Err(msg) => {
+                    eprintln!("Invalid acceptor count {}. Reason: {}", n, msg);
+                    return;
+                }
+            }
+        } else {
+            addrs.push(arg);
+        }
+    }
+
+    if addrs.is_empty() {
+        addrs.push("127.0.0.1:8080".to_string());
+    }
+
+    for addr in &addrs {
+        if let Some(path) = addr.strip_prefix("unix:") {
+            config = config.listen_unix(path);
+            continue;
+        }
+
+        match addr.to_socket_addrs() {
+            Ok(resolved) => {
+                for addr in resolved {
+                    config = config.listen(addr);
+                }
+            }
+            Err(msg) => {
+                eprintln!("Failed to resolve listen address {}. Reason: {}", addr, msg);
+                return;
+            }
+        }
+    }
 
-    let listener = match TcpListener::bind(format!("{}", bind_addr)) {
-        Ok(listener) => listener,
+    let server = match Server::bind(&config) {
+        Ok(server) => server,
         Err(msg) => {
             eprintln!(
-                "Failed to bind tcp listener for {}. Reason: {}",
-                bind_addr, msg
+                "Failed to bind listener for {}. Reason: {}",
+                addrs.join(", "),
+                msg
             );
             return;
         }
     };
 
-    let mut stream_iter = listener.incoming();
+    if let Ok(bound) = server.local_addrs() {
+        for addr in bound {
+            println!("Listening on {}", addr);
+        }
+    }
+
+    let router = Router::new()
+        .get("/", index)
+        .get("/hello/:name", hello)
+        .post("/echo", echo)
+        .post("/upload", upload)
+        .get("/visits", visits)
+        .get("/stream", stream)
+        .get("/report", report)
+        .get("/greeting", greeting)
+        .get("/hinted", hinted)
+        .get("/panic", panics)
+        .nest("/api/v1", Router::new().get("/whoami", whoami))
+        .nest(
+            "/static",
+            Router::new().get(
+                "/*path",
+                StaticFiles::new("static")
+                    .mime_types(MimeTypes::new().sniffing(true))
+                    .listings(true)
+                    .precompressed(true),
+            ),
+        )
+        .state(AtomicU64::new(0))
+        .wrap(RequestIds::new())
+        .wrap(Compression::new())
+        .wrap(Decompression::new().max_size(1024 * 1024))
+        .wrap(RangeRequests)
+        .wrap(ConditionalRequests)
+        .fallback_error_handler(html_or_json)
+        .wrap(Logger);
 
-    while let Some(Ok(stream)) = stream_iter.next() {
-        handle_connection(stream);
+    if let Err(msg) = server.fallback_error_handler(json_body).serve(router) {
+        eprintln!("Server stopped. Reason: {}", msg);
     }
 }
 
-fn handle_connection(mut stream: TcpStream) {
-    let mut buf_reader = BufReader::new(&mut stream);
+fn index(_request: &mut HttpRequest) -> HttpResponse {
+    let body = "Hello from rust_http\n";
+    let mut response = HttpResponse::text(Status::Ok, body);
+    Validators::new()
+        .etag(EntityTag::from_bytes(body.as_bytes()))
+        .apply(&mut response);
+    response
+}
+
+fn hello(Path(name): Path<String>, Query(query): Query) -> String {
+    let greeting = query.get("greeting").map_or("Hello", |it| it.as_str());
+    format!("{}, {}!\n", greeting, name)
+}
+
+fn echo(body: String) -> String {
+    body
+}
+
+// Counts the body without holding it in memory
+fn upload(mut body: RequestBody) -> Result<String, (Status, String)> {
+    match io::copy(&mut body, &mut io::sink()) {
+        Ok(read) => Ok(format!("Received {} bytes\n", read)),
+        Err(msg) if TooLarge::caused(&msg) => Err((Status::ContentTooLarge, format!("{}\n", msg))),
+        Err(msg) => Err((Status::BadRequest, format!("Upload failed: {}\n", msg))),
+    }
+}
 
-    match parse_http_request(&mut buf_reader) {
-        Ok(http_request) => {
-            dbg!(http_request.request_line.method);
+fn whoami(request: &mut HttpRequest) -> HttpResponse {
+    let original = request
+        .extensions
+        .get::<OriginalUri>()
+        .map_or("", |it| it.0.as_str());
+    HttpResponse::text(
+        Status::Ok,
+        format!("{} (mounted at {})\n", request.request_line.uri, original),
+    )
+}
+
+fn visits(State(counter): State<AtomicU64>, Extension(id): Extension<RequestId>) -> String {
+    let visits = counter.fetch_add(1, Ordering::Relaxed) + 1;
+    format!("Visit {} (request {})\n", visits, id.0)
+}
+
+// The same data as JSON, HTML or CSV depending on the Accept header
+fn report(request: &mut HttpRequest) -> HttpResponse {
+    let rows = [("apples", 3), ("pears", 5)];
+
+    let media = match negotiate(request, &["application/json", "text/html", "text/csv"]) {
+        Ok(media) => media,
+        Err(e) => return e.into_response(),
+    };
+
+    let body = match media {
+        "application/json" => {
+            let items: Vec<String> = rows
+                .iter()
+                .map(|(name, count)| format!("{{\"name\":\"{}\",\"count\":{}}}", name, count))
+                .collect();
+            format!("[{}]\n", items.join(","))
         }
-        Err(msg) => {
-            dbg!(msg);
+        "text/html" => {
+            let items: String = rows
+                .iter()
+                .map(|(name, count)| format!("<tr><td>{}</td><td>{}</td></tr>\n", name, count))
+                .collect();
+            format!("<table>\n{}</table>\n", items)
+        }
+        _ => {
+            let items: String = rows
+                .iter()
+                .map(|(name, count)| format!("{},{}\n", name, count))
+                .collect();
+            format!("name,count\n{}", items)
         }
+    };
+
+    HttpResponse::new(Status::Ok)
+        .header("Content-Type", media)
+        .header("Vary", "Accept")
+        .body(body)
+}
+
+fn greeting(request: &mut HttpRequest) -> HttpResponse {
+    let greetings = [
+        ("en", "Hello"),
+        ("de", "Hallo"),
+        ("fr", "Bonjour"),
+        ("pt-BR", "Olá"),
+    ];
+    let available: Vec<&str> = greetings.iter().map(|(tag, _)| *tag).collect();
+
+    let language = negotiate_language(request, &available).unwrap_or("en");
+    let text = greetings
+        .iter()
+        .find(|(tag, _)| *tag == language)
+        .map_or("Hello", |(_, text)| text);
+
+    HttpResponse::text(Status::Ok, format!("{}\n", text))
+        .header("Content-Language", language)
+        .header("Vary", "Accept-Language")
+}
+
+// Sent chunked on HTTP/1.1 with a trailer after the last chunk
+fn stream(_request: &mut HttpRequest) -> HttpResponse {
+    let lines = (1..=5).map(|i| format!("Line {}\n", i).into_bytes());
+    HttpResponse::new(Status::Ok)
+        .header("Content-Type", "text/plain; charset=utf-8")
+        .body(Body::chunks(lines))
+        .trailers(|| vec![("X-Lines".to_string(), "5".to_string())])
+}
+
+// Lets the browser preload the stylesheet while the page is being built
+fn hinted(interim: Interim) -> HttpResponse {
+    if let Err(msg) = interim.early_hints(&["</style.css>; rel=preload; as=style"]) {
+        eprintln!("Failed to send early hints. Reason: {}", msg);
     }
+    thread::sleep(Duration::from_millis(100));
+
+    HttpResponse::html(
+        Status::Ok,
+        "<link rel=\"stylesheet\" href=\"/style.css\">\n<p>Hinted</p>\n",
+    )
+    .header("Link", "</style.css>; rel=preload; as=style")
 }
 
+fn panics(_request: &mut HttpRequest) -> HttpResponse {
+    panic!("Handler for /panic always panics");
+}
@@ -0,0 +1,256 @@
+use crate::handler::Handler;
+use crate::models::HttpRequest;
+use crate::response::HttpResponse;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+// Hooks that run around a handler. `before` runs outermost first and may
+// answer the request itself, `after` runs innermost first on the response.
+pub trait Middleware: Send + Sync + 'static {
+    fn before(&self, _request: &mut HttpRequest) -> Option<HttpResponse> {
+        None
+    }
+
+    fn after(&self, _request: &HttpRequest, _response: &mut HttpResponse) {}
+}
+
+impl<M: Middleware + ?Sized> Middleware for Arc<M> {
+    fn before(&self, request: &mut HttpRequest) -> Option<HttpResponse> {
+        (**self).before(request)
+    }
+
+    fn after(&self, request: &HttpRequest, response: &mut HttpResponse) {
+        (**self).after(request, response)
+    }
+}
+
+pub struct Before<F>(pub F);
+
+impl<F> Middleware for Before<F>
+where
+    F: Fn(&mut HttpRequest) -> Option<HttpResponse> + Send + Sync + 'static,
+{
+    fn before(&self, request: &mut HttpRequest) -> Option<HttpResponse> {
+        (self.0)(request)
+    }
+}
+
+pub struct After<F>(pub F);
+
+impl<F> Middleware for After<F>
+where
+    F: Fn(&HttpRequest, &mut HttpResponse) + Send + Sync + 'static,
+{
+    fn after(&self, request: &HttpRequest, response: &mut HttpResponse) {
+        (self.0)(request, response)
+    }
+}
+
+// Logs one line per request once the response is known
+pub struct Logger;
+
+impl Middleware for Logger {
+    fn after(&self, request: &HttpRequest, response: &mut HttpResponse) {
+        eprintln!(
+            "{} {} -> {}",
+            request.request_line.method,
+            request.request_line.uri,
+            response.status.code()
+        );
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RequestId(pub u64);
+
+// Numbers requests, stores the RequestId in the request extensions and echoes
+// it back in an X-Request-Id header
+pub struct RequestIds {
+    next: AtomicU64,
+}
+
+impl RequestIds {
+    pub fn new() -> Self {
+        Self {
+            next: AtomicU64::new(1),
+        }
+    }
+}
+
+impl Default for RequestIds {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Middleware for RequestIds {
+    fn before(&self, request: &mut HttpRequest) -> Option<HttpResponse> {
+        let id = self.next.fetch_add(1, Ordering::Relaxed);
+        request.extensions.insert(RequestId(id));
+        None
+    }
+
+    fn after(&self, request: &HttpRequest, response: &mut HttpResponse) {
+        if let Some(RequestId(id)) = request.extensions.get::<RequestId>() {
+            let _ = response.insert_header("X-Request-Id", &id.to_string());
+        }
+    }
+}
+
+// Runs `handler` inside `middleware`. When a `before` hook short-circuits,
+// only the layers outside of it get to see the response.
+pub fn run<F>(
+    middleware: &[Arc<dyn Middleware>],
+    request: &mut HttpRequest,
+    handler: F,
+) -> HttpResponse
+where
+    F: FnOnce(&mut HttpRequest) -> HttpResponse,
+{
+    let mut entered = 0;
+    let mut response = None;
+
+    for layer in middleware {
+        if let Some(early) = layer.before(request) {
+            response = Some(early);
+            break;
+        }
+        entered += 1;
+    }
+
+    let mut response = match response {
+        Some(response) => response,
+        None => handler(request),
+    };
+
+    for layer in middleware[..entered].iter().rev() {
+        layer.after(request, &mut response);
+    }
+
+    response
+}
+
+// Wraps a single handler in middleware, for servers without a router
+pub struct Pipeline {
+    middleware: Vec<Arc<dyn Middleware>>,
+    handler: Box<dyn Handler>,
+}
+
+impl Pipeline {
+    pub fn new<H: Handler>(handler: H) -> Self {
+        Self {
+            middleware: Vec::new(),
+            handler: Box::new(handler),
+        }
+    }
+
+    pub fn wrap<M: Middleware>(mut self, middleware: M) -> Self {
+        self.middleware.push(Arc::new(middleware));
+        self
+    }
+}
+
+impl Handler for Pipeline {
+    fn call(&self, request: &mut HttpRequest) -> HttpResponse {
+        run(&self.middleware, request, |request| {
+            self.handler.call(request)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::test_request;
+    use crate::response::Status;
+    use std::sync::Mutex;
+
+    type Calls = Arc<Mutex<Vec<String>>>;
+
+    // Records its hooks in `calls`, answering 403 itself when `answer` is set
+    struct Layer {
+        name: &'static str,
+        answer: bool,
+        calls: Calls,
+    }
+
+    impl Middleware for Layer {
+        fn before(&self, _request: &mut HttpRequest) -> Option<HttpResponse> {
+            self.calls
+                .lock()
+                .unwrap()
+                .push(format!("before {}", self.name));
+            self.answer.then(|| HttpResponse::new(Status::Forbidden))
+        }
+
+        fn after(&self, _request: &HttpRequest, _response: &mut HttpResponse) {
+            self.calls
+                .lock()
+                .unwrap()
+                .push(format!("after {}", self.name));
+        }
+    }
+
+    fn pipeline(calls: &Calls, layers: &[(&'static str, bool)]) -> Pipeline {
+        let handler_calls = calls.clone();
+        let mut pipeline = Pipeline::new(move |_: &mut HttpRequest| {
+            handler_calls.lock().unwrap().push("handler".to_string());
+            HttpResponse::new(Status::Ok)
+        });
+        for &(name, answer) in layers {
+            pipeline = pipeline.wrap(Layer {
+                name,
+                answer,
+                calls: calls.clone(),
+            });
+        }
+        pipeline
+    }
+
+    #[test]
+    fn after_hooks_run_in_reverse() {
+        let calls = Calls::default();
+        let pipeline = pipeline(&calls, &[("a", false), ("b", false)]);
+
+        let response = pipeline.call(&mut test_request("GET", "/", &[]));
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(
+            *calls.lock().unwrap(),
+            ["before a", "before b", "handler", "after b", "after a"]
+        );
+    }
+
+    #[test]
+    fn short_circuits_skip_the_handler_and_inner_layers() {
+        let calls = Calls::default();
+        let pipeline = pipeline(&calls, &[("a", false), ("b", true), ("c", false)]);
+
+        let response = pipeline.call(&mut test_request("GET", "/", &[]));
+        assert_eq!(response.status, Status::Forbidden);
+        assert_eq!(*calls.lock().unwrap(), ["before a", "before b", "after a"]);
+    }
+
+    #[test]
+    fn numbers_requests() {
+        let pipeline = Pipeline::new(|request: &mut HttpRequest| {
+            let RequestId(id) = *request.extensions.get::<RequestId>().unwrap();
+            HttpResponse::text(Status::Ok, id.to_string())
+        })
+        .wrap(RequestIds::new());
+
+        for id in ["1", "2"] {
+            let response = pipeline.call(&mut test_request("GET", "/", &[]));
+            assert_eq!(response.get_header("X-Request-Id").as_deref(), Some(id));
+        }
+    }
+
+    #[test]
+    fn logger_leaves_the_response_alone() {
+        let pipeline =
+            Pipeline::new(|_: &mut HttpRequest| HttpResponse::new(Status::Accepted)).wrap(Logger);
+
+        let response = pipeline.call(&mut test_request("GET", "/", &[]));
+        assert_eq!(response.status, Status::Accepted);
+        assert_eq!(response.get_header("X-Request-Id"), None);
+    }
+}
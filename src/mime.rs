@@ -0,0 +1,331 @@
+use std::collections::HashMap;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+
+// Sent for content nothing more specific is known about, see RFC 9110 8.3
+pub const OCTET_STREAM: &str = "application/octet-stream";
+
+// How many bytes sniff looks at
+pub const SNIFF_LENGTH: usize = 512;
+
+const DEFAULT_TYPES: &[(&str, &str)] = &[
+    // Web
+    ("html", "text/html"),
+    ("htm", "text/html"),
+    ("css", "text/css"),
+    ("js", "text/javascript"),
+    ("mjs", "text/javascript"),
+    ("json", "application/json"),
+    ("map", "application/json"),
+    ("jsonld", "application/ld+json"),
+    ("webmanifest", "application/manifest+json"),
+    ("xml", "application/xml"),
+    ("xhtml", "application/xhtml+xml"),
+    ("rss", "application/rss+xml"),
+    ("atom", "application/atom+xml"),
+    ("wasm", "application/wasm"),
+    ("txt", "text/plain"),
+    ("csv", "text/csv"),
+    ("md", "text/markdown"),
+    ("ics", "text/calendar"),
+    // Images
+    ("png", "image/png"),
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("gif", "image/gif"),
+    ("webp", "image/webp"),
+    ("avif", "image/avif"),
+    ("svg", "image/svg+xml"),
+    ("ico", "image/x-icon"),
+    ("bmp", "image/bmp"),
+    ("tif", "image/tiff"),
+    ("tiff", "image/tiff"),
+    // Fonts
+    ("woff", "font/woff"),
+    ("woff2", "font/woff2"),
+    ("ttf", "font/ttf"),
+    ("otf", "font/otf"),
+    // Audio and video
+    ("mp3", "audio/mpeg"),
+    ("wav", "audio/wav"),
+    ("ogg", "audio/ogg"),
+    ("oga", "audio/ogg"),
+    ("opus", "audio/opus"),
+    ("flac", "audio/flac"),
+    ("aac", "audio/aac"),
+    ("m4a", "audio/mp4"),
+    ("mp4", "video/mp4"),
+    ("m4v", "video/mp4"),
+    ("webm", "video/webm"),
+    ("ogv", "video/ogg"),
+    ("mov", "video/quicktime"),
+    ("mkv", "video/x-matroska"),
+    ("avi", "video/x-msvideo"),
+    // Archives and documents
+    ("zip", "application/zip"),
+    ("gz", "application/gzip"),
+    ("tgz", "application/gzip"),
+    ("tar", "application/x-tar"),
+    ("bz2", "application/x-bzip2"),
+    ("xz", "application/x-xz"),
+    ("zst", "application/zstd"),
+    ("7z", "application/x-7z-compressed"),
+    ("rar", "application/vnd.rar"),
+    ("jar", "application/java-archive"),
+    ("pdf", "application/pdf"),
+    ("epub", "application/epub+zip"),
+    ("bin", OCTET_STREAM),
+];
+
+// Maps file name extensions to media types. Starts out with the common web,
+// media and archive types and takes more with insert, which also
+// overrides the defaults. Sniffing the content of files with an unknown
+// extension is off unless enabled.
+#[derive(Clone, Debug)]
+pub struct MimeTypes {
+    types: HashMap<String, String>,
+    sniff: bool,
+}
+
+impl MimeTypes {
+    pub fn new() -> Self {
+        Self {
+            types: DEFAULT_TYPES
+                .iter()
+                .map(|(extension, mime)| (extension.to_string(), mime.to_string()))
+                .collect(),
+            sniff: false,
+        }
+    }
+
+    // Without the dot, e.g. insert("yaml", "application/yaml")
+    pub fn insert(&mut self, extension: &str, mime: &str) {
+        self.types
+            .insert(extension.to_ascii_lowercase(), mime.to_string());
+    }
+
+    pub fn with(mut self, extension: &str, mime: &str) -> Self {
+        self.insert(extension, mime);
+        self
+    }
+
+    // Whether files with an unknown extension are recognized by their
+    // first bytes, see sniff
+    pub fn sniffing(mut self, sniff: bool) -> Self {
+        self.sniff = sniff;
+        self
+    }
+
+    pub fn get(&self, extension: &str) -> Option<&str> {
+        self.types
+            .get(&extension.to_ascii_lowercase())
+            .map(String::as_str)
+    }
+
+    // The media type by the extension of `path`
+    pub fn for_path(&self, path: &Path) -> Option<&str> {
+        self.get(path.extension()?.to_str()?)
+    }
+
+    // The Content-Type to serve `path` with, from its extension or, when
+    // sniffing, from the first bytes of `reader`, which is rewound after.
+    // Text types get charset=utf-8, anything unknown is OCTET_STREAM.
+    pub fn content_type<R: Read + Seek>(&self, path: &Path, reader: &mut R) -> io::Result<String> {
+        if let Some(mime) = self.for_path(path) {
+            return Ok(with_charset(mime));
+        }
+
+        if !self.sniff {
+            return Ok(OCTET_STREAM.to_string());
+        }
+
+        let mut head = Vec::with_capacity(SNIFF_LENGTH);
+        reader.take(SNIFF_LENGTH as u64).read_to_end(&mut head)?;
+        reader.seek(SeekFrom::Start(0))?;
+
+        Ok(with_charset(sniff(&head).unwrap_or(OCTET_STREAM)))
+    }
+}
+
+impl Default for MimeTypes {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Adds charset=utf-8 to text types without a charset. Other types either
+// define their encoding themselves (JSON, XML) or are binary.
+pub fn with_charset(mime: &str) -> String {
+    let essence = mime.split(';').next().unwrap_or_default().trim();
+    let is_text = essence
+        .get(..5)
+        .is_some_and(|it| it.eq_ignore_ascii_case("text/"));
+
+    if is_text && !mime.to_ascii_lowercase().contains("charset=") {
+        format!("{}; charset=utf-8", mime)
+    } else {
+        mime.to_string()
+    }
+}
+
+// Recognizes content by its magic bytes. Never answers text/html or other
+// types browsers would run scripts from, so an uploaded file can't turn
+// into a page of the site. Valid UTF-8 without control characters is
+// text/plain.
+pub fn sniff(bytes: &[u8]) -> Option<&'static str> {
+    const SIGNATURES: &[(&[u8], &str)] = &[
+        (b"\x89PNG\r\n\x1a\n", "image/png"),
+        (b"\xff\xd8\xff", "image/jpeg"),
+        (b"GIF87a", "image/gif"),
+        (b"GIF89a", "image/gif"),
+        (b"BM", "image/bmp"),
+        (b"\x00\x00\x01\x00", "image/x-icon"),
+        (b"wOFF", "font/woff"),
+        (b"wOF2", "font/woff2"),
+        (b"OTTO", "font/otf"),
+        (b"%PDF-", "application/pdf"),
+        (b"PK\x03\x04", "application/zip"),
+        (b"\x1f\x8b", "application/gzip"),
+        (b"BZh", "application/x-bzip2"),
+        (b"\xfd7zXZ\x00", "application/x-xz"),
+        (b"\x28\xb5\x2f\xfd", "application/zstd"),
+        (b"7z\xbc\xaf\x27\x1c", "application/x-7z-compressed"),
+        (b"Rar!\x1a\x07", "application/vnd.rar"),
+        (b"\x00asm", "application/wasm"),
+        (b"ID3", "audio/mpeg"),
+        (b"fLaC", "audio/flac"),
+        (b"OggS", "audio/ogg"),
+        (b"\x1a\x45\xdf\xa3", "video/webm"),
+    ];
+
+    if let Some((_, mime)) = SIGNATURES
+        .iter()
+        .find(|(signature, _)| bytes.starts_with(signature))
+    {
+        return Some(mime);
+    }
+
+    // RIFF and ISO media files name their type a few bytes in
+    match (bytes.get(..4), bytes.get(8..12)) {
+        (Some(b"RIFF"), Some(b"WEBP")) => return Some("image/webp"),
+        (Some(b"RIFF"), Some(b"WAVE")) => return Some("audio/wav"),
+        (Some(b"RIFF"), Some(b"AVI ")) => return Some("video/x-msvideo"),
+        _ => {}
+    }
+    if bytes.get(4..8) == Some(b"ftyp") {
+        return match bytes.get(8..12) {
+            Some(b"avif") => Some("image/avif"),
+            Some(b"M4A ") => Some("audio/mp4"),
+            Some(b"qt  ") => Some("video/quicktime"),
+            _ => Some("video/mp4"),
+        };
+    }
+
+    if !bytes.is_empty() && is_text(bytes) {
+        return Some("text/plain");
+    }
+
+    None
+}
+
+fn is_text(bytes: &[u8]) -> bool {
+    // The sniffed prefix may end inside a multi-byte character
+    let valid = match std::str::from_utf8(bytes) {
+        Ok(_) => true,
+        Err(e) => e.error_len().is_none(),
+    };
+
+    valid
+        && !bytes
+            .iter()
+            .any(|&it| it < 0x20 && !matches!(it, b'\t' | b'\n' | b'\r' | 0x0c) || it == 0x7f)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn sniffed(bytes: &[u8]) -> String {
+        MimeTypes::new()
+            .sniffing(true)
+            .content_type(Path::new("upload"), &mut Cursor::new(bytes))
+            .unwrap()
+    }
+
+    #[test]
+    fn looks_up_extensions_case_insensitively() {
+        let types = MimeTypes::new();
+        assert_eq!(types.get("PNG"), Some("image/png"));
+        assert_eq!(types.for_path(Path::new("a/INDEX.Html")), Some("text/html"));
+        assert_eq!(types.for_path(Path::new("a/README")), None);
+        assert_eq!(
+            types.for_path(Path::new("a/archive.tar.gz")),
+            Some("application/gzip")
+        );
+    }
+
+    #[test]
+    fn inserted_types_take_precedence() {
+        let types = MimeTypes::new()
+            .with("JS", "application/javascript")
+            .with("yaml", "application/yaml");
+        assert_eq!(types.get("js"), Some("application/javascript"));
+        assert_eq!(types.get("Yaml"), Some("application/yaml"));
+    }
+
+    #[test]
+    fn adds_a_charset_to_text_only() {
+        assert_eq!(with_charset("text/css"), "text/css; charset=utf-8");
+        assert_eq!(with_charset("TEXT/plain"), "TEXT/plain; charset=utf-8");
+        assert_eq!(
+            with_charset("text/html; charset=iso-8859-1"),
+            "text/html; charset=iso-8859-1"
+        );
+        assert_eq!(with_charset("application/json"), "application/json");
+        assert_eq!(with_charset("image/svg+xml"), "image/svg+xml");
+    }
+
+    #[test]
+    fn sniffs_magic_numbers() {
+        assert_eq!(sniff(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR"), Some("image/png"));
+        assert_eq!(sniff(b"GIF89a\x01\0"), Some("image/gif"));
+        assert_eq!(sniff(b"%PDF-1.7\n"), Some("application/pdf"));
+        assert_eq!(sniff(b"RIFF\x24\0\0\0WEBPVP8 "), Some("image/webp"));
+        assert_eq!(sniff(b"\0\0\0\x1cftypavif\0\0\0\0"), Some("image/avif"));
+        assert_eq!(sniff(b"\0\0\0\x20ftypisom\0\0\x02\0"), Some("video/mp4"));
+        assert_eq!(sniff(b"plain words\n"), Some("text/plain"));
+        assert_eq!(sniff(b"\0\x01\x02\x03"), None);
+        assert_eq!(sniff(b""), None);
+    }
+
+    #[test]
+    fn never_sniffs_active_content() {
+        for content in [
+            &b"<html><script>alert(1)</script></html>"[..],
+            b"<!DOCTYPE html>",
+            b"<svg xmlns=\"http://www.w3.org/2000/svg\" onload=\"alert(1)\"/>",
+            b"<?xml version=\"1.0\"?><svg/>",
+        ] {
+            assert_eq!(sniff(content), Some("text/plain"));
+            assert_eq!(sniffed(content), "text/plain; charset=utf-8");
+        }
+    }
+
+    #[test]
+    fn sniffs_only_when_enabled_and_rewinds() {
+        let mut file = Cursor::new(b"%PDF-1.7\n".to_vec());
+        let types = MimeTypes::new();
+        assert_eq!(
+            types.content_type(Path::new("upload"), &mut file).unwrap(),
+            OCTET_STREAM
+        );
+
+        let types = types.sniffing(true);
+        assert_eq!(
+            types.content_type(Path::new("upload"), &mut file).unwrap(),
+            "application/pdf"
+        );
+        assert_eq!(file.position(), 0);
+    }
+}
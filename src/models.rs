@@ -1,6 +1,10 @@
+use crate::body::{BodyReader, Framing, RequestBody};
+use crate::date::HttpDate;
+use crate::error::ParseError;
+use crate::extensions::{Extensions, StateMap};
 use std::collections::HashMap;
 use std::fmt;
-use std::io::{BufRead, BufReader, Read};
+use std::io::{self, BufRead, Read};
 
 #[derive(Clone, Debug)]
 pub struct Host {
@@ -14,10 +18,11 @@ impl fmt::Display for Host {
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub enum Method {
-    OPTION,
+    OPTIONS,
     GET,
+    HEAD,
     POST,
     PUT,
     DELETE,
@@ -29,8 +34,9 @@ pub enum Method {
 impl fmt::Display for Method {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            Method::OPTION => write!(f, "OPTION"),
+            Method::OPTIONS => write!(f, "OPTIONS"),
             Method::GET => write!(f, "GET"),
+            Method::HEAD => write!(f, "HEAD"),
             Method::POST => write!(f, "POST"),
             Method::PUT => write!(f, "PUT"),
             Method::DELETE => write!(f, "DELETE"),
@@ -49,6 +55,35 @@ pub struct RequestLine {
     pub v_minor: u32,
 }
 
+impl RequestLine {
+    // The path component of the request target, without the query and with
+    // the scheme and authority of an absolute-form target removed
+    pub fn path(&self) -> &str {
+        let mut target = self.uri.as_str();
+
+        if let Some(idx) = target.find("://") {
+            target = &target[idx + 3..];
+            target = match target.find('/') {
+                Some(idx) => &target[idx..],
+                None => "/",
+            };
+        }
+
+        match target.find(['?', '#']) {
+            Some(idx) => &target[..idx],
+            None => target,
+        }
+    }
+
+    pub fn query(&self) -> Option<&str> {
+        let (_, query) = self.uri.split_once('?')?;
+        Some(match query.find('#') {
+            Some(idx) => &query[..idx],
+            None => query,
+        })
+    }
+}
+
 impl fmt::Display for RequestLine {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
@@ -59,6 +94,7 @@ impl fmt::Display for RequestLine {
     }
 }
 
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum RequestHeader {
     Accept,
     AcceptCharset,
@@ -130,6 +166,7 @@ impl RequestHeaders {
     }
 
     pub fn insert(&mut self, key: RequestHeader, value: &str) -> Result<(), String> {
+        check_header_value(value)?;
         match key {
             RequestHeader::Accept => {
                 self.accept = Some(value.to_string());
@@ -191,10 +228,143 @@ impl RequestHeaders {
         }
         Ok(())
     }
+
+    // Typed If-Modified-Since, None when missing or not a valid date
+    pub fn if_modified_since(&self) -> Option<HttpDate> {
+        self.if_modified_since.as_deref()?.parse().ok()
+    }
+
+    pub fn if_unmodified_since(&self) -> Option<HttpDate> {
+        self.if_unmodified_since.as_deref()?.parse().ok()
+    }
+
+    pub fn get(&self, key: RequestHeader) -> Option<String> {
+        match key {
+            RequestHeader::Accept => self.accept.as_ref().map(|it| it.to_string()),
+            RequestHeader::AcceptCharset => self.accept_charset.as_ref().map(|it| it.to_string()),
+            RequestHeader::AcceptEncoding => self.accept_encoding.as_ref().map(|it| it.to_string()),
+            RequestHeader::AcceptLanguage => self.accept_language.as_ref().map(|it| it.to_string()),
+            RequestHeader::Authorization => self.authorization.as_ref().map(|it| it.to_string()),
+            RequestHeader::Expect => self.expect.as_ref().map(|it| it.to_string()),
+            RequestHeader::From => self.from.as_ref().map(|it| it.to_string()),
+            RequestHeader::Host => self.host.as_ref().map(|it| it.to_string()),
+            RequestHeader::IfMatch => self.if_match.as_ref().map(|it| it.to_string()),
+            RequestHeader::IfModifiedSince => {
+                self.if_modified_since.as_ref().map(|it| it.to_string())
+            }
+            RequestHeader::IfNoneMatch => self.if_none_match.as_ref().map(|it| it.to_string()),
+            RequestHeader::IfRange => self.if_range.as_ref().map(|it| it.to_string()),
+            RequestHeader::IfUnmodifiedSince => {
+                self.if_unmodified_since.as_ref().map(|it| it.to_string())
+            }
+            RequestHeader::MaxForwards => self.max_forwards.as_ref().map(|it| it.to_string()),
+            RequestHeader::ProxyAuthorization => {
+                self.proxy_authorization.as_ref().map(|it| it.to_string())
+            }
+            RequestHeader::Range => self.range.as_ref().map(|it| it.to_string()),
+            RequestHeader::Referer => self.referer.as_ref().map(|it| it.to_string()),
+            RequestHeader::TE => self.te.as_ref().map(|it| it.to_string()),
+            RequestHeader::UserAgent => self.user_agent.as_ref().map(|it| it.to_string()),
+        }
+    }
+}
+
+impl Default for RequestHeaders {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Display for RequestHeaders {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(value) = &self.accept {
+            write!(f, "{}: {}\r\n", RequestHeader::Accept.value(), value)?;
+        }
+        if let Some(value) = &self.accept_charset {
+            write!(f, "{}: {}\r\n", RequestHeader::AcceptCharset.value(), value)?;
+        }
+        if let Some(value) = &self.accept_encoding {
+            write!(
+                f,
+                "{}: {}\r\n",
+                RequestHeader::AcceptEncoding.value(),
+                value
+            )?;
+        }
+        if let Some(value) = &self.accept_language {
+            write!(
+                f,
+                "{}: {}\r\n",
+                RequestHeader::AcceptLanguage.value(),
+                value
+            )?;
+        }
+        if let Some(value) = &self.authorization {
+            write!(f, "{}: {}\r\n", RequestHeader::Authorization.value(), value)?;
+        }
+        if let Some(value) = &self.expect {
+            write!(f, "{}: {}\r\n", RequestHeader::Expect.value(), value)?;
+        }
+        if let Some(value) = &self.from {
+            write!(f, "{}: {}\r\n", RequestHeader::From.value(), value)?;
+        }
+        if let Some(value) = &self.host {
+            write!(f, "{}: {}\r\n", RequestHeader::Host.value(), value)?;
+        }
+        if let Some(value) = &self.if_match {
+            write!(f, "{}: {}\r\n", RequestHeader::IfMatch.value(), value)?;
+        }
+        if let Some(value) = &self.if_modified_since {
+            write!(
+                f,
+                "{}: {}\r\n",
+                RequestHeader::IfModifiedSince.value(),
+                value
+            )?;
+        }
+        if let Some(value) = &self.if_none_match {
+            write!(f, "{}: {}\r\n", RequestHeader::IfNoneMatch.value(), value)?;
+        }
+        if let Some(value) = &self.if_range {
+            write!(f, "{}: {}\r\n", RequestHeader::IfRange.value(), value)?;
+        }
+        if let Some(value) = &self.if_unmodified_since {
+            write!(
+                f,
+                "{}: {}\r\n",
+                RequestHeader::IfUnmodifiedSince.value(),
+                value
+            )?;
+        }
+        if let Some(value) = &self.max_forwards {
+            write!(f, "{}: {}\r\n", RequestHeader::MaxForwards.value(), value)?;
+        }
+        if let Some(value) = &self.proxy_authorization {
+            write!(
+                f,
+                "{}: {}\r\n",
+                RequestHeader::ProxyAuthorization.value(),
+                value
+            )?;
+        }
+        if let Some(value) = &self.range {
+            write!(f, "{}: {}\r\n", RequestHeader::Range.value(), value)?;
+        }
+        if let Some(value) = &self.referer {
+            write!(f, "{}: {}\r\n", RequestHeader::Referer.value(), value)?;
+        }
+        if let Some(value) = &self.te {
+            write!(f, "{}: {}\r\n", RequestHeader::TE.value(), value)?;
+        }
+        if let Some(value) = &self.user_agent {
+            write!(f, "{}: {}\r\n", RequestHeader::UserAgent.value(), value)?;
+        }
+        Ok(())
+    }
 }
 
 impl RequestHeader {
-    fn value(&self) -> &'static str {
+    pub fn value(&self) -> &'static str {
         match self {
             RequestHeader::Accept => "Accept",
             RequestHeader::AcceptCharset => "Accept-Charset",
@@ -218,32 +388,33 @@ impl RequestHeader {
         }
     }
 
-    fn from(key: &str) -> Option<Self> {
-        Some(match key {
-            "Accept" => RequestHeader::Accept,
-            "Accept-Charset" => RequestHeader::AcceptCharset,
-            "Accept-Encoding" => RequestHeader::AcceptEncoding,
-            "Accept-Language" => RequestHeader::AcceptLanguage,
-            "Authorization" => RequestHeader::Authorization,
-            "Expect" => RequestHeader::Expect,
-            "From" => RequestHeader::From,
-            "Host" => RequestHeader::Host,
-            "If-Match" => RequestHeader::IfMatch,
-            "If-Modified-Since" => RequestHeader::IfModifiedSince,
-            "If-None-Match" => RequestHeader::IfNoneMatch,
-            "If-Range" => RequestHeader::IfRange,
-            "If-Unmodified-Since" => RequestHeader::IfUnmodifiedSince,
-            "Max-Forwards" => RequestHeader::MaxForwards,
-            "Proxy-Authorization" => RequestHeader::ProxyAuthorization,
-            "Range" => RequestHeader::Range,
-            "Referer" => RequestHeader::Referer,
-            "TE" => RequestHeader::TE,
-            "User-Agent" => RequestHeader::UserAgent,
+    pub fn from(key: &str) -> Option<Self> {
+        Some(match key.to_ascii_lowercase().as_str() {
+            "accept" => RequestHeader::Accept,
+            "accept-charset" => RequestHeader::AcceptCharset,
+            "accept-encoding" => RequestHeader::AcceptEncoding,
+            "accept-language" => RequestHeader::AcceptLanguage,
+            "authorization" => RequestHeader::Authorization,
+            "expect" => RequestHeader::Expect,
+            "from" => RequestHeader::From,
+            "host" => RequestHeader::Host,
+            "if-match" => RequestHeader::IfMatch,
+            "if-modified-since" => RequestHeader::IfModifiedSince,
+            "if-none-match" => RequestHeader::IfNoneMatch,
+            "if-range" => RequestHeader::IfRange,
+            "if-unmodified-since" => RequestHeader::IfUnmodifiedSince,
+            "max-forwards" => RequestHeader::MaxForwards,
+            "proxy-authorization" => RequestHeader::ProxyAuthorization,
+            "range" => RequestHeader::Range,
+            "referer" => RequestHeader::Referer,
+            "te" => RequestHeader::TE,
+            "user-agent" => RequestHeader::UserAgent,
             _ => return None,
         })
     }
 }
 
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum GeneralHeader {
     CacheControl,
     Connection,
@@ -285,6 +456,7 @@ impl GeneralHeaders {
     }
 
     pub fn insert(&mut self, key: GeneralHeader, value: &str) -> Result<(), String> {
+        check_header_value(value)?;
         match key {
             GeneralHeader::CacheControl => {
                 self.cache_control = Some(value.to_string());
@@ -316,39 +488,104 @@ impl GeneralHeaders {
         }
         Ok(())
     }
+
+    pub fn date(&self) -> Option<HttpDate> {
+        self.date.as_deref()?.parse().ok()
+    }
+
+    pub fn get(&self, key: GeneralHeader) -> Option<String> {
+        match key {
+            GeneralHeader::CacheControl => self.cache_control.as_ref().map(|it| it.to_string()),
+            GeneralHeader::Connection => self.connection.as_ref().map(|it| it.to_string()),
+            GeneralHeader::Date => self.date.as_ref().map(|it| it.to_string()),
+            GeneralHeader::Pragma => self.pragma.as_ref().map(|it| it.to_string()),
+            GeneralHeader::Trailer => self.trailer.as_ref().map(|it| it.to_string()),
+            GeneralHeader::TransferEncoding => {
+                self.transfer_encoding.as_ref().map(|it| it.to_string())
+            }
+            GeneralHeader::Upgrade => self.upgrade.as_ref().map(|it| it.to_string()),
+            GeneralHeader::Via => self.via.as_ref().map(|it| it.to_string()),
+            GeneralHeader::Warning => self.warning.as_ref().map(|it| it.to_string()),
+        }
+    }
+}
+
+impl Default for GeneralHeaders {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Display for GeneralHeaders {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(value) = &self.cache_control {
+            write!(f, "{}: {}\r\n", GeneralHeader::CacheControl.value(), value)?;
+        }
+        if let Some(value) = &self.connection {
+            write!(f, "{}: {}\r\n", GeneralHeader::Connection.value(), value)?;
+        }
+        if let Some(value) = &self.date {
+            write!(f, "{}: {}\r\n", GeneralHeader::Date.value(), value)?;
+        }
+        if let Some(value) = &self.pragma {
+            write!(f, "{}: {}\r\n", GeneralHeader::Pragma.value(), value)?;
+        }
+        if let Some(value) = &self.trailer {
+            write!(f, "{}: {}\r\n", GeneralHeader::Trailer.value(), value)?;
+        }
+        if let Some(value) = &self.transfer_encoding {
+            write!(
+                f,
+                "{}: {}\r\n",
+                GeneralHeader::TransferEncoding.value(),
+                value
+            )?;
+        }
+        if let Some(value) = &self.upgrade {
+            write!(f, "{}: {}\r\n", GeneralHeader::Upgrade.value(), value)?;
+        }
+        if let Some(value) = &self.via {
+            write!(f, "{}: {}\r\n", GeneralHeader::Via.value(), value)?;
+        }
+        if let Some(value) = &self.warning {
+            write!(f, "{}: {}\r\n", GeneralHeader::Warning.value(), value)?;
+        }
+        Ok(())
+    }
 }
 
 impl GeneralHeader {
-    fn value(&self) -> &'static str {
+    pub fn value(&self) -> &'static str {
         match self {
-            GeneralHeader::CacheControl => "CacheControl",
+            GeneralHeader::CacheControl => "Cache-Control",
             GeneralHeader::Connection => "Connection",
             GeneralHeader::Date => "Date",
             GeneralHeader::Pragma => "Pragma",
             GeneralHeader::Trailer => "Trailer",
-            GeneralHeader::TransferEncoding => "TransferEncoding",
+            GeneralHeader::TransferEncoding => "Transfer-Encoding",
             GeneralHeader::Upgrade => "Upgrade",
             GeneralHeader::Via => "Via",
             GeneralHeader::Warning => "Warning",
         }
     }
 
-    fn from(key: &str) -> Option<Self> {
-        Some(match key {
-            "CacheControl" => GeneralHeader::CacheControl,
-            "Connection" => GeneralHeader::Connection,
-            "Date" => GeneralHeader::Date,
-            "Pragma" => GeneralHeader::Pragma,
-            "Trailer" => GeneralHeader::Trailer,
-            "TransferEncoding" => GeneralHeader::TransferEncoding,
-            "Upgrade" => GeneralHeader::Upgrade,
-            "Via" => GeneralHeader::Via,
-            "Warning" => GeneralHeader::Warning,
+    pub fn from(key: &str) -> Option<Self> {
+        Some(match key.to_ascii_lowercase().as_str() {
+            "cache-control" => GeneralHeader::CacheControl,
+            "connection" => GeneralHeader::Connection,
+            "date" => GeneralHeader::Date,
+            "pragma" => GeneralHeader::Pragma,
+            "trailer" => GeneralHeader::Trailer,
+            "transfer-encoding" => GeneralHeader::TransferEncoding,
+            "upgrade" => GeneralHeader::Upgrade,
+            "via" => GeneralHeader::Via,
+            "warning" => GeneralHeader::Warning,
             _ => return None,
         })
     }
 }
 
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum EntityHeader {
     Allow,
     ContentEncoding,
@@ -396,6 +633,7 @@ impl EntityHeaders {
     }
 
     pub fn insert(&mut self, key: EntityHeader, value: &str) -> Result<(), String> {
+        check_header_value(value)?;
         match key {
             EntityHeader::Allow => {
                 self.allow = Some(value.to_string());
@@ -407,6 +645,10 @@ impl EntityHeaders {
                 self.content_languages = Some(value.to_string());
             }
             EntityHeader::ContentLength => {
+                // parse alone would take a sign too
+                if !value.bytes().all(|it| it.is_ascii_digit()) {
+                    return Err(format!("Invalid length: {}", value));
+                }
                 self.content_length = Some(value.parse::<usize>().map_err(|it| it.to_string())?);
             }
             EntityHeader::ContentLocation => {
@@ -428,47 +670,159 @@ impl EntityHeaders {
                 self.last_modified = Some(value.to_string());
             }
             EntityHeader::Extension(s) => {
+                check_header_name(&s)?;
                 self.extensions.insert(s, value.to_string());
             }
         }
         Ok(())
     }
+
+    pub fn expires(&self) -> Option<HttpDate> {
+        self.expires.as_deref()?.parse().ok()
+    }
+
+    pub fn last_modified(&self) -> Option<HttpDate> {
+        self.last_modified.as_deref()?.parse().ok()
+    }
+
+    pub fn get(&self, key: EntityHeader) -> Option<String> {
+        match key {
+            EntityHeader::Allow => self.allow.as_ref().map(|it| it.to_string()),
+            EntityHeader::ContentEncoding => {
+                self.content_encoding.as_ref().map(|it| it.to_string())
+            }
+            EntityHeader::ContentLanguages => {
+                self.content_languages.as_ref().map(|it| it.to_string())
+            }
+            EntityHeader::ContentLength => self.content_length.as_ref().map(|it| it.to_string()),
+            EntityHeader::ContentLocation => {
+                self.content_location.as_ref().map(|it| it.to_string())
+            }
+            EntityHeader::ContentMD5 => self.content_md5.as_ref().map(|it| it.to_string()),
+            EntityHeader::ContentRange => self.content_range.as_ref().map(|it| it.to_string()),
+            EntityHeader::ContentType => self.content_type.as_ref().map(|it| it.to_string()),
+            EntityHeader::Expires => self.expires.as_ref().map(|it| it.to_string()),
+            EntityHeader::LastModified => self.last_modified.as_ref().map(|it| it.to_string()),
+            EntityHeader::Extension(s) => self.extensions.get(&s).cloned(),
+        }
+    }
+
+    pub fn remove(&mut self, key: EntityHeader) -> Option<String> {
+        let value = self.get(key.clone());
+        match key {
+            EntityHeader::Allow => self.allow = None,
+            EntityHeader::ContentEncoding => self.content_encoding = None,
+            EntityHeader::ContentLanguages => self.content_languages = None,
+            EntityHeader::ContentLength => self.content_length = None,
+            EntityHeader::ContentLocation => self.content_location = None,
+            EntityHeader::ContentMD5 => self.content_md5 = None,
+            EntityHeader::ContentRange => self.content_range = None,
+            EntityHeader::ContentType => self.content_type = None,
+            EntityHeader::Expires => self.expires = None,
+            EntityHeader::LastModified => self.last_modified = None,
+            EntityHeader::Extension(s) => {
+                self.extensions.remove(&s);
+            }
+        }
+        value
+    }
+}
+
+impl Default for EntityHeaders {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Display for EntityHeaders {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(value) = &self.allow {
+            write!(f, "{}: {}\r\n", EntityHeader::Allow.value(), value)?;
+        }
+        if let Some(value) = &self.content_encoding {
+            write!(
+                f,
+                "{}: {}\r\n",
+                EntityHeader::ContentEncoding.value(),
+                value
+            )?;
+        }
+        if let Some(value) = &self.content_languages {
+            write!(
+                f,
+                "{}: {}\r\n",
+                EntityHeader::ContentLanguages.value(),
+                value
+            )?;
+        }
+        if let Some(value) = &self.content_length {
+            write!(f, "{}: {}\r\n", EntityHeader::ContentLength.value(), value)?;
+        }
+        if let Some(value) = &self.content_location {
+            write!(
+                f,
+                "{}: {}\r\n",
+                EntityHeader::ContentLocation.value(),
+                value
+            )?;
+        }
+        if let Some(value) = &self.content_md5 {
+            write!(f, "{}: {}\r\n", EntityHeader::ContentMD5.value(), value)?;
+        }
+        if let Some(value) = &self.content_range {
+            write!(f, "{}: {}\r\n", EntityHeader::ContentRange.value(), value)?;
+        }
+        if let Some(value) = &self.content_type {
+            write!(f, "{}: {}\r\n", EntityHeader::ContentType.value(), value)?;
+        }
+        if let Some(value) = &self.expires {
+            write!(f, "{}: {}\r\n", EntityHeader::Expires.value(), value)?;
+        }
+        if let Some(value) = &self.last_modified {
+            write!(f, "{}: {}\r\n", EntityHeader::LastModified.value(), value)?;
+        }
+        for (key, value) in &self.extensions {
+            write!(f, "{}: {}\r\n", key, value)?;
+        }
+        Ok(())
+    }
 }
 
 impl EntityHeader {
-    fn value(&self) -> &str {
+    pub fn value(&self) -> &str {
         match self {
             EntityHeader::Allow => "Allow",
-            EntityHeader::ContentEncoding => "ContentEncoding",
-            EntityHeader::ContentLanguages => "ContentLanguages",
-            EntityHeader::ContentLength => "ContentLength",
-            EntityHeader::ContentLocation => "ContentLocation",
-            EntityHeader::ContentMD5 => "ContentMD5",
-            EntityHeader::ContentRange => "ContentRange",
-            EntityHeader::ContentType => "ContentType",
+            EntityHeader::ContentEncoding => "Content-Encoding",
+            EntityHeader::ContentLanguages => "Content-Language",
+            EntityHeader::ContentLength => "Content-Length",
+            EntityHeader::ContentLocation => "Content-Location",
+            EntityHeader::ContentMD5 => "Content-MD5",
+            EntityHeader::ContentRange => "Content-Range",
+            EntityHeader::ContentType => "Content-Type",
             EntityHeader::Expires => "Expires",
-            EntityHeader::LastModified => "LastModified",
+            EntityHeader::LastModified => "Last-Modified",
             EntityHeader::Extension(s) => s,
         }
     }
 
-    fn from(key: &str) -> Option<Self> {
-        Some(match key {
-            "Allow" => EntityHeader::Allow,
-            "ContentEncoding" => EntityHeader::ContentEncoding,
-            "ContentLanguages" => EntityHeader::ContentLanguages,
-            "ContentLength" => EntityHeader::ContentLength,
-            "ContentLocation" => EntityHeader::ContentLocation,
-            "ContentMD5" => EntityHeader::ContentMD5,
-            "ContentRange" => EntityHeader::ContentRange,
-            "ContentType" => EntityHeader::ContentType,
-            "Expires" => EntityHeader::Expires,
-            "LastModified" => EntityHeader::LastModified,
-            s => EntityHeader::Extension(s.to_string()),
+    pub fn from(key: &str) -> Option<Self> {
+        Some(match key.to_ascii_lowercase().as_str() {
+            "allow" => EntityHeader::Allow,
+            "content-encoding" => EntityHeader::ContentEncoding,
+            "content-language" => EntityHeader::ContentLanguages,
+            "content-length" => EntityHeader::ContentLength,
+            "content-location" => EntityHeader::ContentLocation,
+            "content-md5" => EntityHeader::ContentMD5,
+            "content-range" => EntityHeader::ContentRange,
+            "content-type" => EntityHeader::ContentType,
+            "expires" => EntityHeader::Expires,
+            "last-modified" => EntityHeader::LastModified,
+            _ => EntityHeader::Extension(key.to_string()),
         })
     }
 }
 
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum ResponseHeader {
     AcceptRanges,
     Age,
@@ -510,6 +864,7 @@ impl ResponseHeaders {
     }
 
     pub fn insert(&mut self, key: ResponseHeader, value: &str) -> Result<(), String> {
+        check_header_value(value)?;
         match key {
             ResponseHeader::AcceptRanges => {
                 self.accept_ranges = Some(value.to_string());
@@ -541,34 +896,101 @@ impl ResponseHeaders {
         }
         Ok(())
     }
+
+    pub fn get(&self, key: ResponseHeader) -> Option<String> {
+        match key {
+            ResponseHeader::AcceptRanges => self.accept_ranges.as_ref().map(|it| it.to_string()),
+            ResponseHeader::Age => self.age.as_ref().map(|it| it.to_string()),
+            ResponseHeader::ETag => self.etag.as_ref().map(|it| it.to_string()),
+            ResponseHeader::Location => self.location.as_ref().map(|it| it.to_string()),
+            ResponseHeader::ProxyAuthenticate => {
+                self.proxy_authenticate.as_ref().map(|it| it.to_string())
+            }
+            ResponseHeader::RetryAfter => self.retry_after.as_ref().map(|it| it.to_string()),
+            ResponseHeader::Server => self.server.as_ref().map(|it| it.to_string()),
+            ResponseHeader::Vary => self.vary.as_ref().map(|it| it.to_string()),
+            ResponseHeader::WWWAuthenticate => {
+                self.www_authenticate.as_ref().map(|it| it.to_string())
+            }
+        }
+    }
+}
+
+impl Default for ResponseHeaders {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Display for ResponseHeaders {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(value) = &self.accept_ranges {
+            write!(f, "{}: {}\r\n", ResponseHeader::AcceptRanges.value(), value)?;
+        }
+        if let Some(value) = &self.age {
+            write!(f, "{}: {}\r\n", ResponseHeader::Age.value(), value)?;
+        }
+        if let Some(value) = &self.etag {
+            write!(f, "{}: {}\r\n", ResponseHeader::ETag.value(), value)?;
+        }
+        if let Some(value) = &self.location {
+            write!(f, "{}: {}\r\n", ResponseHeader::Location.value(), value)?;
+        }
+        if let Some(value) = &self.proxy_authenticate {
+            write!(
+                f,
+                "{}: {}\r\n",
+                ResponseHeader::ProxyAuthenticate.value(),
+                value
+            )?;
+        }
+        if let Some(value) = &self.retry_after {
+            write!(f, "{}: {}\r\n", ResponseHeader::RetryAfter.value(), value)?;
+        }
+        if let Some(value) = &self.server {
+            write!(f, "{}: {}\r\n", ResponseHeader::Server.value(), value)?;
+        }
+        if let Some(value) = &self.vary {
+            write!(f, "{}: {}\r\n", ResponseHeader::Vary.value(), value)?;
+        }
+        if let Some(value) = &self.www_authenticate {
+            write!(
+                f,
+                "{}: {}\r\n",
+                ResponseHeader::WWWAuthenticate.value(),
+                value
+            )?;
+        }
+        Ok(())
+    }
 }
 
 impl ResponseHeader {
-    fn value(&self) -> &'static str {
+    pub fn value(&self) -> &'static str {
         match self {
-            ResponseHeader::AcceptRanges => "AcceptRanges",
+            ResponseHeader::AcceptRanges => "Accept-Ranges",
             ResponseHeader::Age => "Age",
             ResponseHeader::ETag => "ETag",
             ResponseHeader::Location => "Location",
-            ResponseHeader::ProxyAuthenticate => "ProxyAuthenticate",
-            ResponseHeader::RetryAfter => "RetryAfter",
+            ResponseHeader::ProxyAuthenticate => "Proxy-Authenticate",
+            ResponseHeader::RetryAfter => "Retry-After",
             ResponseHeader::Server => "Server",
             ResponseHeader::Vary => "Vary",
-            ResponseHeader::WWWAuthenticate => "WWWAuthenticate",
+            ResponseHeader::WWWAuthenticate => "WWW-Authenticate",
         }
     }
 
-    fn from(key: &str) -> Option<Self> {
-        Some(match key {
-            "AcceptRanges" => ResponseHeader::AcceptRanges,
-            "Age" => ResponseHeader::Age,
-            "ETag" => ResponseHeader::ETag,
-            "Location" => ResponseHeader::Location,
-            "ProxyAuthenticate" => ResponseHeader::ProxyAuthenticate,
-            "RetryAfter" => ResponseHeader::RetryAfter,
-            "Server" => ResponseHeader::Server,
-            "Vary" => ResponseHeader::Vary,
-            "WWWAuthenticate" => ResponseHeader::WWWAuthenticate,
+    pub fn from(key: &str) -> Option<Self> {
+        Some(match key.to_ascii_lowercase().as_str() {
+            "accept-ranges" => ResponseHeader::AcceptRanges,
+            "age" => ResponseHeader::Age,
+            "etag" => ResponseHeader::ETag,
+            "location" => ResponseHeader::Location,
+            "proxy-authenticate" => ResponseHeader::ProxyAuthenticate,
+            "retry-after" => ResponseHeader::RetryAfter,
+            "server" => ResponseHeader::Server,
+            "vary" => ResponseHeader::Vary,
+            "www-authenticate" => ResponseHeader::WWWAuthenticate,
             _ => return None,
         })
     }
@@ -580,94 +1002,273 @@ pub struct HttpRequest {
     pub request_headers: RequestHeaders,
     pub general_headers: GeneralHeaders,
     pub entity_headers: EntityHeaders,
-    pub body: Option<String>,
+    // None when the request has no body, taken by whoever reads it
+    pub body: Option<RequestBody>,
+    // Filled in by the router from `:name` segments of the matched route
+    pub params: HashMap<String, String>,
+    // Values attached by middleware for handlers further in
+    pub extensions: Extensions,
+    // Shared state registered on the server and the routers on the way in
+    pub state: StateMap,
+}
+
+impl HttpRequest {
+    pub fn header(&self, key: &str) -> Option<String> {
+        if let Some(rheader) = RequestHeader::from(key) {
+            self.request_headers.get(rheader)
+        } else if let Some(gheader) = GeneralHeader::from(key) {
+            self.general_headers.get(gheader)
+        } else {
+            self.entity_headers.get(EntityHeader::from(key)?)
+        }
+    }
+
+    pub fn insert_header(&mut self, key: &str, value: &str) -> Result<(), String> {
+        if let Some(rheader) = RequestHeader::from(key) {
+            self.request_headers.insert(rheader, value)
+        } else if let Some(gheader) = GeneralHeader::from(key) {
+            self.general_headers.insert(gheader, value)
+        } else if let Some(eheader) = EntityHeader::from(key) {
+            self.entity_headers.insert(eheader, value)
+        } else {
+            Err(format!("Unknown header: {}", key))
+        }
+    }
+
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params.get(name).map(|it| it.as_str())
+    }
+
+    // Chunked when a Transfer-Encoding is set (parse_request_head only lets
+    // chunked through), otherwise Content-Length if there is one
+    pub fn framing(&self) -> Option<Framing> {
+        if self.general_headers.transfer_encoding.is_some() {
+            Some(Framing::Chunked)
+        } else {
+            self.entity_headers
+                .content_length
+                .map(|length| Framing::Length(length as u64))
+        }
+    }
+
+    // HTTP/1.1 connections persist unless closed, HTTP/1.0 ones only if asked
+    pub fn keep_alive(&self) -> bool {
+        let connection = self
+            .general_headers
+            .get(GeneralHeader::Connection)
+            .unwrap_or_default()
+            .to_ascii_lowercase();
+        let has = |token: &str| connection.split(',').any(|it| it.trim() == token);
+
+        if (self.request_line.v_major, self.request_line.v_minor) >= (1, 1) {
+            !has("close")
+        } else {
+            has("keep-alive")
+        }
+    }
+}
+
+// Longest request line accepted, anything longer is answered with 414
+const MAX_REQUEST_LINE: u64 = 8 * 1024;
+// Combined size of all header lines, anything larger is answered with 431
+const MAX_HEADERS_SIZE: u64 = 64 * 1024;
+
+// A value with CR or LF would end the header line early and let whoever
+// chose the value add headers or a whole response of their own
+pub fn check_header_value(value: &str) -> Result<(), String> {
+    if value.contains(['\r', '\n', '\0']) {
+        return Err(format!("Header value contains CR, LF or NUL: {:?}", value));
+    }
+    Ok(())
 }
 
-fn parse_request_line_from_reader<R: Read>(
-    reader: &mut BufReader<R>,
-) -> Result<RequestLine, String> {
-    let mut request_line = String::new();
+// Field names are tokens, see RFC 9110 5.1
+pub fn check_header_name(name: &str) -> Result<(), String> {
+    let is_tchar = |it: u8| it.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&it);
+
+    if name.is_empty() || !name.bytes().all(is_tchar) {
+        return Err(format!("Invalid header name: {:?}", name));
+    }
+    Ok(())
+}
 
-    let _ = reader
-        .read_line(&mut request_line)
-        .map_err(|e| e.to_string())?;
+// Reads one CRLF or LF terminated line of at most `limit` bytes. None means
+// the line didn't end within the limit.
+pub(crate) fn read_line_limited<R: BufRead>(
+    reader: &mut R,
+    limit: u64,
+) -> Result<Option<String>, ParseError> {
+    let mut line = Vec::new();
+    let read = reader.by_ref().take(limit).read_until(b'\n', &mut line)?;
+
+    if line.ends_with(b"\n") {
+        return Ok(Some(String::from_utf8_lossy(&line).to_string()));
+    }
 
-    request_line = request_line.trim().to_string();
+    if read as u64 == limit {
+        Ok(None)
+    } else {
+        Err(ParseError::Incomplete)
+    }
+}
+
+fn parse_request_line_from_reader<R: BufRead>(reader: &mut R) -> Result<RequestLine, ParseError> {
+    let request_line =
+        read_line_limited(reader, MAX_REQUEST_LINE)?.ok_or(ParseError::UriTooLong)?;
+
+    let request_line = parse_request_line(request_line.trim()).map_err(ParseError::Malformed)?;
+
+    if request_line.v_major != 1 {
+        return Err(ParseError::UnsupportedVersion(
+            request_line.v_major,
+            request_line.v_minor,
+        ));
+    }
 
-    parse_request_line(&request_line)
+    Ok(request_line)
 }
 
-fn parse_headers_from_reader<R: Read>(
-    reader: &mut BufReader<R>,
-) -> Result<(RequestHeaders, GeneralHeaders, EntityHeaders), String> {
+fn parse_headers_from_reader<R: BufRead>(
+    reader: &mut R,
+) -> Result<(RequestHeaders, GeneralHeaders, EntityHeaders), ParseError> {
     // Parse the headers
     let mut request_headers = RequestHeaders::new();
     let mut general_headers = GeneralHeaders::new();
     let mut entity_headers = EntityHeaders::new();
+    let mut budget = MAX_HEADERS_SIZE;
 
     loop {
-        let mut header = String::new();
-        let _ = reader.read_line(&mut header).map_err(|e| e.to_string())?;
-        header = header.trim().to_string();
+        let header = read_line_limited(reader, budget)?.ok_or(ParseError::HeadersTooLarge)?;
+        budget -= header.len() as u64;
+        let header = header.trim();
 
         if header.is_empty() {
             break;
         }
 
         let values: Vec<_> = header.splitn(2, ":").collect();
-        dbg!(&values);
 
         if values.len() != 2 {
-            return Err("Expecting 'key: value' in header".to_string());
+            return Err(ParseError::Malformed(
+                "Expecting 'key: value' in header".to_string(),
+            ));
         }
         let key = values[0];
         let value = values[1].trim();
 
+        // With two different lengths it is anyone's guess where the body
+        // ends, see RFC 9112 6.3
+        if let (Some(EntityHeader::ContentLength), Some(length)) =
+            (EntityHeader::from(key), entity_headers.content_length)
+        {
+            if value != length.to_string() {
+                return Err(ParseError::Malformed(
+                    "Conflicting Content-Length headers".to_string(),
+                ));
+            }
+        }
+
         if let Some(rheader) = RequestHeader::from(key) {
-            request_headers.insert(rheader, value)?;
+            request_headers.insert(rheader, value)
         } else if let Some(gheader) = GeneralHeader::from(key) {
-            general_headers.insert(gheader, value)?;
+            general_headers.insert(gheader, value)
         } else if let Some(eheader) = EntityHeader::from(key) {
-            entity_headers.insert(eheader, value)?;
+            entity_headers.insert(eheader, value)
         } else {
-            panic!("Entity header extension should catch unkown headers");
+            Err("Unknown header".to_string())
         }
+        .map_err(|e| ParseError::Malformed(format!("Invalid {} header: {}", key, e)))?;
     }
 
     Ok((request_headers, general_headers, entity_headers))
 }
 
-fn parse_body_from_reader<R: Read>(
-    content_length: usize,
-    reader: &mut BufReader<R>,
-) -> Result<String, String> {
-    let mut body = Vec::new();
-    let _ = reader
-        .by_ref()
-        .take(content_length as u64)
-        .read_to_end(&mut body)
-        .map_err(|e| e.to_string())?;
-    Ok(String::from_utf8_lossy(&body).to_string())
-}
-
-pub fn parse_http_request<R: Read>(reader: &mut BufReader<R>) -> Result<HttpRequest, String> {
+// Reads the request line and headers and leaves the body, if any, unread on
+// `reader`. See HttpRequest::framing for where the body ends.
+pub fn parse_request_head<R: BufRead>(reader: &mut R) -> Result<HttpRequest, ParseError> {
     let request_line = parse_request_line_from_reader(reader)?;
 
     let (request_headers, general_headers, entity_headers) = parse_headers_from_reader(reader)?;
 
-    let body = if let Some(content_length) = entity_headers.content_length {
-        Some(parse_body_from_reader(content_length, reader)?)
-    } else {
-        None
-    };
+    if let Some(transfer_encoding) = &general_headers.transfer_encoding {
+        // A body framed both ways is how requests get smuggled past proxies
+        if entity_headers.content_length.is_some() {
+            return Err(ParseError::Malformed(
+                "Both Content-Length and Transfer-Encoding are set".to_string(),
+            ));
+        }
 
-    return Ok(HttpRequest {
+        if !transfer_encoding.trim().eq_ignore_ascii_case("chunked") {
+            return Err(ParseError::NotImplemented(format!(
+                "Unsupported Transfer-Encoding: {}",
+                transfer_encoding
+            )));
+        }
+    }
+
+    Ok(HttpRequest {
         request_line,
         request_headers,
         general_headers,
         entity_headers,
-        body,
+        body: None,
+        params: HashMap::new(),
+        extensions: Extensions::new(),
+        state: StateMap::new(),
+    })
+}
+
+// Reads a whole request, body included, into memory
+pub fn parse_http_request<R: BufRead>(reader: &mut R) -> Result<HttpRequest, ParseError> {
+    let mut request = parse_request_head(reader)?;
+
+    if let Some(framing) = request.framing() {
+        let mut body = Vec::new();
+        BodyReader::new(&mut *reader, framing)
+            .read_to_end(&mut body)
+            .map_err(|e| match e.kind() {
+                io::ErrorKind::UnexpectedEof => ParseError::Incomplete,
+                io::ErrorKind::InvalidData => ParseError::Malformed(e.to_string()),
+                _ => ParseError::Io(e),
+            })?;
+        request.body = Some(RequestBody::from(body));
+    }
+
+    Ok(request)
+}
+
+// A parsed request with a Host header and `headers`, for the tests of
+// other modules
+#[cfg(test)]
+pub(crate) fn test_request(method: &str, target: &str, headers: &[(&str, &str)]) -> HttpRequest {
+    test_request_with_body(method, target, headers, b"")
+}
+
+// As test_request, with `body` framed by Content-Length unless `headers`
+// frame it already
+#[cfg(test)]
+pub(crate) fn test_request_with_body(
+    method: &str,
+    target: &str,
+    headers: &[(&str, &str)],
+    body: &[u8],
+) -> HttpRequest {
+    let mut head = format!("{} {} HTTP/1.1\r\nHost: localhost\r\n", method, target);
+    for (name, value) in headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    let framed = headers.iter().any(|(name, _)| {
+        name.eq_ignore_ascii_case("Content-Length")
+            || name.eq_ignore_ascii_case("Transfer-Encoding")
     });
+    if !body.is_empty() && !framed {
+        head.push_str(&format!("Content-Length: {}\r\n", body.len()));
+    }
+    head.push_str("\r\n");
+
+    let mut input = head.into_bytes();
+    input.extend_from_slice(body);
+    parse_http_request(&mut io::Cursor::new(input)).unwrap()
 }
 
 fn parse_request_line(value: &str) -> Result<RequestLine, String> {
@@ -681,7 +1282,7 @@ fn parse_request_line(value: &str) -> Result<RequestLine, String> {
 
     let method = parse_method_from_wire(values[0].to_string())?;
     let uri = values[1].to_string();
-    let (v_major, v_minor) = parse_version_numbers(&values[2].to_string())?;
+    let (v_major, v_minor) = parse_version_numbers(values[2])?;
 
     Ok(RequestLine {
         method,
@@ -691,7 +1292,7 @@ fn parse_request_line(value: &str) -> Result<RequestLine, String> {
     })
 }
 
-fn parse_version_numbers(content: &String) -> Result<(u32, u32), String> {
+fn parse_version_numbers(content: &str) -> Result<(u32, u32), String> {
     let parts: Vec<_> = content.split("/").collect();
 
     if parts.len() != 2 {
@@ -716,7 +1317,7 @@ fn parse_version_numbers(content: &String) -> Result<(u32, u32), String> {
     Ok((v_major, v_minor))
 }
 
-fn parse_host_from_wire(content: &str) -> Result<Host, String> {
+pub(crate) fn parse_host_from_wire(content: &str) -> Result<Host, String> {
     let parts: Vec<&str> = content.split(":").collect();
 
     // No reason you'd see more than one ":" in a hostname
@@ -740,8 +1341,9 @@ fn parse_host_from_wire(content: &str) -> Result<Host, String> {
 
 fn parse_method_from_wire(content: String) -> Result<Method, String> {
     Ok(match content.as_str() {
-        "OPTION" => Method::OPTION,
+        "OPTIONS" => Method::OPTIONS,
         "GET" => Method::GET,
+        "HEAD" => Method::HEAD,
         "POST" => Method::POST,
         "PUT" => Method::PUT,
         "DELETE" => Method::DELETE,
@@ -757,6 +1359,118 @@ fn parse_method_from_wire(content: String) -> Result<Method, String> {
     })
 }
 
-fn is_valid_extension_method(content: &String) -> bool {
+fn is_valid_extension_method(content: &str) -> bool {
     content.chars().all(|c| c.is_ascii_alphabetic())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::response::Status;
+    use std::io::Cursor;
+
+    fn parse_head(head: &str) -> Result<HttpRequest, ParseError> {
+        parse_request_head(&mut Cursor::new(head.as_bytes()))
+    }
+
+    #[test]
+    fn rejects_content_length_with_transfer_encoding() {
+        let error = parse_head(
+            "POST / HTTP/1.1\r\nContent-Length: 5\r\nTransfer-Encoding: chunked\r\n\r\n",
+        )
+        .unwrap_err();
+
+        assert!(matches!(error, ParseError::Malformed(_)));
+        assert_eq!(error.status(), Status::BadRequest);
+    }
+
+    #[test]
+    fn rejects_transfer_encodings_other_than_chunked() {
+        for coding in ["gzip", "gzip, chunked", "chunked, gzip", "identity"] {
+            let head = format!("POST / HTTP/1.1\r\nTransfer-Encoding: {}\r\n\r\n", coding);
+            let error = parse_head(&head).unwrap_err();
+
+            assert!(matches!(error, ParseError::NotImplemented(_)), "{}", coding);
+            assert_eq!(error.status(), Status::NotImplemented);
+        }
+    }
+
+    #[test]
+    fn rejects_conflicting_content_lengths() {
+        let error = parse_head("POST / HTTP/1.1\r\nContent-Length: 5\r\nContent-Length: 6\r\n\r\n")
+            .unwrap_err();
+        assert!(matches!(error, ParseError::Malformed(_)));
+
+        let error = parse_head("POST / HTTP/1.1\r\nContent-Length: +5\r\n\r\n").unwrap_err();
+        assert!(matches!(error, ParseError::Malformed(_)));
+    }
+
+    #[test]
+    fn rejects_invalid_header_names() {
+        for line in ["Bad Name: x", "Host : x", ": x", "X(y): z"] {
+            let head = format!("GET / HTTP/1.1\r\n{}\r\n\r\n", line);
+            let error = parse_head(&head).unwrap_err();
+            assert!(matches!(error, ParseError::Malformed(_)), "{}", line);
+        }
+    }
+
+    #[test]
+    fn rejects_line_breaks_in_header_values() {
+        let mut response = crate::response::HttpResponse::new(Status::Ok);
+        for value in ["a\r\nSet-Cookie: b", "a\nb", "a\rb", "a\0b"] {
+            assert!(response.insert_header("Location", value).is_err());
+            assert!(response.insert_header("X-Value", value).is_err());
+            assert!(response.insert_header("Cache-Control", value).is_err());
+        }
+        assert!(response.insert_header("X Value", "a").is_err());
+
+        let response = response.header("Location", "/a\r\n\r\n<html>");
+        assert_eq!(response.get_header("Location"), None);
+
+        let mut request = parse_head("GET / HTTP/1.1\r\n\r\n").unwrap();
+        assert!(request.insert_header("Accept", "a\r\nb").is_err());
+        assert!(request.insert_header("Accept", "text/html").is_ok());
+    }
+
+    #[test]
+    fn frames_bodies_by_chunked_or_length() {
+        let request = parse_head("POST / HTTP/1.1\r\nTransfer-Encoding: Chunked\r\n\r\n").unwrap();
+        assert_eq!(request.framing(), Some(Framing::Chunked));
+
+        let request = parse_head("POST / HTTP/1.1\r\nContent-Length: 12\r\n\r\n").unwrap();
+        assert_eq!(request.framing(), Some(Framing::Length(12)));
+
+        let request = parse_head("GET / HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(request.framing(), None);
+    }
+
+    #[test]
+    fn leaves_the_body_unread() {
+        let mut input = Cursor::new(b"POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello".to_vec());
+        parse_request_head(&mut input).unwrap();
+
+        let mut body = String::new();
+        input.read_to_string(&mut body).unwrap();
+        assert_eq!(body, "hello");
+    }
+
+    #[test]
+    fn reads_whole_requests() {
+        let mut input = Cursor::new(
+            b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n0\r\n\r\n".to_vec(),
+        );
+        let request = parse_http_request(&mut input).unwrap();
+
+        let mut body = String::new();
+        request.body.unwrap().read_to_string(&mut body).unwrap();
+        assert_eq!(body, "abc");
+
+        let mut input = Cursor::new(
+            b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\nabc\r\n0\r\n\r\n".to_vec(),
+        );
+        assert!(matches!(
+            parse_http_request(&mut input),
+            Err(ParseError::Malformed(_))
+        ));
+    }
+}
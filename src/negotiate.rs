@@ -0,0 +1,528 @@
+use crate::error::HttpError;
+use crate::extract::TypedHeader;
+use crate::models::{HttpRequest, RequestHeader};
+use crate::response::Status;
+use std::fmt;
+
+// Quality values are kept in thousandths, q=0.5 is 500, see RFC 9110 12.4.2
+pub const Q_MAX: u16 = 1000;
+
+// Parses a qvalue such as "1", "0.8" or "0.125"
+pub fn parse_q(value: &str) -> Option<u16> {
+    let value = value.trim();
+    let (int, frac) = value.split_once('.').unwrap_or((value, ""));
+
+    if frac.len() > 3 || !frac.bytes().all(|it| it.is_ascii_digit()) {
+        return None;
+    }
+
+    let frac = format!("{:0<3}", frac).parse::<u16>().ok()?;
+    match int {
+        "0" => Some(frac),
+        "1" if frac == 0 => Some(Q_MAX),
+        _ => None,
+    }
+}
+
+// One element of a comma separated list with optional parameters and a
+// weight, e.g. "gzip;q=0.8" or "text/html;level=1;q=0.5"
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Weighted {
+    pub value: String,
+    pub params: Vec<(String, String)>,
+    pub q: u16,
+}
+
+// Parses the weighted lists of Accept, Accept-Encoding, Accept-Language and
+// Accept-Charset. Parameters after q (accept-ext) are dropped, as are
+// elements with an invalid q.
+pub fn parse_weighted(value: &str) -> Vec<Weighted> {
+    let mut list = Vec::new();
+
+    for element in value.split(',').map(str::trim).filter(|it| !it.is_empty()) {
+        let mut parts = element.split(';').map(str::trim);
+        let value = parts.next().unwrap_or_default().to_string();
+        let mut params = Vec::new();
+        let mut q = Some(Q_MAX);
+
+        for param in parts {
+            let (name, param_value) = param.split_once('=').unwrap_or((param, ""));
+            let name = name.trim();
+            let param_value = param_value.trim().trim_matches('"');
+
+            if name.eq_ignore_ascii_case("q") {
+                q = parse_q(param_value);
+                break;
+            }
+            params.push((name.to_ascii_lowercase(), param_value.to_string()));
+        }
+
+        if let Some(q) = q {
+            list.push(Weighted { value, params, q });
+        }
+    }
+
+    list
+}
+
+// A media range of an Accept header such as text/*;q=0.5
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MediaRange {
+    pub kind: String,
+    pub subtype: String,
+    pub params: Vec<(String, String)>,
+    pub q: u16,
+}
+
+impl MediaRange {
+    pub fn parse(value: &str) -> Option<Self> {
+        parse_weighted(value)
+            .into_iter()
+            .next()
+            .and_then(Self::from_weighted)
+    }
+
+    fn from_weighted(weighted: Weighted) -> Option<Self> {
+        let (kind, subtype) = weighted.value.split_once('/')?;
+        let (kind, subtype) = (kind.trim(), subtype.trim());
+
+        if kind.is_empty() || subtype.is_empty() || (kind == "*" && subtype != "*") {
+            return None;
+        }
+
+        Some(Self {
+            kind: kind.to_ascii_lowercase(),
+            subtype: subtype.to_ascii_lowercase(),
+            params: weighted.params,
+            q: weighted.q,
+        })
+    }
+
+    // How specific the range is, more specific ranges take precedence
+    fn precedence(&self) -> (u8, usize) {
+        match (self.kind.as_str(), self.subtype.as_str()) {
+            ("*", _) => (0, 0),
+            (_, "*") => (1, 0),
+            _ => (2, self.params.len()),
+        }
+    }
+
+    pub fn matches(&self, media: &MediaRange) -> bool {
+        (self.kind == "*" || self.kind == media.kind)
+            && (self.subtype == "*" || self.subtype == media.subtype)
+            && self.params.iter().all(|(name, value)| {
+                media
+                    .params
+                    .iter()
+                    .any(|(n, v)| n == name && v.eq_ignore_ascii_case(value))
+            })
+    }
+}
+
+impl fmt::Display for MediaRange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.kind, self.subtype)?;
+        for (name, value) in &self.params {
+            write!(f, ";{}={}", name, value)?;
+        }
+        if self.q != Q_MAX {
+            write!(f, ";q={}", self.q as f32 / Q_MAX as f32)?;
+        }
+        Ok(())
+    }
+}
+
+pub fn parse_accept(value: &str) -> Vec<MediaRange> {
+    parse_weighted(value)
+        .into_iter()
+        .filter_map(MediaRange::from_weighted)
+        .collect()
+}
+
+// The weight `accept` gives `media`, from the most specific range matching
+// it. None when no range matches.
+pub fn media_quality(accept: &[MediaRange], media: &MediaRange) -> Option<u16> {
+    accept
+        .iter()
+        .filter(|range| range.matches(media))
+        .max_by_key(|range| range.precedence())
+        .map(|range| range.q)
+}
+
+// Picks the representation the client prefers out of `available`, e.g.
+// ["application/json", "text/html", "text/csv"]. Ties go to the earlier
+// entry, so list the preferred default first. A missing Accept header
+// accepts anything, a 406 error means nothing in `available` is
+// acceptable. Responses that depend on this should carry Vary: Accept.
+pub fn negotiate<'a>(request: &HttpRequest, available: &[&'a str]) -> Result<&'a str, HttpError> {
+    let not_acceptable = || {
+        HttpError::new(
+            Status::NotAcceptable,
+            format!("Available: {}", available.join(", ")),
+        )
+    };
+
+    let Some(accept) = request.request_headers.get(RequestHeader::Accept) else {
+        return available.first().copied().ok_or_else(not_acceptable);
+    };
+    let accept = parse_accept(&accept);
+
+    let mut best: Option<(&str, u16)> = None;
+    for &candidate in available {
+        let Some(media) = MediaRange::parse(candidate) else {
+            continue;
+        };
+
+        let q = media_quality(&accept, &media).unwrap_or(0);
+        if q > 0 && best.is_none_or(|(_, best_q)| q > best_q) {
+            best = Some((candidate, q));
+        }
+    }
+
+    best.map(|(media, _)| media).ok_or_else(not_acceptable)
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Accept(pub Vec<MediaRange>);
+
+impl TypedHeader for Accept {
+    fn name() -> &'static str {
+        "Accept"
+    }
+
+    fn decode(value: &str) -> Result<Self, String> {
+        Ok(Accept(parse_accept(value)))
+    }
+}
+
+// Whether the basic language range `range` matches `tag`: equal, a prefix
+// of it ending at a subtag boundary, or "*", see RFC 4647 3.3.1
+pub fn language_matches(range: &str, tag: &str) -> bool {
+    if range == "*" {
+        return true;
+    }
+
+    match tag.get(..range.len()) {
+        Some(prefix) if prefix.eq_ignore_ascii_case(range) => {
+            tag.len() == range.len() || tag.as_bytes()[range.len()] == b'-'
+        }
+        _ => false,
+    }
+}
+
+// The ranges of an Accept-Language header, highest weight first. Equal
+// weights keep the order the client sent them in.
+fn language_priority(accept_language: &str) -> Vec<Weighted> {
+    let mut ranges = parse_weighted(accept_language);
+    ranges.sort_by_key(|it| std::cmp::Reverse(it.q));
+    ranges
+}
+
+// Basic filtering, RFC 4647 3.3.1. The tags out of `available` matching a
+// range of the Accept-Language value, in the order the client prefers
+// them. Each tag is weighed by the most specific range matching it, so
+// "de-CH, de;q=0" accepts de-CH but no other German.
+pub fn filter_languages<'a>(accept_language: &str, available: &[&'a str]) -> Vec<&'a str> {
+    let ranges = parse_weighted(accept_language);
+
+    let mut matching: Vec<(&str, u16)> = available
+        .iter()
+        .filter_map(|&tag| {
+            ranges
+                .iter()
+                .filter(|range| language_matches(&range.value, tag))
+                .max_by_key(|range| (range.value != "*", range.value.len()))
+                .map(|range| (tag, range.q))
+        })
+        .filter(|(_, q)| *q > 0)
+        .collect();
+
+    matching.sort_by_key(|(_, q)| std::cmp::Reverse(*q));
+    matching.into_iter().map(|(tag, _)| tag).collect()
+}
+
+// Lookup, RFC 4647 3.4. The single tag out of `available` that best fits
+// the Accept-Language value: each range, most preferred first, is
+// shortened a subtag at a time until it names an available tag, so
+// "de-CH-1996" falls back to "de-CH" and then "de". Tags ruled out with
+// q=0 are never picked. None when no range leads to an available tag.
+pub fn lookup_language<'a>(accept_language: &str, available: &[&'a str]) -> Option<&'a str> {
+    let ranges = language_priority(accept_language);
+    let excluded = |tag: &str| {
+        ranges
+            .iter()
+            .any(|range| range.q == 0 && range.value != "*" && language_matches(&range.value, tag))
+    };
+
+    for range in ranges.iter().filter(|it| it.q > 0 && it.value != "*") {
+        let mut range = range.value.as_str();
+
+        loop {
+            let found = available
+                .iter()
+                .find(|tag| tag.eq_ignore_ascii_case(range) && !excluded(tag));
+            if let Some(tag) = found {
+                return Some(tag);
+            }
+
+            let Some((shorter, _)) = range.rsplit_once('-') else {
+                break;
+            };
+            range = shorter;
+
+            // A single letter subtag introduces an extension or private
+            // use and goes together with the subtag after it
+            if let Some((shorter, singleton)) = range.rsplit_once('-') {
+                if singleton.len() == 1 {
+                    range = shorter;
+                }
+            }
+        }
+    }
+
+    None
+}
+
+// Picks the language to answer in out of `available`, e.g. ["en", "de",
+// "fr-CA"], by lookup. The first entry is the default for clients without
+// an Accept-Language header or without a match, as serving some language
+// beats a 406 here. Set it as Content-Language and add Vary:
+// Accept-Language. None only when `available` is empty.
+pub fn negotiate_language<'a>(request: &HttpRequest, available: &[&'a str]) -> Option<&'a str> {
+    request
+        .request_headers
+        .get(RequestHeader::AcceptLanguage)
+        .and_then(|accept_language| lookup_language(&accept_language, available))
+        .or_else(|| available.first().copied())
+}
+
+// Picks the charset the client prefers out of `available`, e.g. ["utf-8",
+// "iso-8859-1"], see RFC 9110 12.5.2. Names compare case-insensitively and
+// "*" stands for every charset not listed. Ties go to the earlier entry, a
+// missing header accepts anything and a 406 error means none is acceptable.
+pub fn negotiate_charset<'a>(
+    request: &HttpRequest,
+    available: &[&'a str],
+) -> Result<&'a str, HttpError> {
+    let not_acceptable = || {
+        HttpError::new(
+            Status::NotAcceptable,
+            format!("Available charsets: {}", available.join(", ")),
+        )
+    };
+
+    let Some(accept_charset) = request.request_headers.get(RequestHeader::AcceptCharset) else {
+        return available.first().copied().ok_or_else(not_acceptable);
+    };
+    let accepted = parse_weighted(&accept_charset);
+
+    let mut best: Option<(&str, u16)> = None;
+    for &candidate in available {
+        let q = accepted
+            .iter()
+            .find(|it| it.value.eq_ignore_ascii_case(candidate))
+            .or_else(|| accepted.iter().find(|it| it.value == "*"))
+            .map_or(0, |it| it.q);
+
+        if q > 0 && best.is_none_or(|(_, best_q)| q > best_q) {
+            best = Some((candidate, q));
+        }
+    }
+
+    best.map(|(charset, _)| charset).ok_or_else(not_acceptable)
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AcceptLanguage(pub Vec<Weighted>);
+
+impl TypedHeader for AcceptLanguage {
+    fn name() -> &'static str {
+        "Accept-Language"
+    }
+
+    fn decode(value: &str) -> Result<Self, String> {
+        Ok(AcceptLanguage(parse_weighted(value)))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AcceptCharset(pub Vec<Weighted>);
+
+impl TypedHeader for AcceptCharset {
+    fn name() -> &'static str {
+        "Accept-Charset"
+    }
+
+    fn decode(value: &str) -> Result<Self, String> {
+        Ok(AcceptCharset(parse_weighted(value)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::test_request;
+
+    fn accept(value: &str) -> HttpRequest {
+        test_request("GET", "/", &[("Accept", value)])
+    }
+
+    fn media(value: &str) -> MediaRange {
+        MediaRange::parse(value).unwrap()
+    }
+
+    #[test]
+    fn parses_q_values() {
+        assert_eq!(parse_q("1"), Some(Q_MAX));
+        assert_eq!(parse_q("1.000"), Some(Q_MAX));
+        assert_eq!(parse_q("0.5"), Some(500));
+        assert_eq!(parse_q("0.125"), Some(125));
+        assert_eq!(parse_q("0"), Some(0));
+
+        for value in ["1.5", "1.001", "0.1234", "abc", "", "2", "-0.5", "0.5x"] {
+            assert_eq!(parse_q(value), None, "{}", value);
+        }
+    }
+
+    #[test]
+    fn drops_elements_with_invalid_weights() {
+        let list = parse_weighted("gzip;q=0.5, br;q=1.5, deflate;q=abc, identity");
+        let values: Vec<(&str, u16)> = list.iter().map(|it| (it.value.as_str(), it.q)).collect();
+        assert_eq!(values, [("gzip", 500), ("identity", Q_MAX)]);
+    }
+
+    #[test]
+    fn specific_ranges_take_precedence() {
+        let ranges = parse_accept("*/*;q=0.1, text/*;q=0.5, text/html;q=0.9");
+        assert_eq!(media_quality(&ranges, &media("text/html")), Some(900));
+        assert_eq!(media_quality(&ranges, &media("text/plain")), Some(500));
+        assert_eq!(media_quality(&ranges, &media("image/png")), Some(100));
+
+        let request = accept("*/*;q=0.1, text/*;q=0.5, text/html;q=0.9");
+        let available = ["image/png", "text/plain", "text/html"];
+        assert_eq!(negotiate(&request, &available).unwrap(), "text/html");
+    }
+
+    #[test]
+    fn zero_weight_excludes_a_matching_type() {
+        let request = accept("text/*, text/csv;q=0");
+        assert_eq!(
+            negotiate(&request, &["text/csv", "text/plain"]).unwrap(),
+            "text/plain"
+        );
+        assert!(negotiate(&request, &["text/csv"]).is_err());
+    }
+
+    #[test]
+    fn ties_keep_the_server_order() {
+        let request = accept("text/html, application/json");
+        let available = ["application/json", "text/html"];
+        assert_eq!(negotiate(&request, &available).unwrap(), "application/json");
+
+        let request = test_request("GET", "/", &[]);
+        assert_eq!(negotiate(&request, &available).unwrap(), "application/json");
+    }
+
+    #[test]
+    fn nothing_acceptable_is_406() {
+        let error =
+            negotiate(&accept("image/png"), &["text/html", "application/json"]).unwrap_err();
+        assert_eq!(error.status, Status::NotAcceptable);
+    }
+
+    fn with_header(name: &str, value: &str) -> HttpRequest {
+        test_request("GET", "/", &[(name, value)])
+    }
+
+    #[test]
+    fn filters_by_prefix_at_subtag_boundaries() {
+        let available = ["de", "en-US", "en-GB", "eng"];
+        assert_eq!(filter_languages("en", &available), ["en-US", "en-GB"]);
+        assert_eq!(
+            filter_languages("en-GB, de;q=0.5", &available),
+            ["en-GB", "de"]
+        );
+        assert!(language_matches("en", "EN-us"));
+        assert!(!language_matches("en", "eng"));
+    }
+
+    #[test]
+    fn zero_weight_excludes_languages() {
+        let available = ["de", "de-CH", "fr"];
+        assert_eq!(filter_languages("de-CH, de;q=0", &available), ["de-CH"]);
+        assert_eq!(lookup_language("de-CH;q=0, de", &["de-CH"]), None);
+        assert_eq!(lookup_language("fr;q=0, de", &available), Some("de"));
+    }
+
+    #[test]
+    fn lookup_truncates_ranges() {
+        assert_eq!(
+            lookup_language("de-CH-1996", &["de", "de-CH"]),
+            Some("de-CH")
+        );
+
+        // x and the subtag after it go together, so zh-Hant-CN-x is skipped
+        let available = ["zh-Hant-CN-x", "zh-Hant", "zh"];
+        assert_eq!(
+            lookup_language("zh-Hant-CN-x-private", &available),
+            Some("zh-Hant")
+        );
+        assert_eq!(
+            lookup_language("zh-Hant-CN-x-private", &["zh-Hant-CN", "zh"]),
+            Some("zh-Hant-CN")
+        );
+    }
+
+    #[test]
+    fn wildcard_languages() {
+        let available = ["en", "fr"];
+        assert_eq!(filter_languages("fr, *;q=0.5", &available), ["fr", "en"]);
+        assert!(filter_languages("*;q=0", &available).is_empty());
+
+        // Lookup skips *, the default answers instead
+        assert_eq!(lookup_language("*", &available), None);
+        let request = with_header("Accept-Language", "*");
+        assert_eq!(negotiate_language(&request, &available), Some("en"));
+    }
+
+    #[test]
+    fn languages_default_to_the_first_entry() {
+        let available = ["en", "de"];
+        let request = with_header("Accept-Language", "ja, ko;q=0.5");
+        assert_eq!(negotiate_language(&request, &available), Some("en"));
+
+        let request = test_request("GET", "/", &[]);
+        assert_eq!(negotiate_language(&request, &available), Some("en"));
+        assert_eq!(negotiate_language(&request, &[]), None);
+
+        let request = with_header("Accept-Language", "de-AT, en;q=0.5");
+        assert_eq!(negotiate_language(&request, &available), Some("de"));
+    }
+
+    #[test]
+    fn wildcard_charsets() {
+        let available = ["utf-8", "iso-8859-1"];
+
+        let request = with_header("Accept-Charset", "ISO-8859-1, *;q=0.1");
+        assert_eq!(
+            negotiate_charset(&request, &available).unwrap(),
+            "iso-8859-1"
+        );
+
+        let request = with_header("Accept-Charset", "*;q=0, utf-8");
+        assert_eq!(negotiate_charset(&request, &available).unwrap(), "utf-8");
+
+        let request = with_header("Accept-Charset", "*");
+        assert_eq!(negotiate_charset(&request, &available).unwrap(), "utf-8");
+    }
+
+    #[test]
+    fn unacceptable_charsets_are_406() {
+        let request = with_header("Accept-Charset", "iso-8859-1, utf-8;q=0");
+        let error = negotiate_charset(&request, &["utf-8"]).unwrap_err();
+        assert_eq!(error.status, Status::NotAcceptable);
+
+        let request = test_request("GET", "/", &[]);
+        assert_eq!(negotiate_charset(&request, &["utf-8"]).unwrap(), "utf-8");
+    }
+}
@@ -1 +1,3 @@
 pub mod request_parser;
+pub mod uri;
+pub mod urlencoded;
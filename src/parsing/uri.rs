@@ -0,0 +1,73 @@
+// Resolves "." and ".." segments of a path, see RFC 3986 5.2.4. ".." never
+// climbs above the root, "/a/../../b" becomes "/b".
+pub fn remove_dot_segments(path: &str) -> String {
+    let mut input = path;
+    let mut output = String::with_capacity(path.len());
+
+    while !input.is_empty() {
+        if let Some(rest) = input.strip_prefix("../") {
+            input = rest;
+        } else if let Some(rest) = input.strip_prefix("./") {
+            input = rest;
+        } else if input.starts_with("/./") {
+            input = &input[2..];
+        } else if input == "/." {
+            input = "/";
+        } else if input.starts_with("/../") || input == "/.." {
+            input = if input == "/.." { "/" } else { &input[3..] };
+            output.truncate(output.rfind('/').unwrap_or(0));
+        } else if input == "." || input == ".." {
+            input = "";
+        } else {
+            // The first segment with its leading slash, up to the next one
+            let end = input
+                .bytes()
+                .skip(1)
+                .position(|it| it == b'/')
+                .map_or(input.len(), |idx| idx + 1);
+            output.push_str(&input[..end]);
+            input = &input[end..];
+        }
+    }
+
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn removes_dot_segments_like_rfc_3986() {
+        // The examples of RFC 3986 5.2.4 and 5.4
+        assert_eq!(remove_dot_segments("/a/b/c/./../../g"), "/a/g");
+        assert_eq!(remove_dot_segments("mid/content=5/../6"), "mid/6");
+        assert_eq!(remove_dot_segments("/b/c/./g"), "/b/c/g");
+        assert_eq!(remove_dot_segments("/b/c/g/."), "/b/c/g/");
+        assert_eq!(remove_dot_segments("/b/c/.."), "/b/");
+        assert_eq!(remove_dot_segments("/b/c/../g"), "/b/g");
+    }
+
+    #[test]
+    fn never_climbs_above_the_root() {
+        assert_eq!(remove_dot_segments("/.."), "/");
+        assert_eq!(remove_dot_segments("/../secret"), "/secret");
+        assert_eq!(remove_dot_segments("/a/../../../etc/passwd"), "/etc/passwd");
+        assert_eq!(remove_dot_segments("../../x"), "x");
+        assert_eq!(remove_dot_segments(".."), "");
+    }
+
+    #[test]
+    fn keeps_dots_inside_segments() {
+        assert_eq!(remove_dot_segments("/a/..b/c."), "/a/..b/c.");
+        assert_eq!(remove_dot_segments("/.hidden/..."), "/.hidden/...");
+        // Encoded dots are left to whoever decodes the segments
+        assert_eq!(remove_dot_segments("/a/%2e%2e/b"), "/a/%2e%2e/b");
+    }
+
+    #[test]
+    fn handles_multi_byte_segments() {
+        assert_eq!(remove_dot_segments("/ä/../ö"), "/ö");
+        assert_eq!(remove_dot_segments("ü/x"), "ü/x");
+    }
+}
@@ -0,0 +1,101 @@
+use std::collections::HashMap;
+
+// Decodes %XX escapes. With `plus_as_space`, '+' becomes ' ' as in
+// application/x-www-form-urlencoded data.
+pub fn percent_decode(content: &str, plus_as_space: bool) -> Result<String, String> {
+    let bytes = content.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => {
+                let hex = content
+                    .get(i + 1..i + 3)
+                    .ok_or_else(|| format!("Truncated percent escape in: {}", content))?;
+                // from_str_radix alone would take "+f" too
+                if !hex.bytes().all(|it| it.is_ascii_hexdigit()) {
+                    return Err(format!("Invalid percent escape %{} in: {}", hex, content));
+                }
+                let byte = u8::from_str_radix(hex, 16)
+                    .map_err(|_| format!("Invalid percent escape %{} in: {}", hex, content))?;
+                decoded.push(byte);
+                i += 3;
+            }
+            b'+' if plus_as_space => {
+                decoded.push(b' ');
+                i += 1;
+            }
+            b => {
+                decoded.push(b);
+                i += 1;
+            }
+        }
+    }
+
+    String::from_utf8(decoded).map_err(|_| format!("Decoded value is not UTF-8: {}", content))
+}
+
+// Escapes everything but the unreserved characters of RFC 3986 2.3, so the
+// result is safe as a path segment or query value
+pub fn percent_encode(content: &str) -> String {
+    let mut encoded = String::with_capacity(content.len());
+    for b in content.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(b as char)
+            }
+            b => encoded.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    encoded
+}
+
+// Parses `a=1&b=2` pairs, later duplicates win
+pub fn parse_urlencoded(content: &str) -> Result<HashMap<String, String>, String> {
+    let mut pairs = HashMap::new();
+
+    for pair in content.split('&').filter(|it| !it.is_empty()) {
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+        pairs.insert(percent_decode(key, true)?, percent_decode(value, true)?);
+    }
+
+    Ok(pairs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_percent_escapes() {
+        assert_eq!(percent_decode("a%20b+c", false).unwrap(), "a b+c");
+        assert_eq!(percent_decode("a%20b+c", true).unwrap(), "a b c");
+        assert_eq!(percent_decode("%C3%A9%c3%a9", false).unwrap(), "éé");
+    }
+
+    #[test]
+    fn rejects_malformed_escapes() {
+        for value in ["%", "%2", "%+f", "%-1", "% 1", "%zz", "%é", "%FF"] {
+            assert!(percent_decode(value, false).is_err(), "{}", value);
+        }
+    }
+
+    #[test]
+    fn round_trips_encoded_values() {
+        let value = "a b/c?d=e&f+g~é";
+        assert_eq!(
+            percent_decode(&percent_encode(value), false).unwrap(),
+            value
+        );
+    }
+
+    #[test]
+    fn later_duplicates_win() {
+        let pairs = parse_urlencoded("a=1&&b&a=2&c=x+y%21").unwrap();
+        assert_eq!(pairs["a"], "2");
+        assert_eq!(pairs["b"], "");
+        assert_eq!(pairs["c"], "x y!");
+        assert!(parse_urlencoded("a=%+1").is_err());
+    }
+}
@@ -0,0 +1,596 @@
+use crate::conditional::{EntityTag, Validators};
+use crate::date::HttpDate;
+use crate::extract::TypedHeader;
+use crate::middleware::Middleware;
+use crate::models::{HttpRequest, Method, RequestHeader, ResponseHeader};
+use crate::response::{Body, HttpResponse, ReadSeek, Status};
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::{self, Read, SeekFrom};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// More ranges than this (after merging overlaps) are ignored and the whole
+// body is sent, so a request can't ask for thousands of tiny parts
+const MAX_RANGES: usize = 32;
+
+// One byte-range-spec of a Range header, see RFC 9110 14.1.1
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ByteRange {
+    // first-last, both inclusive
+    FromTo(u64, u64),
+    // first- up to the end
+    From(u64),
+    // -length, the last `length` bytes
+    Suffix(u64),
+}
+
+impl ByteRange {
+    // First and last byte (inclusive) of the range in a body of `length`
+    // bytes, None when it is not satisfiable
+    pub fn resolve(&self, length: u64) -> Option<(u64, u64)> {
+        match *self {
+            ByteRange::FromTo(first, last) if first < length => Some((first, last.min(length - 1))),
+            ByteRange::From(first) if first < length => Some((first, length - 1)),
+            ByteRange::Suffix(suffix) if suffix > 0 && length > 0 => {
+                Some((length.saturating_sub(suffix), length - 1))
+            }
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ByteRange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ByteRange::FromTo(first, last) => write!(f, "{}-{}", first, last),
+            ByteRange::From(first) => write!(f, "{}-", first),
+            ByteRange::Suffix(suffix) => write!(f, "-{}", suffix),
+        }
+    }
+}
+
+// Parses "bytes=0-99, 200-, -50". Other range units are an error, servers
+// ignore Range headers they don't understand.
+pub fn parse_range(value: &str) -> Result<Vec<ByteRange>, String> {
+    let (unit, specs) = value
+        .split_once('=')
+        .ok_or_else(|| "Expecting unit=ranges".to_string())?;
+
+    if !unit.trim().eq_ignore_ascii_case("bytes") {
+        return Err(format!("Unsupported range unit: {}", unit.trim()));
+    }
+
+    // Digits only, parse alone would take a sign
+    let parse = |it: &str| {
+        let it = it.trim();
+        if it.is_empty() || !it.bytes().all(|it| it.is_ascii_digit()) {
+            return Err(format!("Invalid range position: {}", it));
+        }
+        it.parse::<u64>()
+            .map_err(|_| format!("Invalid range position: {}", it))
+    };
+
+    let mut ranges = Vec::new();
+
+    for spec in specs.split(',').map(str::trim).filter(|it| !it.is_empty()) {
+        let (first, last) = spec
+            .split_once('-')
+            .ok_or_else(|| format!("Invalid byte range: {}", spec))?;
+
+        let range = match (first.trim().is_empty(), last.trim().is_empty()) {
+            (true, false) => ByteRange::Suffix(parse(last)?),
+            (false, true) => ByteRange::From(parse(first)?),
+            (false, false) => {
+                let (first, last) = (parse(first)?, parse(last)?);
+                if last < first {
+                    return Err(format!("Invalid byte range: {}", spec));
+                }
+                ByteRange::FromTo(first, last)
+            }
+            (true, true) => return Err(format!("Invalid byte range: {}", spec)),
+        };
+
+        ranges.push(range);
+    }
+
+    if ranges.is_empty() {
+        return Err("Expecting at least one byte range".to_string());
+    }
+
+    Ok(ranges)
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Range(pub Vec<ByteRange>);
+
+impl TypedHeader for Range {
+    fn name() -> &'static str {
+        "Range"
+    }
+
+    fn decode(value: &str) -> Result<Self, String> {
+        parse_range(value).map(Range)
+    }
+}
+
+// Answers GET requests with a Range header with 206 Partial Content (one
+// range) or multipart/byteranges (several), or 416 when no range fits the
+// body. Only 200 responses whose body length is known up front take part,
+// and those advertise Accept-Ranges: bytes, to HEAD requests as well.
+pub fn apply_ranges(request: &HttpRequest, response: &mut HttpResponse) {
+    let method = &request.request_line.method;
+    if !matches!(method, Method::GET | Method::HEAD) || response.status != Status::Ok {
+        return;
+    }
+
+    let length = match &response.body {
+        Body::Bytes(bytes) => bytes.len() as u64,
+        Body::Seekable { length, .. } => *length,
+        Body::Reader {
+            length: Some(length),
+            ..
+        } => *length,
+        _ => return,
+    };
+
+    let _ = response
+        .response_headers
+        .insert(ResponseHeader::AcceptRanges, "bytes");
+
+    // Range only applies to GET, RFC 9110 14.2
+    if *method == Method::HEAD {
+        return;
+    }
+
+    let Some(range) = request.request_headers.get(RequestHeader::Range) else {
+        return;
+    };
+
+    if let Some(if_range) = request.request_headers.get(RequestHeader::IfRange) {
+        if !if_range_matches(&if_range, response) {
+            return;
+        }
+    }
+
+    let Ok(ranges) = parse_range(&range) else {
+        return;
+    };
+
+    let mut resolved: Vec<(u64, u64)> = ranges.iter().filter_map(|it| it.resolve(length)).collect();
+
+    if resolved.is_empty() {
+        *response = HttpResponse::error(Status::RangeNotSatisfiable)
+            .header("Content-Range", &format!("bytes */{}", length))
+            .header("Accept-Ranges", "bytes");
+        return;
+    }
+
+    let resolved = coalesce(&mut resolved);
+    if resolved.len() > MAX_RANGES {
+        return;
+    }
+
+    let source = match std::mem::take(&mut response.body) {
+        Body::Bytes(bytes) => Source::Seekable(Box::new(io::Cursor::new(bytes))),
+        Body::Seekable { reader, .. } => Source::Seekable(reader),
+        Body::Reader { reader, .. } => Source::Stream(reader, 0),
+        _ => unreachable!("Only bodies with a known length get this far"),
+    };
+
+    let reader = if let [(first, last)] = resolved[..] {
+        let _ = response.insert_header(
+            "Content-Range",
+            &format!("bytes {}-{}/{}", first, last, length),
+        );
+        RangeReader::new(source, vec![(Vec::new(), first, last)], Vec::new())
+    } else {
+        let boundary = boundary();
+        let content_type = response.get_header("Content-Type");
+
+        let parts = resolved
+            .iter()
+            .map(|&(first, last)| {
+                let mut header = format!("\r\n--{}\r\n", boundary);
+                if let Some(content_type) = &content_type {
+                    header.push_str(&format!("Content-Type: {}\r\n", content_type));
+                }
+                header.push_str(&format!(
+                    "Content-Range: bytes {}-{}/{}\r\n\r\n",
+                    first, last, length
+                ));
+                (header.into_bytes(), first, last)
+            })
+            .collect();
+
+        let _ = response.insert_header(
+            "Content-Type",
+            &format!("multipart/byteranges; boundary={}", boundary),
+        );
+        RangeReader::new(
+            source,
+            parts,
+            format!("\r\n--{}--\r\n", boundary).into_bytes(),
+        )
+    };
+
+    let length = reader.length();
+    response.status = Status::PartialContent;
+    response.body = Body::reader(reader, Some(length));
+}
+
+// Serves Range requests for every response passing through, see apply_ranges
+pub struct RangeRequests;
+
+impl Middleware for RangeRequests {
+    fn after(&self, request: &HttpRequest, response: &mut HttpResponse) {
+        apply_ranges(request, response);
+    }
+}
+
+// If-Range holds either an entity tag or a date. Ranges are only served
+// when it names the current representation, using a strong comparison.
+fn if_range_matches(if_range: &str, response: &HttpResponse) -> bool {
+    let validators = Validators::from_response(response);
+    let if_range = if_range.trim();
+
+    if if_range.starts_with('"') || if_range.starts_with("W/") {
+        match (EntityTag::parse(if_range), &validators.etag) {
+            (Ok(tag), Some(etag)) => tag.strong_eq(etag),
+            _ => false,
+        }
+    } else {
+        match (HttpDate::parse(if_range), validators.last_modified) {
+            (Ok(date), Some(modified)) => date == modified,
+            _ => false,
+        }
+    }
+}
+
+// Sorts the ranges and merges overlapping and adjacent ones, so the parts
+// can be read front to back
+fn coalesce(ranges: &mut [(u64, u64)]) -> Vec<(u64, u64)> {
+    ranges.sort_unstable();
+
+    let mut merged: Vec<(u64, u64)> = Vec::new();
+    for &(first, last) in ranges.iter() {
+        match merged.last_mut() {
+            Some((_, end)) if first <= end.saturating_add(1) => *end = (*end).max(last),
+            _ => merged.push((first, last)),
+        }
+    }
+    merged
+}
+
+fn boundary() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |it| it.subsec_nanos() as u64);
+    format!(
+        "{:016x}{:08x}",
+        nanos.rotate_left(32) ^ std::process::id() as u64,
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+enum Source {
+    Seekable(Box<dyn ReadSeek + Send>),
+    // Plain readers can only skip forward, position is how far they got
+    Stream(Box<dyn Read + Send>, u64),
+}
+
+impl Source {
+    fn seek_to(&mut self, offset: u64) -> io::Result<()> {
+        match self {
+            Source::Seekable(reader) => reader.seek(SeekFrom::Start(offset)).map(|_| ()),
+            Source::Stream(reader, position) => {
+                let skip = offset.checked_sub(*position).ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidInput, "Can't seek backwards")
+                })?;
+                let skipped = io::copy(&mut reader.take(skip), &mut io::sink())?;
+                *position += skipped;
+
+                if skipped != skip {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "Body ended before the range",
+                    ));
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Source::Seekable(reader) => reader.read(buf),
+            Source::Stream(reader, position) => {
+                let read = reader.read(buf)?;
+                *position += read as u64;
+                Ok(read)
+            }
+        }
+    }
+}
+
+// Reads the parts of a ranged body in order, each part being the header
+// bytes (empty for single ranges) followed by the bytes first..=last
+struct RangeReader {
+    source: Source,
+    parts: VecDeque<(Vec<u8>, u64, u64)>,
+    epilogue: Option<Vec<u8>>,
+    pending: io::Cursor<Vec<u8>>,
+    remaining: u64,
+}
+
+impl RangeReader {
+    fn new(source: Source, parts: Vec<(Vec<u8>, u64, u64)>, epilogue: Vec<u8>) -> Self {
+        Self {
+            source,
+            parts: parts.into(),
+            epilogue: Some(epilogue),
+            pending: io::Cursor::new(Vec::new()),
+            remaining: 0,
+        }
+    }
+
+    fn length(&self) -> u64 {
+        let parts: u64 = self
+            .parts
+            .iter()
+            .map(|(header, first, last)| header.len() as u64 + last - first + 1)
+            .sum();
+        parts + self.epilogue.as_ref().map_or(0, |it| it.len() as u64)
+    }
+}
+
+impl Read for RangeReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let read = self.pending.read(buf)?;
+            if read > 0 || buf.is_empty() {
+                return Ok(read);
+            }
+
+            if self.remaining > 0 {
+                let max = buf
+                    .len()
+                    .min(self.remaining.min(usize::MAX as u64) as usize);
+                let read = self.source.read(&mut buf[..max])?;
+                if read == 0 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "Body ended before the range",
+                    ));
+                }
+                self.remaining -= read as u64;
+                return Ok(read);
+            }
+
+            if let Some((header, first, last)) = self.parts.pop_front() {
+                self.source.seek_to(first)?;
+                self.pending = io::Cursor::new(header);
+                self.remaining = last - first + 1;
+            } else if let Some(epilogue) = self.epilogue.take() {
+                self.pending = io::Cursor::new(epilogue);
+            } else {
+                return Ok(0);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::test_request;
+
+    const DIGITS: &str = "0123456789";
+
+    fn ranged(method: &str, headers: &[(&str, &str)], mut response: HttpResponse) -> HttpResponse {
+        apply_ranges(&test_request(method, "/", headers), &mut response);
+        response
+    }
+
+    fn digits() -> HttpResponse {
+        HttpResponse::text(Status::Ok, DIGITS)
+    }
+
+    fn body(response: HttpResponse) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        match response.body {
+            Body::Reader { mut reader, .. } => {
+                reader.read_to_end(&mut bytes).unwrap();
+            }
+            Body::Bytes(body) => bytes = body,
+            body => panic!("Unexpected body {:?}", body),
+        }
+        bytes
+    }
+
+    #[test]
+    fn parses_byte_ranges() {
+        assert_eq!(
+            parse_range("bytes=0-99, 200-, -50").unwrap(),
+            [
+                ByteRange::FromTo(0, 99),
+                ByteRange::From(200),
+                ByteRange::Suffix(50)
+            ]
+        );
+        assert_eq!(
+            parse_range("Bytes = 5-5").unwrap(),
+            [ByteRange::FromTo(5, 5)]
+        );
+
+        for value in [
+            "bytes=5-4",
+            "items=0-1",
+            "bytes",
+            "bytes=",
+            "bytes=-",
+            "bytes=+0-+5",
+            "bytes=0x1-2",
+            "bytes=1-2-3",
+            "bytes=-+5",
+            "bytes=99999999999999999999-",
+        ] {
+            assert!(parse_range(value).is_err(), "{}", value);
+        }
+    }
+
+    #[test]
+    fn resolves_ranges_against_the_length() {
+        assert_eq!(ByteRange::Suffix(3).resolve(10), Some((7, 9)));
+        assert_eq!(ByteRange::Suffix(30).resolve(10), Some((0, 9)));
+        assert_eq!(ByteRange::From(4).resolve(10), Some((4, 9)));
+        assert_eq!(ByteRange::FromTo(8, 20).resolve(10), Some((8, 9)));
+        assert_eq!(ByteRange::From(10).resolve(10), None);
+        assert_eq!(ByteRange::Suffix(0).resolve(10), None);
+    }
+
+    #[test]
+    fn serves_single_ranges() {
+        let response = ranged("GET", &[("Range", "bytes=-3")], digits());
+        assert_eq!(response.status, Status::PartialContent);
+        assert_eq!(
+            response.get_header("Accept-Ranges").as_deref(),
+            Some("bytes")
+        );
+        assert_eq!(
+            response.get_header("Content-Range").as_deref(),
+            Some("bytes 7-9/10")
+        );
+        assert_eq!(body(response), b"789");
+    }
+
+    #[test]
+    fn unsatisfiable_ranges_are_416() {
+        let response = ranged("GET", &[("Range", "bytes=10-, 20-30")], digits());
+        assert_eq!(response.status, Status::RangeNotSatisfiable);
+        assert_eq!(
+            response.get_header("Content-Range").as_deref(),
+            Some("bytes */10")
+        );
+    }
+
+    #[test]
+    fn ignores_malformed_and_excessive_ranges() {
+        let response = ranged("GET", &[("Range", "bytes=+0-+5")], digits());
+        assert_eq!(response.status, Status::Ok);
+
+        let many: Vec<String> = (0..=MAX_RANGES)
+            .map(|i| format!("{0}-{0}", i * 2))
+            .collect();
+        let response = HttpResponse::text(Status::Ok, "x".repeat(100));
+        let response = ranged(
+            "GET",
+            &[("Range", &format!("bytes={}", many.join(",")))],
+            response,
+        );
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(response.body.length(), Some(100));
+    }
+
+    #[test]
+    fn coalesces_overlapping_ranges() {
+        let response = ranged("GET", &[("Range", "bytes=0-3, 2-5, 6-6")], digits());
+        assert_eq!(
+            response.get_header("Content-Range").as_deref(),
+            Some("bytes 0-6/10")
+        );
+        assert_eq!(body(response), b"0123456");
+    }
+
+    #[test]
+    fn frames_multiple_ranges_as_multipart() {
+        let response = ranged("GET", &[("Range", "bytes=0-1, 5-6")], digits());
+        assert_eq!(response.status, Status::PartialContent);
+        assert_eq!(response.get_header("Content-Range"), None);
+
+        let content_type = response.get_header("Content-Type").unwrap();
+        let boundary = content_type
+            .strip_prefix("multipart/byteranges; boundary=")
+            .unwrap()
+            .to_string();
+        let length = response.body.length().unwrap();
+
+        let body = String::from_utf8(body(response)).unwrap();
+        assert_eq!(body.len() as u64, length);
+        assert_eq!(
+            body,
+            format!(
+                "\r\n--{0}\r\nContent-Type: text/plain; charset=utf-8\r\n\
+                 Content-Range: bytes 0-1/10\r\n\r\n01\
+                 \r\n--{0}\r\nContent-Type: text/plain; charset=utf-8\r\n\
+                 Content-Range: bytes 5-6/10\r\n\r\n56\
+                 \r\n--{0}--\r\n",
+                boundary
+            )
+        );
+    }
+
+    #[test]
+    fn if_range_needs_a_strong_match() {
+        let tagged = |etag: &str| digits().header("ETag", etag);
+
+        let response = ranged(
+            "GET",
+            &[("Range", "bytes=0-1"), ("If-Range", "\"v1\"")],
+            tagged("\"v1\""),
+        );
+        assert_eq!(response.status, Status::PartialContent);
+
+        let response = ranged(
+            "GET",
+            &[("Range", "bytes=0-1"), ("If-Range", "\"v0\"")],
+            tagged("\"v1\""),
+        );
+        assert_eq!(response.status, Status::Ok);
+
+        let response = ranged(
+            "GET",
+            &[("Range", "bytes=0-1"), ("If-Range", "W/\"v1\"")],
+            tagged("W/\"v1\""),
+        );
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(body(response), DIGITS.as_bytes());
+    }
+
+    #[test]
+    fn if_range_compares_dates() {
+        let modified = "Sun, 06 Nov 1994 08:49:37 GMT";
+        let dated = || digits().header("Last-Modified", modified);
+
+        let response = ranged(
+            "GET",
+            &[("Range", "bytes=0-1"), ("If-Range", modified)],
+            dated(),
+        );
+        assert_eq!(response.status, Status::PartialContent);
+
+        let response = ranged(
+            "GET",
+            &[
+                ("Range", "bytes=0-1"),
+                ("If-Range", "Sun, 06 Nov 1994 08:49:36 GMT"),
+            ],
+            dated(),
+        );
+        assert_eq!(response.status, Status::Ok);
+    }
+
+    #[test]
+    fn advertises_ranges_to_head_without_serving_them() {
+        let response = ranged("HEAD", &[("Range", "bytes=0-1")], digits());
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(
+            response.get_header("Accept-Ranges").as_deref(),
+            Some("bytes")
+        );
+        assert_eq!(response.get_header("Content-Range"), None);
+        assert_eq!(response.body.length(), Some(10));
+    }
+}
@@ -0,0 +1,833 @@
+use crate::date::date_header;
+use crate::error::HttpError;
+use crate::models::{
+    check_header_name, check_header_value, EntityHeader, EntityHeaders, GeneralHeader,
+    GeneralHeaders, HttpRequest, Method, ResponseHeader, ResponseHeaders,
+};
+use std::fmt;
+use std::io::{self, Read, Seek, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Status {
+    Continue,
+    SwitchingProtocols,
+    EarlyHints,
+    Ok,
+    Created,
+    Accepted,
+    NoContent,
+    PartialContent,
+    MovedPermanently,
+    Found,
+    SeeOther,
+    NotModified,
+    TemporaryRedirect,
+    PermanentRedirect,
+    BadRequest,
+    Unauthorized,
+    Forbidden,
+    NotFound,
+    MethodNotAllowed,
+    NotAcceptable,
+    RequestTimeout,
+    Conflict,
+    Gone,
+    LengthRequired,
+    PreconditionFailed,
+    ContentTooLarge,
+    UriTooLong,
+    UnsupportedMediaType,
+    RangeNotSatisfiable,
+    ExpectationFailed,
+    RequestHeaderFieldsTooLarge,
+    InternalServerError,
+    NotImplemented,
+    BadGateway,
+    ServiceUnavailable,
+    HttpVersionNotSupported,
+    Other(u16),
+}
+
+impl Status {
+    pub fn code(&self) -> u16 {
+        match self {
+            Status::Continue => 100,
+            Status::SwitchingProtocols => 101,
+            Status::EarlyHints => 103,
+            Status::Ok => 200,
+            Status::Created => 201,
+            Status::Accepted => 202,
+            Status::NoContent => 204,
+            Status::PartialContent => 206,
+            Status::MovedPermanently => 301,
+            Status::Found => 302,
+            Status::SeeOther => 303,
+            Status::NotModified => 304,
+            Status::TemporaryRedirect => 307,
+            Status::PermanentRedirect => 308,
+            Status::BadRequest => 400,
+            Status::Unauthorized => 401,
+            Status::Forbidden => 403,
+            Status::NotFound => 404,
+            Status::MethodNotAllowed => 405,
+            Status::NotAcceptable => 406,
+            Status::RequestTimeout => 408,
+            Status::Conflict => 409,
+            Status::Gone => 410,
+            Status::LengthRequired => 411,
+            Status::PreconditionFailed => 412,
+            Status::ContentTooLarge => 413,
+            Status::UriTooLong => 414,
+            Status::UnsupportedMediaType => 415,
+            Status::RangeNotSatisfiable => 416,
+            Status::ExpectationFailed => 417,
+            Status::RequestHeaderFieldsTooLarge => 431,
+            Status::InternalServerError => 500,
+            Status::NotImplemented => 501,
+            Status::BadGateway => 502,
+            Status::ServiceUnavailable => 503,
+            Status::HttpVersionNotSupported => 505,
+            Status::Other(code) => *code,
+        }
+    }
+
+    pub fn reason(&self) -> &'static str {
+        match self {
+            Status::Continue => "Continue",
+            Status::SwitchingProtocols => "Switching Protocols",
+            Status::EarlyHints => "Early Hints",
+            Status::Ok => "OK",
+            Status::Created => "Created",
+            Status::Accepted => "Accepted",
+            Status::NoContent => "No Content",
+            Status::PartialContent => "Partial Content",
+            Status::MovedPermanently => "Moved Permanently",
+            Status::Found => "Found",
+            Status::SeeOther => "See Other",
+            Status::NotModified => "Not Modified",
+            Status::TemporaryRedirect => "Temporary Redirect",
+            Status::PermanentRedirect => "Permanent Redirect",
+            Status::BadRequest => "Bad Request",
+            Status::Unauthorized => "Unauthorized",
+            Status::Forbidden => "Forbidden",
+            Status::NotFound => "Not Found",
+            Status::MethodNotAllowed => "Method Not Allowed",
+            Status::NotAcceptable => "Not Acceptable",
+            Status::RequestTimeout => "Request Timeout",
+            Status::Conflict => "Conflict",
+            Status::Gone => "Gone",
+            Status::LengthRequired => "Length Required",
+            Status::PreconditionFailed => "Precondition Failed",
+            Status::ContentTooLarge => "Content Too Large",
+            Status::UriTooLong => "URI Too Long",
+            Status::UnsupportedMediaType => "Unsupported Media Type",
+            Status::RangeNotSatisfiable => "Range Not Satisfiable",
+            Status::ExpectationFailed => "Expectation Failed",
+            Status::RequestHeaderFieldsTooLarge => "Request Header Fields Too Large",
+            Status::InternalServerError => "Internal Server Error",
+            Status::NotImplemented => "Not Implemented",
+            Status::BadGateway => "Bad Gateway",
+            Status::ServiceUnavailable => "Service Unavailable",
+            Status::HttpVersionNotSupported => "HTTP Version Not Supported",
+            Status::Other(_) => "",
+        }
+    }
+
+    pub fn is_informational(&self) -> bool {
+        (100..200).contains(&self.code())
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.code() >= 400
+    }
+
+    // 1xx, 204 and 304 responses never carry a body, see RFC 9112 6.3
+    pub fn allows_body(&self) -> bool {
+        !self.is_informational() && !matches!(self, Status::NoContent | Status::NotModified)
+    }
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.code(), self.reason())
+    }
+}
+
+#[derive(Default)]
+pub enum Body {
+    #[default]
+    Empty,
+    Bytes(Vec<u8>),
+    // Copied from `reader` as it is read. Without a length the body is sent
+    // chunked, or on HTTP/1.0 until the connection closes.
+    Reader {
+        reader: Box<dyn Read + Send>,
+        length: Option<u64>,
+    },
+    // A reader of known length that can be positioned, e.g. a File, so
+    // ranges of it can be served without reading what comes before them
+    Seekable {
+        reader: Box<dyn ReadSeek + Send>,
+        length: u64,
+    },
+    // Each item is sent as one chunk and flushed, so clients see it right away
+    Chunks(Box<dyn Iterator<Item = Vec<u8>> + Send>),
+}
+
+impl Body {
+    pub fn reader<R: Read + Send + 'static>(reader: R, length: Option<u64>) -> Self {
+        Body::Reader {
+            reader: Box::new(reader),
+            length,
+        }
+    }
+
+    pub fn seekable<R: Read + Seek + Send + 'static>(reader: R, length: u64) -> Self {
+        Body::Seekable {
+            reader: Box::new(reader),
+            length,
+        }
+    }
+
+    pub fn chunks<I>(chunks: I) -> Self
+    where
+        I: IntoIterator<Item = Vec<u8>>,
+        I::IntoIter: Send + 'static,
+    {
+        Body::Chunks(Box::new(chunks.into_iter()))
+    }
+
+    // Length of the body if known before it is written
+    pub fn length(&self) -> Option<u64> {
+        match self {
+            Body::Empty => Some(0),
+            Body::Bytes(bytes) => Some(bytes.len() as u64),
+            Body::Reader { length, .. } => *length,
+            Body::Seekable { length, .. } => Some(*length),
+            Body::Chunks(_) => None,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.length() == Some(0)
+    }
+}
+
+impl fmt::Debug for Body {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Body::Empty => write!(f, "Empty"),
+            Body::Bytes(bytes) => write!(f, "Bytes({} bytes)", bytes.len()),
+            Body::Reader { length, .. } => {
+                f.debug_struct("Reader").field("length", length).finish()
+            }
+            Body::Seekable { length, .. } => {
+                f.debug_struct("Seekable").field("length", length).finish()
+            }
+            Body::Chunks(_) => write!(f, "Chunks"),
+        }
+    }
+}
+
+pub trait ReadSeek: Read + Seek {}
+
+impl<T: Read + Seek> ReadSeek for T {}
+
+// Produces the trailer fields sent after the last chunk of a chunked body,
+// called once the body is written (e.g. to send a checksum of it). Fields
+// not allowed in trailers, like Content-Length or Host, are dropped.
+pub struct Trailers(pub Box<dyn FnOnce() -> Vec<(String, String)> + Send>);
+
+impl fmt::Debug for Trailers {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Trailers")
+    }
+}
+
+impl From<Vec<u8>> for Body {
+    fn from(bytes: Vec<u8>) -> Self {
+        Body::Bytes(bytes)
+    }
+}
+
+impl From<String> for Body {
+    fn from(text: String) -> Self {
+        Body::Bytes(text.into_bytes())
+    }
+}
+
+impl From<&str> for Body {
+    fn from(text: &str) -> Self {
+        Body::Bytes(text.as_bytes().to_vec())
+    }
+}
+
+#[derive(Debug)]
+pub struct HttpResponse {
+    pub status: Status,
+    pub response_headers: ResponseHeaders,
+    pub general_headers: GeneralHeaders,
+    pub entity_headers: EntityHeaders,
+    pub body: Body,
+    // Set on responses the error handlers of routers and the server may
+    // render, cleared once one of them has
+    pub error: Option<HttpError>,
+    // Only sent when the body goes out chunked
+    pub trailers: Option<Trailers>,
+}
+
+impl HttpResponse {
+    pub fn new(status: Status) -> Self {
+        Self {
+            status,
+            response_headers: ResponseHeaders::new(),
+            general_headers: GeneralHeaders::new(),
+            entity_headers: EntityHeaders::new(),
+            body: Body::Empty,
+            error: None,
+            trailers: None,
+        }
+    }
+
+    pub fn text<B: Into<Body>>(status: Status, body: B) -> Self {
+        Self::new(status)
+            .header("Content-Type", "text/plain; charset=utf-8")
+            .body(body)
+    }
+
+    pub fn html<B: Into<Body>>(status: Status, body: B) -> Self {
+        Self::new(status)
+            .header("Content-Type", "text/html; charset=utf-8")
+            .body(body)
+    }
+
+    // Plain text response with the reason phrase as body that error
+    // handlers may replace, used for the responses the server and router
+    // generate on their own
+    pub fn error(status: Status) -> Self {
+        Self::from_error(HttpError::new(status, status.reason()))
+    }
+
+    pub fn from_error(error: HttpError) -> Self {
+        let mut response = Self::text(error.status, format!("{}\n", error.status));
+        response.error = Some(error);
+        response
+    }
+
+    // Invalid headers are dropped: names that aren't tokens, values with CR,
+    // LF or NUL and unparsable Content-Length values. Use insert_header to
+    // see the error, e.g. for values taken from the request.
+    pub fn header(mut self, key: &str, value: &str) -> Self {
+        if let Err(msg) = self.insert_header(key, value) {
+            eprintln!("Dropped response header {}. Reason: {}", key, msg);
+        }
+        self
+    }
+
+    pub fn body<B: Into<Body>>(mut self, body: B) -> Self {
+        self.body = body.into();
+        self
+    }
+
+    pub fn trailers<F>(mut self, trailers: F) -> Self
+    where
+        F: FnOnce() -> Vec<(String, String)> + Send + 'static,
+    {
+        self.trailers = Some(Trailers(Box::new(trailers)));
+        self
+    }
+
+    pub fn get_header(&self, key: &str) -> Option<String> {
+        if let Some(rheader) = ResponseHeader::from(key) {
+            self.response_headers.get(rheader)
+        } else if let Some(gheader) = GeneralHeader::from(key) {
+            self.general_headers.get(gheader)
+        } else {
+            self.entity_headers.get(EntityHeader::from(key)?)
+        }
+    }
+
+    pub fn insert_header(&mut self, key: &str, value: &str) -> Result<(), String> {
+        if let Some(rheader) = ResponseHeader::from(key) {
+            self.response_headers.insert(rheader, value)
+        } else if let Some(gheader) = GeneralHeader::from(key) {
+            self.general_headers.insert(gheader, value)
+        } else if let Some(eheader) = EntityHeader::from(key) {
+            self.entity_headers.insert(eheader, value)
+        } else {
+            Err(format!("Unknown header: {}", key))
+        }
+    }
+}
+
+// How the request being answered shapes the response on the wire
+#[derive(Clone, Copy, Debug)]
+pub struct WriteOptions {
+    // HEAD requests get the headers a GET would have but no body
+    pub head: bool,
+    pub keep_alive: bool,
+    // Version of the request, chunked bodies need HTTP/1.1
+    pub version: (u32, u32),
+}
+
+impl WriteOptions {
+    pub fn new() -> Self {
+        Self {
+            head: false,
+            keep_alive: false,
+            version: (1, 1),
+        }
+    }
+
+    pub fn for_request(request: &HttpRequest) -> Self {
+        Self {
+            head: request.request_line.method == Method::HEAD,
+            keep_alive: request.keep_alive(),
+            version: (request.request_line.v_major, request.request_line.v_minor),
+        }
+    }
+}
+
+impl Default for WriteOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Writes `response` to the connection, framing the body with Content-Length
+// when its length is known and chunked otherwise. Returns whether the
+// connection can carry another request.
+pub fn write_response<W: Write>(
+    writer: &mut W,
+    response: &mut HttpResponse,
+    options: WriteOptions,
+) -> io::Result<bool> {
+    let send_body = response.status.allows_body();
+    let length = response.body.length();
+    let chunked = send_body && length.is_none() && options.version >= (1, 1);
+    let mut keep_alive = options.keep_alive;
+
+    if send_body {
+        match length {
+            Some(length) => response
+                .entity_headers
+                .insert(EntityHeader::ContentLength, &length.to_string())
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+            None if chunked => {
+                let _ = response
+                    .general_headers
+                    .insert(GeneralHeader::TransferEncoding, "chunked");
+            }
+            // HTTP/1.0 clients learn where the body ends from the connection closing
+            None if !options.head => keep_alive = false,
+            None => {}
+        }
+    }
+
+    if !keep_alive {
+        let _ = response
+            .general_headers
+            .insert(GeneralHeader::Connection, "close");
+    } else if options.version < (1, 1) {
+        let _ = response
+            .general_headers
+            .insert(GeneralHeader::Connection, "keep-alive");
+    }
+
+    // Required on every final response from a server with a clock, RFC 9110 6.6.1
+    if response.general_headers.get(GeneralHeader::Date).is_none() {
+        let _ = response
+            .general_headers
+            .insert(GeneralHeader::Date, &date_header());
+    }
+
+    write!(writer, "HTTP/1.1 {}\r\n", response.status)?;
+    write!(writer, "{}", response.general_headers)?;
+    write!(writer, "{}", response.response_headers)?;
+    write!(writer, "{}", response.entity_headers)?;
+    write!(writer, "\r\n")?;
+
+    if !send_body || options.head {
+        writer.flush()?;
+        return Ok(keep_alive);
+    }
+
+    let body = match std::mem::take(&mut response.body) {
+        Body::Seekable { reader, length } => Body::Reader {
+            reader: Box::new(reader),
+            length: Some(length),
+        },
+        body => body,
+    };
+
+    match body {
+        Body::Empty => {}
+        Body::Bytes(bytes) => writer.write_all(&bytes)?,
+        // Turned into a Reader above
+        Body::Seekable { .. } => {}
+        Body::Reader { mut reader, length } => {
+            if chunked {
+                let mut buf = [0; 8192];
+                loop {
+                    let read = reader.read(&mut buf)?;
+                    if read == 0 {
+                        break;
+                    }
+                    write_chunk(writer, &buf[..read])?;
+                }
+            } else if let Some(length) = length {
+                let copied = io::copy(&mut reader.take(length), writer)?;
+                // The client was promised `length` bytes, the connection is unusable
+                if copied != length {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        format!("Body ended after {} of {} bytes", copied, length),
+                    ));
+                }
+            } else {
+                io::copy(&mut reader, writer)?;
+            }
+        }
+        Body::Chunks(chunks) => {
+            for chunk in chunks {
+                if chunked {
+                    write_chunk(writer, &chunk)?;
+                } else {
+                    writer.write_all(&chunk)?;
+                }
+                writer.flush()?;
+            }
+        }
+    }
+
+    if chunked {
+        write!(writer, "0\r\n")?;
+        if let Some(Trailers(trailers)) = response.trailers.take() {
+            for (name, value) in trailers() {
+                if let Err(msg) = check_trailer(&name, &value) {
+                    eprintln!("Dropped trailer {}. Reason: {}", name, msg);
+                    continue;
+                }
+                write!(writer, "{}: {}\r\n", name, value)?;
+            }
+        }
+        write!(writer, "\r\n")?;
+    }
+
+    writer.flush()?;
+    Ok(keep_alive)
+}
+
+// Fields that frame or route the message, control caching or its
+// processing and authentication, which recipients may only act on before
+// the body, see RFC 9110 6.5.1
+const FORBIDDEN_TRAILERS: &[&str] = &[
+    "Age",
+    "Authorization",
+    "Cache-Control",
+    "Connection",
+    "Content-Encoding",
+    "Content-Length",
+    "Content-Range",
+    "Content-Type",
+    "Date",
+    "Expect",
+    "Expires",
+    "Host",
+    "If-Match",
+    "If-Modified-Since",
+    "If-None-Match",
+    "If-Range",
+    "If-Unmodified-Since",
+    "Keep-Alive",
+    "Location",
+    "Max-Forwards",
+    "Pragma",
+    "Proxy-Authenticate",
+    "Proxy-Authorization",
+    "Range",
+    "Retry-After",
+    "Set-Cookie",
+    "TE",
+    "Trailer",
+    "Transfer-Encoding",
+    "Upgrade",
+    "Vary",
+    "WWW-Authenticate",
+];
+
+fn check_trailer(name: &str, value: &str) -> Result<(), String> {
+    check_header_name(name)?;
+    check_header_value(value)?;
+
+    if FORBIDDEN_TRAILERS
+        .iter()
+        .any(|it| it.eq_ignore_ascii_case(name))
+    {
+        return Err(format!("{} is not allowed in trailers", name));
+    }
+    Ok(())
+}
+
+fn write_chunk<W: Write>(writer: &mut W, chunk: &[u8]) -> io::Result<()> {
+    // An empty chunk would end the body early
+    if chunk.is_empty() {
+        return Ok(());
+    }
+
+    write!(writer, "{:x}\r\n", chunk.len())?;
+    writer.write_all(chunk)?;
+    write!(writer, "\r\n")
+}
+
+// Sends interim 1xx responses ahead of the final response. The server puts
+// one in the extensions of every request, see FromRequest for Interim.
+#[derive(Clone)]
+pub struct Interim {
+    writer: Arc<Mutex<Box<dyn Write + Send>>>,
+    // HTTP/1.0 clients don't understand 1xx responses, sends are dropped
+    enabled: bool,
+    // Cleared once the handler returned and the final response is on its way
+    open: Arc<AtomicBool>,
+}
+
+impl Interim {
+    pub fn new<W: Write + Send + 'static>(writer: W) -> Self {
+        Self {
+            writer: Arc::new(Mutex::new(Box::new(writer))),
+            enabled: true,
+            open: Arc::new(AtomicBool::new(true)),
+        }
+    }
+
+    // A handle for the next request on the same connection
+    pub fn for_request(&self, request: &HttpRequest) -> Self {
+        Self {
+            writer: Arc::clone(&self.writer),
+            enabled: (request.request_line.v_major, request.request_line.v_minor) >= (1, 1),
+            open: Arc::new(AtomicBool::new(true)),
+        }
+    }
+
+    // Called before the final response is written. Clearing `open` under
+    // the writer lock waits for a send in progress on another thread, and
+    // sends after it fail, so no 1xx ends up inside the final response.
+    pub fn close(&self) {
+        let _writer = self.writer.lock().unwrap_or_else(|e| e.into_inner());
+        self.open.store(false, Ordering::Release);
+    }
+
+    // Writes the status line and headers of `response`, which must be a 1xx
+    // other than 100 Continue (the request body sends that) and 101
+    pub fn send(&self, response: &HttpResponse) -> io::Result<()> {
+        let status = response.status;
+        if !status.is_informational()
+            || matches!(status, Status::Continue | Status::SwitchingProtocols)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} can't be sent as an interim response", status),
+            ));
+        }
+
+        // Checked under the lock close takes, see there
+        let mut writer = self.writer.lock().unwrap_or_else(|e| e.into_inner());
+        if !self.open.load(Ordering::Acquire) {
+            return Err(io::Error::other(
+                "Interim responses must be sent before the final response",
+            ));
+        }
+
+        if !self.enabled {
+            return Ok(());
+        }
+
+        write!(writer, "HTTP/1.1 {}\r\n", status)?;
+        write!(writer, "{}", response.general_headers)?;
+        write!(writer, "{}", response.response_headers)?;
+        write!(writer, "{}", response.entity_headers)?;
+        write!(writer, "\r\n")?;
+        writer.flush()
+    }
+
+    // 103 Early Hints with a Link header per entry, e.g.
+    // "</style.css>; rel=preload; as=style", so browsers start fetching
+    // them while the final response is being computed
+    pub fn early_hints(&self, links: &[&str]) -> io::Result<()> {
+        self.send(&HttpResponse::new(Status::EarlyHints).header("Link", &links.join(", ")))
+    }
+}
+
+impl fmt::Debug for Interim {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Interim")
+            .field("enabled", &self.enabled)
+            .field("open", &self.open.load(Ordering::Relaxed))
+            .finish_non_exhaustive()
+    }
+}
+
+// Anything a handler may return in place of a full HttpResponse
+pub trait IntoResponse {
+    fn into_response(self) -> HttpResponse;
+}
+
+impl IntoResponse for HttpResponse {
+    fn into_response(self) -> HttpResponse {
+        self
+    }
+}
+
+impl IntoResponse for Status {
+    fn into_response(self) -> HttpResponse {
+        if self.is_error() {
+            HttpResponse::error(self)
+        } else {
+            HttpResponse::new(self)
+        }
+    }
+}
+
+impl IntoResponse for HttpError {
+    fn into_response(self) -> HttpResponse {
+        HttpResponse::from_error(self)
+    }
+}
+
+impl IntoResponse for String {
+    fn into_response(self) -> HttpResponse {
+        HttpResponse::text(Status::Ok, self)
+    }
+}
+
+impl IntoResponse for &'static str {
+    fn into_response(self) -> HttpResponse {
+        HttpResponse::text(Status::Ok, self)
+    }
+}
+
+impl IntoResponse for Vec<u8> {
+    fn into_response(self) -> HttpResponse {
+        HttpResponse::new(Status::Ok)
+            .header("Content-Type", "application/octet-stream")
+            .body(self)
+    }
+}
+
+impl<T: IntoResponse> IntoResponse for (Status, T) {
+    fn into_response(self) -> HttpResponse {
+        let mut response = self.1.into_response();
+        response.status = self.0;
+        response
+    }
+}
+
+impl<T: IntoResponse, E: IntoResponse> IntoResponse for Result<T, E> {
+    fn into_response(self) -> HttpResponse {
+        match self {
+            Ok(it) => it.into_response(),
+            Err(e) => e.into_response(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_chunked(response: HttpResponse) -> String {
+        let mut response = response.body(Body::chunks(vec![b"abc".to_vec()]));
+        let mut output = Vec::new();
+        write_response(&mut output, &mut response, WriteOptions::new()).unwrap();
+
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn sends_trailers_after_the_last_chunk() {
+        let response = HttpResponse::new(Status::Ok)
+            .trailers(|| vec![("X-Checksum".to_string(), "1234".to_string())]);
+        let output = write_chunked(response);
+
+        assert!(output.ends_with("\r\n3\r\nabc\r\n0\r\nX-Checksum: 1234\r\n\r\n"));
+    }
+
+    #[test]
+    fn drops_invalid_and_forbidden_trailers() {
+        let response = HttpResponse::new(Status::Ok).trailers(|| {
+            vec![
+                ("Content-Length".to_string(), "5".to_string()),
+                ("transfer-encoding".to_string(), "chunked".to_string()),
+                ("Host".to_string(), "example.com".to_string()),
+                ("Set-Cookie".to_string(), "a=b".to_string()),
+                (
+                    "X-Split".to_string(),
+                    "a\r\n\r\nHTTP/1.1 200 OK".to_string(),
+                ),
+                ("X Bad".to_string(), "a".to_string()),
+                ("".to_string(), "a".to_string()),
+                ("X-Lines".to_string(), "5".to_string()),
+            ]
+        });
+        let output = write_chunked(response);
+
+        assert!(
+            output.ends_with("\r\n0\r\nX-Lines: 5\r\n\r\n"),
+            "{}",
+            output
+        );
+    }
+
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn refuses_interim_responses_once_closed() {
+        let output = Shared::default();
+        let interim = Interim::new(output.clone());
+
+        interim.early_hints(&["</a.css>; rel=preload"]).unwrap();
+        let sent = output.0.lock().unwrap().len();
+        assert!(sent > 0);
+
+        interim.close();
+        assert!(interim.early_hints(&["</b.css>; rel=preload"]).is_err());
+        assert_eq!(output.0.lock().unwrap().len(), sent);
+    }
+
+    #[test]
+    fn close_waits_for_a_send_in_progress() {
+        let output = Shared::default();
+        let interim = Interim::new(output.clone());
+
+        let sender = {
+            let interim = interim.clone();
+            std::thread::spawn(move || {
+                for _ in 0..1000 {
+                    if interim.early_hints(&["</a.css>; rel=preload"]).is_err() {
+                        break;
+                    }
+                }
+            })
+        };
+        interim.close();
+        let sent = output.0.lock().unwrap().clone();
+        sender.join().unwrap();
+
+        // Nothing was written after close returned, and only whole responses
+        assert_eq!(*output.0.lock().unwrap(), sent);
+        assert!(sent.is_empty() || sent.ends_with(b"\r\n\r\n"));
+    }
+}
@@ -0,0 +1,575 @@
+use crate::error::{ErrorHandlers, HttpError};
+use crate::extensions::StateMap;
+use crate::handler::{Handler, IntoHandler};
+use crate::middleware::{self, Middleware};
+use crate::models::{EntityHeader, HttpRequest, Method};
+use crate::response::{HttpResponse, Status};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+#[derive(Debug)]
+enum Segment {
+    Static(String),
+    // `:name` matches exactly one segment
+    Param(String),
+    // `*name` matches the rest of the path, possibly empty
+    Rest(String),
+}
+
+struct Route {
+    method: Method,
+    segments: Vec<Segment>,
+    handler: Box<dyn Handler>,
+}
+
+impl Route {
+    fn matches(&self, path: &[&str]) -> Option<HashMap<String, String>> {
+        let (params, consumed) = match_prefix(&self.segments, path)?;
+
+        if consumed != path.len() {
+            return None;
+        }
+
+        Some(params)
+    }
+}
+
+// A router mounted under a path prefix with Router::nest
+struct Mount {
+    prefix: Vec<Segment>,
+    router: Router,
+}
+
+// The original request target, kept in the request extensions when a nested
+// router strips its prefix
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OriginalUri(pub String);
+
+// Name of the parameter matched last, from the route or, if it has none,
+// the prefix of the mount it is in. Path reads this one.
+#[derive(Clone, Debug)]
+pub(crate) struct LastParam(pub(crate) String);
+
+fn add_params(request: &mut HttpRequest, segments: &[Segment], params: HashMap<String, String>) {
+    let last = segments.iter().rev().find_map(|segment| match segment {
+        Segment::Static(_) => None,
+        Segment::Param(name) | Segment::Rest(name) => Some(name),
+    });
+    if let Some(name) = last {
+        request.extensions.insert(LastParam(name.clone()));
+    }
+    request.params.extend(params);
+}
+
+// Matches `segments` against the start of `path`, returning the captured
+// parameters and how many path segments were used
+fn match_prefix(segments: &[Segment], path: &[&str]) -> Option<(HashMap<String, String>, usize)> {
+    let mut params = HashMap::new();
+
+    for (i, segment) in segments.iter().enumerate() {
+        match segment {
+            Segment::Static(s) => {
+                if path.get(i) != Some(&s.as_str()) {
+                    return None;
+                }
+            }
+            Segment::Param(name) => {
+                params.insert(name.clone(), path.get(i)?.to_string());
+            }
+            Segment::Rest(name) => {
+                let rest = path.get(i..).unwrap_or_default().join("/");
+                params.insert(name.clone(), rest);
+                return Some((params, path.len()));
+            }
+        }
+    }
+
+    Some((params, segments.len()))
+}
+
+pub struct Router {
+    routes: Vec<Route>,
+    mounts: Vec<Mount>,
+    middleware: Vec<Arc<dyn Middleware>>,
+    state: StateMap,
+    errors: ErrorHandlers,
+}
+
+impl Router {
+    pub fn new() -> Self {
+        Self {
+            routes: Vec::new(),
+            mounts: Vec::new(),
+            middleware: Vec::new(),
+            state: StateMap::new(),
+            errors: ErrorHandlers::new(),
+        }
+    }
+
+    pub fn route<H: IntoHandler<Args>, Args>(
+        mut self,
+        method: Method,
+        path: &str,
+        handler: H,
+    ) -> Self {
+        self.routes.push(Route {
+            method,
+            segments: parse_route(path),
+            handler: handler.into_handler(),
+        });
+        self
+    }
+
+    pub fn get<H: IntoHandler<Args>, Args>(self, path: &str, handler: H) -> Self {
+        self.route(Method::GET, path, handler)
+    }
+
+    pub fn post<H: IntoHandler<Args>, Args>(self, path: &str, handler: H) -> Self {
+        self.route(Method::POST, path, handler)
+    }
+
+    pub fn put<H: IntoHandler<Args>, Args>(self, path: &str, handler: H) -> Self {
+        self.route(Method::PUT, path, handler)
+    }
+
+    pub fn delete<H: IntoHandler<Args>, Args>(self, path: &str, handler: H) -> Self {
+        self.route(Method::DELETE, path, handler)
+    }
+
+    // Serves `router` under `prefix`. Its handlers see the request target
+    // with the prefix removed, the full one is in the OriginalUri extension.
+    // The prefix may contain `:name` segments but no `*rest`.
+    pub fn nest(mut self, prefix: &str, router: Router) -> Self {
+        self.mounts.push(Mount {
+            prefix: parse_route(prefix),
+            router,
+        });
+        self
+    }
+
+    // Middleware wraps every route of this router, in the order added
+    pub fn wrap<M: Middleware>(mut self, middleware: M) -> Self {
+        self.middleware.push(Arc::new(middleware));
+        self
+    }
+
+    // Makes `value` available to this router's middleware and handlers
+    // through the State extractor or HttpRequest::state
+    pub fn state<T: Send + Sync + 'static>(mut self, value: T) -> Self {
+        self.state.insert(value);
+        self
+    }
+
+    // Renders error responses with `status` coming out of this router's
+    // routes, e.g. 404 for unknown paths or 400 for failed extractors
+    pub fn error_handler<F>(mut self, status: Status, handler: F) -> Self
+    where
+        F: Fn(&HttpError, &mut HttpResponse) + Send + Sync + 'static,
+    {
+        self.errors.insert(status, handler);
+        self
+    }
+
+    // Renders error responses no status specific handler covers
+    pub fn fallback_error_handler<F>(mut self, handler: F) -> Self
+    where
+        F: Fn(&HttpError, &mut HttpResponse) + Send + Sync + 'static,
+    {
+        self.errors.set_fallback(handler);
+        self
+    }
+
+    fn dispatch(&self, request: &mut HttpRequest) -> HttpResponse {
+        // `OPTIONS *` asks about the server as a whole
+        if request.request_line.method == Method::OPTIONS && request.request_line.uri == "*" {
+            return options_response(&self.all_methods());
+        }
+
+        // Owned, mounts rewrite the request target
+        let target = request.request_line.path().to_string();
+        let path = split_path(&target);
+        let method = request.request_line.method.clone();
+
+        if let Some((route, params)) = self.find_route(&method, &path) {
+            add_params(request, &route.segments, params);
+            return route.handler.call(request);
+        }
+
+        // HEAD is GET without the body, the writer leaves the body out
+        if method == Method::HEAD {
+            if let Some((route, params)) = self.find_route(&Method::GET, &path) {
+                add_params(request, &route.segments, params);
+                return route.handler.call(request);
+            }
+        }
+
+        // A mounted router with a route for the method goes before answering
+        // 405 for the routes of this one
+        let mounts: Vec<_> = self
+            .mounts
+            .iter()
+            .filter_map(|mount| {
+                let (params, consumed) = match_prefix(&mount.prefix, &path)?;
+                Some((mount, params, consumed))
+            })
+            .collect();
+
+        if let Some((mount, params, consumed)) = mounts
+            .iter()
+            .find(|(mount, _, consumed)| mount.router.serves(&method, &path[*consumed..]))
+        {
+            return call_mount(mount, request, params, &path[*consumed..]);
+        }
+
+        let allowed = self.allowed_methods(&path);
+        if !allowed.is_empty() {
+            if method == Method::OPTIONS {
+                return options_response(&allowed);
+            }
+
+            let mut response = HttpResponse::error(Status::MethodNotAllowed);
+            let _ = response
+                .entity_headers
+                .insert(EntityHeader::Allow, &allow_header(&allowed));
+            return response;
+        }
+
+        // Nothing matches, the mount's error handlers render the 404
+        if let Some((mount, params, consumed)) = mounts.first() {
+            return call_mount(mount, request, params, &path[*consumed..]);
+        }
+
+        HttpResponse::error(Status::NotFound)
+    }
+}
+
+impl Router {
+    fn find_route(
+        &self,
+        method: &Method,
+        path: &[&str],
+    ) -> Option<(&Route, HashMap<String, String>)> {
+        self.routes
+            .iter()
+            .filter(|route| &route.method == method)
+            .find_map(|route| Some((route, route.matches(path)?)))
+    }
+
+    // Whether a route here or in a mounted router takes `method` for `path`
+    fn serves(&self, method: &Method, path: &[&str]) -> bool {
+        if self.find_route(method, path).is_some()
+            || *method == Method::HEAD && self.find_route(&Method::GET, path).is_some()
+        {
+            return true;
+        }
+
+        self.mounts.iter().any(|mount| {
+            match_prefix(&mount.prefix, path)
+                .is_some_and(|(_, consumed)| mount.router.serves(method, &path[consumed..]))
+        })
+    }
+
+    // Methods of every route, including mounted ones
+    fn all_methods(&self) -> Vec<Method> {
+        let mut methods: Vec<Method> = self
+            .routes
+            .iter()
+            .map(|route| route.method.clone())
+            .collect();
+
+        for mount in &self.mounts {
+            methods.extend(mount.router.all_methods());
+        }
+
+        methods
+    }
+
+    // Methods of the routes matching `path`, including mounted ones
+    fn allowed_methods(&self, path: &[&str]) -> Vec<Method> {
+        let mut methods: Vec<Method> = self
+            .routes
+            .iter()
+            .filter(|route| route.matches(path).is_some())
+            .map(|route| route.method.clone())
+            .collect();
+
+        for mount in &self.mounts {
+            if let Some((_, consumed)) = match_prefix(&mount.prefix, path) {
+                methods.extend(mount.router.allowed_methods(&path[consumed..]));
+            }
+        }
+
+        methods
+    }
+}
+
+impl Default for Router {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Handler for Router {
+    fn call(&self, request: &mut HttpRequest) -> HttpResponse {
+        request.state.extend(&self.state);
+        middleware::run(&self.middleware, request, |request| {
+            let mut response = self.dispatch(request);
+            self.errors.apply(Some(request), &mut response);
+            response
+        })
+    }
+}
+
+// Registered methods plus the ones the router answers on its own
+fn allow_header(methods: &[Method]) -> String {
+    let mut allowed: Vec<String> = Vec::new();
+    let mut add = |method: &Method| {
+        let method = method.to_string();
+        if !allowed.contains(&method) {
+            allowed.push(method);
+        }
+    };
+
+    for method in methods {
+        add(method);
+        if *method == Method::GET {
+            add(&Method::HEAD);
+        }
+    }
+    add(&Method::OPTIONS);
+
+    allowed.join(", ")
+}
+
+fn options_response(methods: &[Method]) -> HttpResponse {
+    let mut response = HttpResponse::new(Status::NoContent);
+    let _ = response
+        .entity_headers
+        .insert(EntityHeader::Allow, &allow_header(methods));
+    response
+}
+
+fn call_mount(
+    mount: &Mount,
+    request: &mut HttpRequest,
+    params: &HashMap<String, String>,
+    path: &[&str],
+) -> HttpResponse {
+    let mut rest = path.join("/");
+    if !rest.is_empty() && request.request_line.path().ends_with('/') {
+        rest.push('/');
+    }
+    add_params(request, &mount.prefix, params.clone());
+    call_nested(&mount.router, request, &rest)
+}
+
+fn call_nested(router: &Router, request: &mut HttpRequest, rest: &str) -> HttpResponse {
+    let original = request.request_line.uri.clone();
+
+    let mut uri = format!("/{}", rest);
+    if let Some(query) = request.request_line.query() {
+        uri = format!("{}?{}", uri, query);
+    }
+
+    if !request.extensions.contains::<OriginalUri>() {
+        request.extensions.insert(OriginalUri(original.clone()));
+    }
+
+    request.request_line.uri = uri;
+    let response = router.call(request);
+    // Layers outside the mount see the target they were given
+    request.request_line.uri = original;
+
+    response
+}
+
+fn split_path(path: &str) -> Vec<&str> {
+    path.split('/').filter(|it| !it.is_empty()).collect()
+}
+
+fn parse_route(path: &str) -> Vec<Segment> {
+    split_path(path)
+        .into_iter()
+        .map(|segment| {
+            if let Some(name) = segment.strip_prefix(':') {
+                Segment::Param(name.to_string())
+            } else if let Some(name) = segment.strip_prefix('*') {
+                Segment::Rest(name.to_string())
+            } else {
+                Segment::Static(segment.to_string())
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::extract::{Params, Path, Rejection, State};
+    use crate::models::test_request;
+    use crate::response::Body;
+
+    fn ok(_: &mut HttpRequest) -> HttpResponse {
+        HttpResponse::new(Status::Ok)
+    }
+
+    fn created(_: &mut HttpRequest) -> HttpResponse {
+        HttpResponse::new(Status::Created)
+    }
+
+    fn call(router: &Router, method: &str, target: &str) -> HttpResponse {
+        router.call(&mut test_request(method, target, &[]))
+    }
+
+    fn echo_id(Path(id): Path<u32>) -> HttpResponse {
+        HttpResponse::text(Status::Ok, id.to_string())
+    }
+
+    fn body(response: &HttpResponse) -> &[u8] {
+        match &response.body {
+            Body::Bytes(bytes) => bytes,
+            _ => panic!("Expected a body of bytes"),
+        }
+    }
+
+    fn api() -> Router {
+        Router::new()
+            .get("/api/:id", ok)
+            .nest("/api", Router::new().post("/users", created))
+    }
+
+    #[test]
+    fn tries_mounts_before_answering_405() {
+        let response = call(&api(), "POST", "/api/users");
+        assert_eq!(response.status, Status::Created);
+
+        let response = call(&api(), "GET", "/api/users");
+        assert_eq!(response.status, Status::Ok);
+    }
+
+    #[test]
+    fn merges_mounted_methods_into_allow() {
+        let response = call(&api(), "DELETE", "/api/users");
+        assert_eq!(response.status, Status::MethodNotAllowed);
+        assert_eq!(
+            response.get_header("Allow").as_deref(),
+            Some("GET, HEAD, POST, OPTIONS")
+        );
+
+        let response = call(&api(), "OPTIONS", "/api/users");
+        assert_eq!(response.status, Status::NoContent);
+        assert_eq!(
+            response.get_header("Allow").as_deref(),
+            Some("GET, HEAD, POST, OPTIONS")
+        );
+    }
+
+    #[test]
+    fn answers_405_for_mounted_routes() {
+        let router = Router::new().nest("/api", Router::new().post("/users", created));
+
+        let response = call(&router, "GET", "/api/users");
+        assert_eq!(response.status, Status::MethodNotAllowed);
+        assert_eq!(
+            response.get_header("Allow").as_deref(),
+            Some("POST, OPTIONS")
+        );
+
+        let response = call(&router, "POST", "/api/other");
+        assert_eq!(response.status, Status::NotFound);
+    }
+
+    #[test]
+    fn path_reads_the_last_parameter() {
+        let router = Router::new()
+            .nest("/teams/:team", Router::new().get("/members/:id", echo_id))
+            .nest("/users/:id", Router::new().get("/", echo_id))
+            .get("/plain", echo_id);
+
+        let response = call(&router, "GET", "/teams/7/members/42");
+        assert_eq!(body(&response), b"42");
+
+        let response = call(&router, "GET", "/users/5");
+        assert_eq!(body(&response), b"5");
+
+        let response = call(&router, "GET", "/teams/7/members/x");
+        assert_eq!(response.status, Status::BadRequest);
+
+        let response = call(&router, "GET", "/plain");
+        assert_eq!(response.status, Status::InternalServerError);
+    }
+
+    #[test]
+    fn params_read_the_others_by_name() {
+        fn member(params: Params) -> Result<HttpResponse, Rejection> {
+            let team = params.parse::<u32>("team")?;
+            let id = params.parse::<u32>("id")?;
+            Ok(HttpResponse::text(Status::Ok, format!("{}/{}", team, id)))
+        }
+        let router = Router::new().get("/teams/:team/members/:id", member);
+
+        let response = call(&router, "GET", "/teams/7/members/42");
+        assert_eq!(body(&response), b"7/42");
+
+        let response = call(&router, "GET", "/teams/x/members/42");
+        assert_eq!(response.status, Status::BadRequest);
+    }
+
+    #[test]
+    fn state_reaches_nested_handlers() {
+        fn greet(State(greeting): State<String>, State(count): State<u32>) -> String {
+            format!("{} {}", greeting, count)
+        }
+        let router = Router::new()
+            .state("hello".to_string())
+            .state(1u32)
+            .get("/outer", greet)
+            .nest("/inner", Router::new().state(2u32).get("/", greet))
+            .get("/missing", |State(_): State<u64>| "unreachable");
+
+        let response = call(&router, "GET", "/outer");
+        assert_eq!(body(&response), b"hello 1");
+
+        let response = call(&router, "GET", "/inner");
+        assert_eq!(body(&response), b"hello 2");
+
+        let response = call(&router, "GET", "/missing");
+        assert_eq!(response.status, Status::InternalServerError);
+    }
+
+    #[test]
+    fn options_star_includes_mounted_methods() {
+        let router = api().nest("/admin", Router::new().delete("/users/:id", ok));
+
+        let response = call(&router, "OPTIONS", "*");
+        assert_eq!(response.status, Status::NoContent);
+        assert_eq!(
+            response.get_header("Allow").as_deref(),
+            Some("GET, HEAD, POST, DELETE, OPTIONS")
+        );
+    }
+
+    #[test]
+    fn answers_head_and_options_for_plain_routes() {
+        let router = Router::new()
+            .get("/items/:id", echo_id)
+            .delete("/items/:id", ok);
+
+        let response = call(&router, "HEAD", "/items/3");
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(body(&response), b"3");
+
+        let response = call(&router, "OPTIONS", "/items/3");
+        assert_eq!(response.status, Status::NoContent);
+        assert_eq!(
+            response.get_header("Allow").as_deref(),
+            Some("GET, HEAD, DELETE, OPTIONS")
+        );
+
+        let response = call(&router, "PUT", "/items/3");
+        assert_eq!(response.status, Status::MethodNotAllowed);
+
+        let response = call(&router, "OPTIONS", "/other");
+        assert_eq!(response.status, Status::NotFound);
+    }
+}
@@ -0,0 +1,749 @@
+#[cfg(unix)]
+use crate::activation;
+use crate::body::{BodyReader, Framing, Lent, RequestBody, SharedBody};
+use crate::error::{ErrorHandlers, HttpError};
+use crate::extensions::StateMap;
+use crate::handler::Handler;
+use crate::listener::{ListenAddr, Listener, Stream, UnixOptions};
+use crate::models::{parse_request_head, GeneralHeader, HttpRequest, RequestHeader};
+use crate::response::{write_response, HttpResponse, Interim, Status, WriteOptions};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::any::Any;
+use std::cell::{Cell, RefCell};
+use std::io::{self, BufRead, BufReader, BufWriter};
+use std::net::{SocketAddr, TcpListener};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::PathBuf;
+#[cfg(unix)]
+use std::process::{Child, Command};
+use std::sync::{Arc, Mutex, Once};
+use std::thread;
+use std::time::Duration;
+
+#[derive(Clone, Debug)]
+pub struct ServerConfig {
+    pub listen: Vec<ListenAddr>,
+    // Accept IPv4 connections on IPv6 wildcard sockets ([::]) too. Off by
+    // default, so 0.0.0.0:P and [::]:P can both be listened on.
+    pub dual_stack: bool,
+    // Bind one SO_REUSEPORT socket per acceptor instead of sharing one socket
+    pub reuse_port: bool,
+    pub acceptors: usize,
+    pub backlog: i32,
+    pub unix: UnixOptions,
+    // Adopt sockets passed through LISTEN_FDS instead of binding `listen`
+    pub socket_activation: bool,
+    // How long an idle persistent connection may hold an acceptor
+    pub keep_alive_timeout: Option<Duration>,
+}
+
+impl ServerConfig {
+    pub fn new() -> Self {
+        Self {
+            listen: Vec::new(),
+            dual_stack: false,
+            reuse_port: false,
+            acceptors: 1,
+            backlog: 128,
+            unix: UnixOptions::new(),
+            socket_activation: true,
+            keep_alive_timeout: Some(Duration::from_secs(5)),
+        }
+    }
+
+    pub fn listen<A: Into<ListenAddr>>(mut self, addr: A) -> Self {
+        self.listen.push(addr.into());
+        self
+    }
+
+    pub fn listen_unix<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.listen.push(ListenAddr::Unix(path.into()));
+        self
+    }
+
+    pub fn unix_mode(mut self, mode: u32) -> Self {
+        self.unix.mode = Some(mode);
+        self
+    }
+
+    pub fn remove_stale_socket(mut self, remove_stale: bool) -> Self {
+        self.unix.remove_stale = remove_stale;
+        self
+    }
+
+    pub fn dual_stack(mut self, dual_stack: bool) -> Self {
+        self.dual_stack = dual_stack;
+        self
+    }
+
+    pub fn reuse_port(mut self, reuse_port: bool) -> Self {
+        self.reuse_port = reuse_port;
+        self
+    }
+
+    pub fn acceptors(mut self, acceptors: usize) -> Self {
+        self.acceptors = acceptors.max(1);
+        self
+    }
+
+    pub fn backlog(mut self, backlog: i32) -> Self {
+        self.backlog = backlog;
+        self
+    }
+
+    pub fn socket_activation(mut self, socket_activation: bool) -> Self {
+        self.socket_activation = socket_activation;
+        self
+    }
+
+    pub fn keep_alive_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.keep_alive_timeout = timeout;
+        self
+    }
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct Server {
+    // One entry per configured address, each holding the sockets its acceptors use
+    listeners: Vec<Vec<Listener>>,
+    acceptors: usize,
+    keep_alive_timeout: Option<Duration>,
+    state: StateMap,
+    errors: ErrorHandlers,
+}
+
+// Everything a connection needs besides the stream itself
+struct App<H> {
+    handler: H,
+    state: StateMap,
+    errors: ErrorHandlers,
+    keep_alive_timeout: Option<Duration>,
+}
+
+impl Server {
+    pub fn bind(config: &ServerConfig) -> io::Result<Self> {
+        #[cfg(unix)]
+        if config.socket_activation {
+            let inherited = activation::listen_fds()?;
+            if !inherited.is_empty() {
+                let mut server = Self::from_listeners(inherited, config.acceptors);
+                server.keep_alive_timeout = config.keep_alive_timeout;
+                return Ok(server);
+            }
+        }
+
+        if config.listen.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "No listen addresses configured",
+            ));
+        }
+
+        let mut listeners = Vec::new();
+
+        for addr in &config.listen {
+            let group = match addr {
+                ListenAddr::Tcp(addr) => bind_tcp_group(*addr, config)?,
+                #[cfg(unix)]
+                ListenAddr::Unix(path) => vec![Listener::bind_unix(path, &config.unix)?],
+                #[cfg(not(unix))]
+                ListenAddr::Unix(_) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Unsupported,
+                        "Unix domain sockets are not supported on this platform",
+                    ))
+                }
+            };
+
+            listeners.push(group);
+        }
+
+        Ok(Self {
+            listeners,
+            acceptors: config.acceptors.max(1),
+            keep_alive_timeout: config.keep_alive_timeout,
+            state: StateMap::new(),
+            errors: ErrorHandlers::new(),
+        })
+    }
+
+    pub fn from_listeners(listeners: Vec<Listener>, acceptors: usize) -> Self {
+        Self {
+            listeners: listeners.into_iter().map(|l| vec![l]).collect(),
+            acceptors: acceptors.max(1),
+            keep_alive_timeout: ServerConfig::new().keep_alive_timeout,
+            state: StateMap::new(),
+            errors: ErrorHandlers::new(),
+        }
+    }
+
+    // Hands every listening socket to a new process, see activation.rs.
+    // This process keeps accepting until it is told to stop.
+    #[cfg(unix)]
+    pub fn spawn_successor(&self, command: Command) -> io::Result<Child> {
+        let listeners: Vec<&Listener> = self.listeners.iter().flatten().collect();
+        activation::spawn_with_listeners(command, &listeners)
+    }
+
+    pub fn local_addrs(&self) -> io::Result<Vec<ListenAddr>> {
+        self.listeners
+            .iter()
+            .map(|group| group[0].local_addr())
+            .collect()
+    }
+
+    // Shared state every request starts out with, see Router::state
+    pub fn state<T: Send + Sync + 'static>(mut self, value: T) -> Self {
+        self.state.insert(value);
+        self
+    }
+
+    // Renders error responses with `status` that reach the server, which
+    // includes requests that failed to parse (400, 414, 431, 505, ...)
+    pub fn error_handler<F>(mut self, status: Status, handler: F) -> Self
+    where
+        F: Fn(&HttpError, &mut HttpResponse) + Send + Sync + 'static,
+    {
+        self.errors.insert(status, handler);
+        self
+    }
+
+    pub fn fallback_error_handler<F>(mut self, handler: F) -> Self
+    where
+        F: Fn(&HttpError, &mut HttpResponse) + Send + Sync + 'static,
+    {
+        self.errors.set_fallback(handler);
+        self
+    }
+
+    // Answers every request on every listener with `handler`
+    pub fn serve<H: Handler>(mut self, handler: H) -> io::Result<()> {
+        let app = App {
+            handler,
+            state: std::mem::take(&mut self.state),
+            errors: std::mem::take(&mut self.errors),
+            keep_alive_timeout: self.keep_alive_timeout,
+        };
+        self.run(move |stream| serve_connection(stream, &app))
+    }
+
+    pub fn run<F>(self, handler: F) -> io::Result<()>
+    where
+        F: Fn(Stream) + Send + Sync + 'static,
+    {
+        install_panic_hook();
+
+        let handler = Arc::new(handler);
+        let mut threads = Vec::new();
+
+        for group in self.listeners {
+            let mut sockets = Vec::new();
+
+            if group.len() > 1 {
+                sockets = group;
+            } else {
+                // Without SO_REUSEPORT the acceptors all share the single socket
+                for _ in 1..self.acceptors {
+                    sockets.push(group[0].try_clone()?);
+                }
+                sockets.extend(group);
+            }
+
+            for listener in sockets {
+                let handler = Arc::clone(&handler);
+                threads.push(thread::spawn(move || accept_loop(listener, handler)));
+            }
+        }
+
+        for thread in threads {
+            let _ = thread.join();
+        }
+
+        Ok(())
+    }
+}
+
+fn accept_loop<F: Fn(Stream)>(listener: Listener, handler: Arc<F>) {
+    loop {
+        match listener.accept() {
+            // A panic must not take the acceptor down with the connection
+            Ok(stream) => {
+                if let Err(panic) = catch_panic(|| handler(stream)) {
+                    eprintln!("Connection handler panicked {}", panic);
+                }
+            }
+            Err(msg) => {
+                eprintln!("Failed to accept connection. Reason: {}", msg);
+            }
+        }
+    }
+}
+
+// Most unread request body skipped to keep a connection open, larger
+// leftovers close it instead
+const MAX_DRAIN: u64 = 64 * 1024;
+
+fn serve_connection<H: Handler>(stream: Stream, app: &App<H>) {
+    let _ = stream.set_read_timeout(app.keep_alive_timeout);
+
+    let mut reader = match stream.try_clone() {
+        Ok(read_half) => BufReader::new(read_half),
+        Err(msg) => {
+            eprintln!("Failed to clone connection. Reason: {}", msg);
+            return;
+        }
+    };
+    let interim = match stream.try_clone() {
+        Ok(write_half) => Interim::new(write_half),
+        Err(msg) => {
+            eprintln!("Failed to clone connection. Reason: {}", msg);
+            return;
+        }
+    };
+    let mut writer = BufWriter::new(stream);
+
+    loop {
+        // Nothing more from the client: it closed the connection or idled out
+        match reader.fill_buf() {
+            Ok(buf) if !buf.is_empty() => {}
+            _ => return,
+        }
+
+        let mut request = match parse_request_head(&mut reader) {
+            Ok(request) => request,
+            Err(e) => {
+                eprintln!("Failed to parse request. Reason: {}", e);
+                if e.can_respond() {
+                    // The rest of the stream can't be trusted, always close
+                    let mut response = HttpResponse::from_error(HttpError::from(&e));
+                    app.errors.apply(None, &mut response);
+                    let _ = write_response(&mut writer, &mut response, WriteOptions::new());
+                }
+                return;
+            }
+        };
+
+        // The connection is lent to the request body until the handler is done
+        let framing = request.framing();
+        let mut body_reader = BodyReader::new(reader, framing.unwrap_or(Framing::Length(0)));
+
+        match expects_continue(&request) {
+            Ok(false) => {}
+            Ok(true) => match writer.get_ref().try_clone() {
+                Ok(interim) => body_reader = body_reader.expect_continue(interim),
+                Err(msg) => {
+                    eprintln!("Failed to clone connection. Reason: {}", msg);
+                    return;
+                }
+            },
+            Err(expectation) => {
+                eprintln!("Unsupported expectation {}", expectation);
+                let mut response = HttpResponse::error(Status::ExpectationFailed);
+                app.errors.apply(Some(&request), &mut response);
+                let _ = write_response(&mut writer, &mut response, WriteOptions::new());
+                return;
+            }
+        }
+
+        let body: SharedBody<_> = Arc::new(Mutex::new(Some(body_reader)));
+        if let Some(framing) = framing {
+            let length = match framing {
+                Framing::Length(length) => Some(length),
+                Framing::Chunked => None,
+            };
+            request.body = Some(RequestBody::new(Lent(Arc::clone(&body))).with_length(length));
+        }
+
+        let request_interim = interim.for_request(&request);
+        request.extensions.insert(request_interim.clone());
+
+        request.state.extend(&app.state);
+        let response = catch_panic(|| app.handler.call(&mut request));
+        request_interim.close();
+
+        let mut response = match response {
+            Ok(response) => response,
+            Err(panic) => {
+                eprintln!(
+                    "Handler panicked on {} {} {}",
+                    request.request_line.method, request.request_line.uri, panic
+                );
+                // Whatever the handler left unread of the request is lost
+                HttpResponse::error(Status::InternalServerError).header("Connection", "close")
+            }
+        };
+        app.errors.apply(Some(&request), &mut response);
+
+        // Skip the unread rest of the body so the next request starts in the
+        // right place. A body reader the handler kept around stops working.
+        let body = body.lock().unwrap_or_else(|e| e.into_inner()).take();
+        let Some(mut body) = body else {
+            return;
+        };
+        // A client still waiting for 100 Continue may or may not send the
+        // body anyway, the connection can't be reused either way
+        let drained = !body.awaiting_continue() && body.drain(MAX_DRAIN).unwrap_or(false);
+        reader = body.into_inner();
+
+        let closing = response
+            .general_headers
+            .get(GeneralHeader::Connection)
+            .is_some_and(|it| it.eq_ignore_ascii_case("close"));
+
+        let mut options = WriteOptions::for_request(&request);
+        options.keep_alive &= !closing && drained;
+
+        match write_response(&mut writer, &mut response, options) {
+            Ok(true) => {}
+            Ok(false) => return,
+            Err(msg) => {
+                eprintln!("Failed to write response. Reason: {}", msg);
+                return;
+            }
+        }
+    }
+}
+
+// Whether the client waits for 100 Continue before sending the body. Err
+// holds an expectation other than 100-continue, which is answered with 417.
+fn expects_continue(request: &HttpRequest) -> Result<bool, String> {
+    let Some(expect) = request.request_headers.get(RequestHeader::Expect) else {
+        return Ok(false);
+    };
+
+    if !expect.trim().eq_ignore_ascii_case("100-continue") {
+        return Err(expect);
+    }
+
+    // HTTP/1.0 clients don't know 100 Continue, RFC 9110 10.1.1
+    let version = (request.request_line.v_major, request.request_line.v_minor);
+    Ok(version >= (1, 1) && request.framing().is_some())
+}
+
+thread_local! {
+    // Set while catch_panic runs on this thread, the hook then records where
+    // the panic happened instead of printing it
+    static CATCHING: Cell<bool> = const { Cell::new(false) };
+    static PANIC_LOCATION: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+static PANIC_HOOK: Once = Once::new();
+
+fn install_panic_hook() {
+    PANIC_HOOK.call_once(chain_panic_hook);
+}
+
+// Wraps the current hook, which still sees panics outside of catch_panic
+fn chain_panic_hook() {
+    let previous = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        if CATCHING.with(|it| it.get()) {
+            let location = info.location().map(|it| it.to_string());
+            PANIC_LOCATION.with(|it| *it.borrow_mut() = location);
+        } else {
+            previous(info);
+        }
+    }));
+}
+
+// Runs `f`, turning an unwinding panic into "at <location>: <message>"
+fn catch_panic<T, F: FnOnce() -> T>(f: F) -> Result<T, String> {
+    let catching = CATCHING.with(|it| it.replace(true));
+    let result = panic::catch_unwind(AssertUnwindSafe(f));
+    CATCHING.with(|it| it.set(catching));
+
+    result.map_err(|payload| {
+        let location = PANIC_LOCATION
+            .with(|it| it.borrow_mut().take())
+            .unwrap_or_else(|| "unknown location".to_string());
+        format!("at {}: {}", location, panic_message(&*payload))
+    })
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message
+    } else {
+        "Box<dyn Any>"
+    }
+}
+
+fn bind_tcp_group(addr: SocketAddr, config: &ServerConfig) -> io::Result<Vec<Listener>> {
+    let mut group = vec![bind_socket(addr, config)?];
+
+    if config.reuse_port {
+        // Port 0 picks an ephemeral port on the first bind, every other
+        // acceptor socket has to join that same port
+        let bound = group[0].local_addr()?;
+        for _ in 1..config.acceptors {
+            group.push(bind_socket(bound, config)?);
+        }
+    }
+
+    Ok(group.into_iter().map(Listener::Tcp).collect())
+}
+
+fn bind_socket(addr: SocketAddr, config: &ServerConfig) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+
+    if addr.is_ipv6() {
+        socket.set_only_v6(!config.dual_stack)?;
+    }
+
+    // Matches what TcpListener::bind does so restarts don't hit TIME_WAIT
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+
+    if config.reuse_port {
+        #[cfg(unix)]
+        socket.set_reuse_port(true)?;
+
+        #[cfg(not(unix))]
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "SO_REUSEPORT is not supported on this platform",
+        ));
+    }
+
+    socket.bind(&addr.into())?;
+    socket.listen(config.backlog)?;
+
+    Ok(socket.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::test_request_with_body;
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+
+    const CONTINUE: &str = "HTTP/1.1 100 Continue\r\n\r\n";
+
+    // Serves `handler` on an ephemeral port with a single acceptor
+    fn spawn<H: Handler>(handler: H) -> SocketAddr {
+        let config = ServerConfig::new()
+            .listen(SocketAddr::from(([127, 0, 0, 1], 0)))
+            .socket_activation(false);
+        let server = Server::bind(&config).unwrap();
+        let ListenAddr::Tcp(addr) = server.local_addrs().unwrap().remove(0) else {
+            panic!("Expected a TCP listener");
+        };
+        thread::spawn(move || server.serve(handler));
+        addr
+    }
+
+    fn connect(addr: SocketAddr) -> TcpStream {
+        let stream = TcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        stream
+    }
+
+    // Everything the server sends until it closes the connection
+    fn read_all(stream: &mut TcpStream) -> String {
+        let mut output = String::new();
+        stream.read_to_string(&mut output).unwrap();
+        output
+    }
+
+    // Echoes the body, reading it in two steps
+    fn echo(request: &mut HttpRequest) -> HttpResponse {
+        let mut body = request.body.take().unwrap();
+        let mut first = [0; 1];
+        body.read_exact(&mut first).unwrap();
+        let mut rest = Vec::new();
+        body.read_to_end(&mut rest).unwrap();
+
+        let mut echoed = first.to_vec();
+        echoed.extend(rest);
+        HttpResponse::text(Status::Ok, String::from_utf8(echoed).unwrap())
+    }
+
+    fn ignore(_: &mut HttpRequest) -> HttpResponse {
+        HttpResponse::text(Status::Ok, "ignored")
+    }
+
+    #[test]
+    fn expects_continue_for_http_11_bodies_only() {
+        let expect = |value: &str, body: &[u8]| {
+            test_request_with_body("POST", "/", &[("Expect", value)], body)
+        };
+
+        assert_eq!(
+            expects_continue(&expect("100-Continue", b"hello")),
+            Ok(true)
+        );
+        assert_eq!(expects_continue(&expect("100-continue", b"")), Ok(false));
+        assert_eq!(
+            expects_continue(&expect("fancy", b"hello")),
+            Err("fancy".to_string())
+        );
+
+        let mut request = expect("100-continue", b"hello");
+        request.request_line.v_minor = 0;
+        assert_eq!(expects_continue(&request), Ok(false));
+    }
+
+    #[test]
+    fn sends_100_continue_on_the_first_read_only() {
+        let mut stream = connect(spawn(echo));
+        stream
+            .write_all(
+                b"POST / HTTP/1.1\r\nHost: x\r\nExpect: 100-continue\r\n\
+                  Content-Length: 5\r\nConnection: close\r\n\r\n",
+            )
+            .unwrap();
+
+        let mut interim = vec![0; CONTINUE.len()];
+        stream.read_exact(&mut interim).unwrap();
+        assert_eq!(interim, CONTINUE.as_bytes());
+
+        stream.write_all(b"hello").unwrap();
+        let output = read_all(&mut stream);
+        assert!(output.starts_with("HTTP/1.1 200 OK\r\n"), "{}", output);
+        assert!(output.ends_with("\r\n\r\nhello"), "{}", output);
+        assert!(!output.contains("100 Continue"), "{}", output);
+    }
+
+    #[test]
+    fn closes_when_the_body_was_never_asked_for() {
+        let mut stream = connect(spawn(ignore));
+        stream
+            .write_all(
+                b"POST / HTTP/1.1\r\nHost: x\r\nExpect: 100-continue\r\n\
+                  Content-Length: 5\r\n\r\n",
+            )
+            .unwrap();
+
+        // Without a close the read would time out
+        let output = read_all(&mut stream);
+        assert!(output.starts_with("HTTP/1.1 200 OK\r\n"), "{}", output);
+        assert!(output.contains("Connection: close\r\n"), "{}", output);
+        assert!(!output.contains("100 Continue"), "{}", output);
+    }
+
+    #[test]
+    fn answers_unknown_expectations_with_417() {
+        let mut stream = connect(spawn(echo));
+        stream
+            .write_all(b"POST / HTTP/1.1\r\nHost: x\r\nExpect: fancy\r\nContent-Length: 5\r\n\r\n")
+            .unwrap();
+
+        let output = read_all(&mut stream);
+        assert!(
+            output.starts_with("HTTP/1.1 417 Expectation Failed\r\n"),
+            "{}",
+            output
+        );
+    }
+
+    #[test]
+    fn ignores_100_continue_from_http_10_clients() {
+        let mut stream = connect(spawn(echo));
+        stream
+            .write_all(b"POST / HTTP/1.0\r\nExpect: 100-continue\r\nContent-Length: 5\r\n\r\nhello")
+            .unwrap();
+
+        let output = read_all(&mut stream);
+        assert!(output.starts_with("HTTP/1.1 200 OK\r\n"), "{}", output);
+        assert!(output.ends_with("\r\n\r\nhello"), "{}", output);
+        assert!(!output.contains("100 Continue"), "{}", output);
+    }
+
+    fn panics(request: &mut HttpRequest) -> HttpResponse {
+        if request.request_line.uri == "/panic" {
+            panic!("handler failed");
+        }
+        HttpResponse::text(Status::Ok, "fine")
+    }
+
+    #[test]
+    fn panicking_handlers_get_500_and_a_closed_connection() {
+        let addr = spawn(panics);
+
+        let mut stream = connect(addr);
+        stream
+            .write_all(b"GET /panic HTTP/1.1\r\nHost: x\r\n\r\n")
+            .unwrap();
+        let output = read_all(&mut stream);
+        assert!(
+            output.starts_with("HTTP/1.1 500 Internal Server Error\r\n"),
+            "{}",
+            output
+        );
+        assert!(output.contains("Connection: close\r\n"), "{}", output);
+
+        // The only acceptor survived it
+        let mut stream = connect(addr);
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        let output = read_all(&mut stream);
+        assert!(output.starts_with("HTTP/1.1 200 OK\r\n"), "{}", output);
+        assert!(output.ends_with("fine"), "{}", output);
+    }
+
+    #[test]
+    fn forwards_other_panics_to_the_previous_hook() {
+        static FORWARDED: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+        // Records the panics meant for this test and passes everything on
+        let previous = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            let message = panic_message(info.payload()).to_string();
+            if message.starts_with("forward test") {
+                FORWARDED.lock().unwrap().push(message);
+            }
+            previous(info);
+        }));
+        chain_panic_hook();
+
+        let caught = catch_panic(|| panic!("forward test: caught"));
+        assert!(caught.unwrap_err().contains("server.rs"));
+
+        let thread = thread::spawn(|| panic!("forward test: elsewhere"));
+        assert!(thread.join().is_err());
+
+        assert_eq!(*FORWARDED.lock().unwrap(), ["forward test: elsewhere"]);
+    }
+
+    // The server keeps the sockets open while it lives
+    fn bind(config: ServerConfig) -> io::Result<(Server, SocketAddr)> {
+        let server = Server::bind(&config.socket_activation(false))?;
+        match server.local_addrs()?.remove(0) {
+            ListenAddr::Tcp(addr) => Ok((server, addr)),
+            addr => panic!("Expected a TCP listener, got {:?}", addr),
+        }
+    }
+
+    #[test]
+    fn reports_the_ephemeral_port() {
+        let (_server, addr) =
+            bind(ServerConfig::new().listen(SocketAddr::from(([127, 0, 0, 1], 0)))).unwrap();
+        assert_eq!(addr.ip(), std::net::Ipv4Addr::LOCALHOST);
+        assert_ne!(addr.port(), 0);
+        assert!(TcpStream::connect(addr).is_ok());
+    }
+
+    #[test]
+    fn binds_ipv4_and_ipv6_wildcards_side_by_side() {
+        let (_v4, addr) =
+            bind(ServerConfig::new().listen(SocketAddr::from(([0, 0, 0, 0], 0)))).unwrap();
+        let v6 = SocketAddr::from(([0u16; 8], addr.port()));
+
+        let dual_stack = bind(ServerConfig::new().listen(v6).dual_stack(true));
+        assert_eq!(dual_stack.err().unwrap().kind(), io::ErrorKind::AddrInUse);
+
+        let (_v6, addr) = bind(ServerConfig::new().listen(v6)).unwrap();
+        assert_eq!(addr, v6);
+    }
+}
@@ -0,0 +1,779 @@
+use crate::compression::{add_vary, preferred_encoding};
+use crate::conditional::{check_preconditions, EntityTag, Validators};
+use crate::date::HttpDate;
+use crate::error::{escape_html, escape_json};
+use crate::handler::Handler;
+use crate::mime::{with_charset, MimeTypes};
+use crate::models::{HttpRequest, Method, RequestHeader};
+use crate::negotiate::negotiate;
+use crate::parsing::uri::remove_dot_segments;
+use crate::parsing::urlencoded::{percent_decode, percent_encode};
+use crate::range::apply_ranges;
+use crate::response::{Body, HttpResponse, IntoResponse, Status};
+use crate::router::OriginalUri;
+use std::fs::{self, File, Metadata};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+// Serves the files under a root directory, with the request path naming
+// the file. Mount it on a rest route so the path is relative to the mount:
+//
+//     Router::new().nest("/assets", Router::new().get("/*path", StaticFiles::new("public")))
+//
+// Paths are percent-decoded per segment after dot-segment removal, and
+// anything resolving outside the root, symlinks included, is not found,
+// as are hidden files unless show_hidden is on.
+// Answers conditional and Range requests itself. Directories without an
+// index are not found unless listings are on.
+pub struct StaticFiles {
+    root: PathBuf,
+    index: Option<String>,
+    mime_types: MimeTypes,
+    listings: bool,
+    show_hidden: bool,
+    precompressed: bool,
+}
+
+// Content codings with the file name suffix of their sidecars, best first
+const SIDECARS: &[(&str, &str)] = &[("br", "br"), ("gzip", "gz")];
+
+impl StaticFiles {
+    pub fn new<P: Into<PathBuf>>(root: P) -> Self {
+        Self {
+            root: root.into(),
+            index: Some("index.html".to_string()),
+            mime_types: MimeTypes::new(),
+            listings: false,
+            show_hidden: false,
+            precompressed: false,
+        }
+    }
+
+    // The file served for a directory, None to not serve directories
+    pub fn index(mut self, index: Option<&str>) -> Self {
+        self.index = index.map(str::to_string);
+        self
+    }
+
+    // Lists the content of directories without an index, as HTML or JSON
+    // depending on Accept
+    pub fn listings(mut self, listings: bool) -> Self {
+        self.listings = listings;
+        self
+    }
+
+    // Whether names starting with a dot, like .env or .git, are served and
+    // listed. Off by default, they are not found then.
+    pub fn show_hidden(mut self, show_hidden: bool) -> Self {
+        self.show_hidden = show_hidden;
+        self
+    }
+
+    // Serves app.js.br or app.js.gz in place of app.js when they exist and
+    // the client accepts the coding, so assets can be compressed once at
+    // build time with the slowest settings
+    pub fn precompressed(mut self, precompressed: bool) -> Self {
+        self.precompressed = precompressed;
+        self
+    }
+
+    // How Content-Type is picked, e.g. with sniffing or extra extensions
+    pub fn mime_types(mut self, mime_types: MimeTypes) -> Self {
+        self.mime_types = mime_types;
+        self
+    }
+
+    // Maps a request path onto a file system path below the root. Err is the
+    // status to answer with.
+    pub fn resolve(&self, request_path: &str) -> Result<PathBuf, Status> {
+        let root = fs::canonicalize(&self.root).map_err(|e| io_status(&e))?;
+        let mut path = root.clone();
+
+        for segment in remove_dot_segments(request_path).split('/') {
+            let segment = percent_decode(segment, false).map_err(|_| Status::BadRequest)?;
+
+            // Encoded dots and separators would otherwise sneak past the
+            // dot-segment removal above
+            if segment == "."
+                || segment == ".."
+                || segment.contains(['/', '\\', '\0'])
+                || Path::new(&segment).is_absolute()
+            {
+                return Err(Status::NotFound);
+            }
+
+            if !segment.is_empty() {
+                path.push(segment);
+            }
+        }
+
+        // Resolves symlinks, a link pointing out of the root is refused
+        let path = fs::canonicalize(&path).map_err(|e| io_status(&e))?;
+        let Ok(relative) = path.strip_prefix(&root) else {
+            return Err(Status::NotFound);
+        };
+
+        // Checked after resolving so a link can't lead to a hidden file either
+        let hidden = relative
+            .components()
+            .any(|it| it.as_os_str().as_encoded_bytes().starts_with(b"."));
+        if hidden && !self.show_hidden {
+            return Err(Status::NotFound);
+        }
+
+        Ok(path)
+    }
+
+    fn serve(&self, request: &HttpRequest) -> Result<HttpResponse, Status> {
+        let request_path = request.request_line.path();
+        let mut path = self.resolve(request_path)?;
+        let mut metadata = fs::metadata(&path).map_err(|e| io_status(&e))?;
+
+        if metadata.is_dir() {
+            // Relative links in the index resolve against the directory
+            // only with the trailing slash
+            if !request_path.ends_with('/') {
+                return Ok(redirect_to_directory(request));
+            }
+
+            let index = self.index.as_ref().and_then(|index| {
+                self.resolve(&format!("{}{}", request_path, index))
+                    .ok()
+                    .filter(|it| it.is_file())
+            });
+
+            match index {
+                Some(index) => path = index,
+                None if self.listings => return self.listing(request, &path),
+                None => return Err(Status::NotFound),
+            }
+            metadata = fs::metadata(&path).map_err(|e| io_status(&e))?;
+        }
+
+        if !metadata.is_file() {
+            return Err(Status::NotFound);
+        }
+
+        // The type is the original file's even when a sidecar is sent
+        let mut file = File::open(&path).map_err(|e| io_status(&e))?;
+        let content_type = self
+            .mime_types
+            .content_type(&path, &mut file)
+            .map_err(|e| io_status(&e))?;
+
+        let sidecars = if self.precompressed {
+            self.sidecars(&path)?
+        } else {
+            Vec::new()
+        };
+
+        let available: Vec<&str> = sidecars.iter().map(|(coding, ..)| *coding).collect();
+        let accept_encoding = request.request_headers.get(RequestHeader::AcceptEncoding);
+        let encoding = preferred_encoding(accept_encoding.as_deref(), &available)
+            .filter(|it| *it != "identity");
+
+        if let Some((_, sidecar, sidecar_metadata)) = sidecars
+            .into_iter()
+            .find(|(coding, ..)| Some(*coding) == encoding)
+        {
+            file = File::open(&sidecar).map_err(|e| io_status(&e))?;
+            metadata = sidecar_metadata;
+        }
+
+        // Each encoding is a representation of its own with its own tag
+        let mut validators = validators(&metadata);
+        if let (Some(encoding), Some(etag)) = (encoding, validators.etag.as_mut()) {
+            etag.tag = format!("{}-{}", etag.tag, encoding);
+        }
+
+        let mut response = match check_preconditions(request, &validators) {
+            Some(response) => response,
+            None => {
+                let mut response = HttpResponse::new(Status::Ok)
+                    .header("Content-Type", &content_type)
+                    .body(Body::seekable(file, metadata.len()));
+                if let Some(encoding) = encoding {
+                    let _ = response.insert_header("Content-Encoding", encoding);
+                }
+                validators.apply(&mut response);
+                response
+            }
+        };
+
+        if !available.is_empty() {
+            add_vary(&mut response, "Accept-Encoding");
+        }
+
+        apply_ranges(request, &mut response);
+        Ok(response)
+    }
+
+    // The precompressed variants of `path` that exist, best first, as
+    // coding, path and metadata
+    fn sidecars(&self, path: &Path) -> Result<Vec<(&'static str, PathBuf, Metadata)>, Status> {
+        let root = fs::canonicalize(&self.root).map_err(|e| io_status(&e))?;
+
+        Ok(SIDECARS
+            .iter()
+            .filter_map(|(coding, suffix)| {
+                let mut sidecar = path.as_os_str().to_owned();
+                sidecar.push(".");
+                sidecar.push(suffix);
+
+                // A symlinked sidecar has to stay inside the root too
+                let sidecar = fs::canonicalize(sidecar).ok()?;
+                let metadata = fs::metadata(&sidecar).ok()?;
+                (sidecar.starts_with(&root) && metadata.is_file())
+                    .then_some((*coding, sidecar, metadata))
+            })
+            .collect())
+    }
+
+    fn listing(&self, request: &HttpRequest, dir: &Path) -> Result<HttpResponse, Status> {
+        let media = match negotiate(request, &["text/html", "application/json"]) {
+            Ok(media) => media,
+            Err(e) => return Ok(e.into_response()),
+        };
+
+        let root = fs::canonicalize(&self.root).map_err(|e| io_status(&e))?;
+        let mut entries = Vec::new();
+
+        for entry in fs::read_dir(dir).map_err(|e| io_status(&e))? {
+            let Ok(entry) = entry else {
+                continue;
+            };
+            // Names that aren't UTF-8 couldn't be requested anyway
+            let Ok(name) = entry.file_name().into_string() else {
+                continue;
+            };
+            if name.starts_with('.') && !self.show_hidden {
+                continue;
+            }
+
+            // Symlinks are listed with what they point to, if that is in the root
+            let Ok(target) = fs::canonicalize(entry.path()) else {
+                continue;
+            };
+            let Ok(metadata) = fs::metadata(&target) else {
+                continue;
+            };
+            if !target.starts_with(&root) {
+                continue;
+            }
+
+            entries.push(Entry {
+                name,
+                is_dir: metadata.is_dir(),
+                size: metadata.len(),
+                modified: metadata.modified().ok().map(HttpDate::from),
+            });
+        }
+
+        // Directories first, each group by name
+        entries.sort_by(|a, b| b.is_dir.cmp(&a.is_dir).then_with(|| a.name.cmp(&b.name)));
+
+        let path = original_uri(request);
+        let path = path.split('?').next().unwrap_or_default();
+        let path = percent_decode(path, false).unwrap_or_else(|_| path.to_string());
+
+        let body = match media {
+            "application/json" => listing_json(&path, &entries),
+            // No way up from the root, even when it is mounted deeper
+            _ => listing_html(&path, &entries, request.request_line.path() != "/"),
+        };
+
+        Ok(HttpResponse::new(Status::Ok)
+            .header("Content-Type", &with_charset(media))
+            .header("Vary", "Accept")
+            .body(body))
+    }
+}
+
+struct Entry {
+    name: String,
+    is_dir: bool,
+    size: u64,
+    modified: Option<HttpDate>,
+}
+
+fn listing_html(path: &str, entries: &[Entry], parent: bool) -> String {
+    let title = escape_html(&format!("Index of {}", path));
+    let mut rows = String::new();
+
+    if parent {
+        rows.push_str("<tr><td><a href=\"../\">../</a></td><td></td><td></td></tr>\n");
+    }
+
+    for entry in entries {
+        let suffix = if entry.is_dir { "/" } else { "" };
+        let size = if entry.is_dir {
+            "-".to_string()
+        } else {
+            entry.size.to_string()
+        };
+        let modified = entry.modified.map(|it| it.to_string()).unwrap_or_default();
+
+        rows.push_str(&format!(
+            "<tr><td><a href=\"{}{}\">{}{}</a></td><td>{}</td><td>{}</td></tr>\n",
+            percent_encode(&entry.name),
+            suffix,
+            escape_html(&entry.name),
+            suffix,
+            size,
+            modified
+        ));
+    }
+
+    format!(
+        "<!DOCTYPE html>\n<html>\n<head><title>{}</title></head>\n<body>\n<h1>{}</h1>\n<table>\n<tr><th>Name</th><th>Size</th><th>Modified</th></tr>\n{}</table>\n</body>\n</html>\n",
+        title, title, rows
+    )
+}
+
+fn listing_json(path: &str, entries: &[Entry]) -> String {
+    let entries: Vec<String> = entries
+        .iter()
+        .map(|entry| {
+            let (kind, size) = if entry.is_dir {
+                ("directory", "null".to_string())
+            } else {
+                ("file", entry.size.to_string())
+            };
+            let modified = entry
+                .modified
+                .map_or("null".to_string(), |it| format!("\"{}\"", it));
+
+            format!(
+                "{{\"name\":\"{}\",\"type\":\"{}\",\"size\":{},\"modified\":{}}}",
+                escape_json(&entry.name),
+                kind,
+                size,
+                modified
+            )
+        })
+        .collect();
+
+    format!(
+        "{{\"path\":\"{}\",\"entries\":[{}]}}\n",
+        escape_json(path),
+        entries.join(",")
+    )
+}
+
+impl Handler for StaticFiles {
+    fn call(&self, request: &mut HttpRequest) -> HttpResponse {
+        if !matches!(request.request_line.method, Method::GET | Method::HEAD) {
+            return HttpResponse::error(Status::MethodNotAllowed).header("Allow", "GET, HEAD");
+        }
+
+        self.serve(request).unwrap_or_else(HttpResponse::error)
+    }
+}
+
+fn io_status(error: &io::Error) -> Status {
+    match error.kind() {
+        io::ErrorKind::NotFound | io::ErrorKind::NotADirectory => Status::NotFound,
+        io::ErrorKind::PermissionDenied => Status::Forbidden,
+        _ => Status::InternalServerError,
+    }
+}
+
+// The target the client sent, which differs from the request line inside
+// a nested router
+fn original_uri(request: &HttpRequest) -> &str {
+    request
+        .extensions
+        .get::<OriginalUri>()
+        .map_or(request.request_line.uri.as_str(), |it| it.0.as_str())
+}
+
+// Directory URLs end with a slash, see serve
+fn redirect_to_directory(request: &HttpRequest) -> HttpResponse {
+    let uri = original_uri(request);
+
+    let location = match uri.split_once('?') {
+        Some((path, query)) => format!("{}/?{}", path, query),
+        None => format!("{}/", uri),
+    };
+
+    HttpResponse::new(Status::MovedPermanently).header("Location", &location)
+}
+
+// A strong tag from size and modification time, which changes whenever the
+// file is written to in practice
+fn validators(metadata: &Metadata) -> Validators {
+    let mut validators = Validators::new();
+
+    if let Ok(modified) = metadata.modified() {
+        let since_epoch = modified.duration_since(UNIX_EPOCH).unwrap_or_default();
+        validators = validators
+            .last_modified(modified)
+            .etag(EntityTag::strong(format!(
+                "{:x}-{:x}.{:x}",
+                metadata.len(),
+                since_epoch.as_secs(),
+                since_epoch.subsec_nanos()
+            )));
+    }
+
+    validators
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::test_request;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    // A scratch directory holding `root` plus a file next to it, removed
+    // when dropped
+    struct Scratch {
+        base: PathBuf,
+    }
+
+    impl Scratch {
+        fn new() -> Self {
+            static NEXT: AtomicU32 = AtomicU32::new(0);
+            let base = std::env::temp_dir().join(format!(
+                "static-files-{}-{}",
+                std::process::id(),
+                NEXT.fetch_add(1, Ordering::Relaxed)
+            ));
+
+            fs::create_dir_all(base.join("root/sub")).unwrap();
+            fs::write(base.join("root/sub/a.txt"), "a").unwrap();
+            fs::write(base.join("outside.txt"), "secret").unwrap();
+            Self { base }
+        }
+
+        fn root(&self) -> PathBuf {
+            self.base.join("root")
+        }
+
+        fn files(&self) -> StaticFiles {
+            StaticFiles::new(self.root())
+        }
+    }
+
+    impl Drop for Scratch {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.base);
+        }
+    }
+
+    fn get(files: &StaticFiles, target: &str) -> HttpResponse {
+        files.call(&mut test_request("GET", target, &[]))
+    }
+
+    fn get_encoded(files: &StaticFiles, target: &str, accept_encoding: &str) -> HttpResponse {
+        files.call(&mut test_request(
+            "GET",
+            target,
+            &[("Accept-Encoding", accept_encoding)],
+        ))
+    }
+
+    fn body(response: HttpResponse) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        match response.body {
+            Body::Seekable { mut reader, .. } => {
+                io::Read::read_to_end(&mut reader, &mut bytes).unwrap();
+            }
+            Body::Bytes(body) => bytes = body,
+            body => panic!("Unexpected body {:?}", body),
+        }
+        bytes
+    }
+
+    // app.js with both sidecars, served precompressed
+    fn precompressed(scratch: &Scratch) -> StaticFiles {
+        fs::write(scratch.root().join("app.js"), "console.log(1)").unwrap();
+        fs::write(scratch.root().join("app.js.br"), "brotli").unwrap();
+        fs::write(scratch.root().join("app.js.gz"), "gzip").unwrap();
+        scratch.files().precompressed(true)
+    }
+
+    #[test]
+    fn resolves_files_below_the_root() {
+        let scratch = Scratch::new();
+        let expected = fs::canonicalize(scratch.root().join("sub/a.txt")).unwrap();
+
+        assert_eq!(scratch.files().resolve("/sub/a.txt"), Ok(expected.clone()));
+        assert_eq!(
+            scratch.files().resolve("/sub/./x/../a.txt"),
+            Ok(expected.clone())
+        );
+        assert_eq!(scratch.files().resolve("/%73ub/a.txt"), Ok(expected));
+    }
+
+    #[test]
+    fn dot_segments_stay_inside_the_root() {
+        let scratch = Scratch::new();
+        let files = scratch.files();
+
+        assert_eq!(files.resolve("/../outside.txt"), Err(Status::NotFound));
+        assert_eq!(
+            files.resolve("/sub/../../outside.txt"),
+            Err(Status::NotFound)
+        );
+    }
+
+    #[test]
+    fn refuses_encoded_dots_and_separators() {
+        let scratch = Scratch::new();
+        let files = scratch.files();
+
+        assert_eq!(
+            files.resolve("/sub/%2e%2e/%2e%2e/outside.txt"),
+            Err(Status::NotFound)
+        );
+        assert_eq!(files.resolve("/sub/%2E%2E/a.txt"), Err(Status::NotFound));
+        assert_eq!(files.resolve("/sub/%2e/a.txt"), Err(Status::NotFound));
+        assert_eq!(
+            files.resolve("/sub/..%2f..%2foutside.txt"),
+            Err(Status::NotFound)
+        );
+        assert_eq!(files.resolve("/sub%2fa.txt"), Err(Status::NotFound));
+        assert_eq!(
+            files.resolve("/sub\\..\\..\\outside.txt"),
+            Err(Status::NotFound)
+        );
+        assert_eq!(
+            files.resolve("/sub%5c..%5c..%5coutside.txt"),
+            Err(Status::NotFound)
+        );
+        assert_eq!(files.resolve("/sub/a.txt%00.png"), Err(Status::NotFound));
+        assert_eq!(files.resolve("/%zz"), Err(Status::BadRequest));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn refuses_symlinks_out_of_the_root() {
+        use std::os::unix::fs::symlink;
+
+        let scratch = Scratch::new();
+        symlink("../outside.txt", scratch.root().join("escape.txt")).unwrap();
+        symlink(scratch.base.clone(), scratch.root().join("escape-dir")).unwrap();
+        symlink("sub/a.txt", scratch.root().join("inside.txt")).unwrap();
+        let files = scratch.files();
+
+        assert_eq!(files.resolve("/escape.txt"), Err(Status::NotFound));
+        assert_eq!(
+            files.resolve("/escape-dir/outside.txt"),
+            Err(Status::NotFound)
+        );
+        assert_eq!(
+            files.resolve("/inside.txt"),
+            Ok(fs::canonicalize(scratch.root().join("sub/a.txt")).unwrap())
+        );
+        assert_eq!(get(&files, "/escape.txt").status, Status::NotFound);
+    }
+
+    #[test]
+    fn hidden_files_are_not_found_unless_shown() {
+        let scratch = Scratch::new();
+        fs::create_dir_all(scratch.root().join(".git")).unwrap();
+        fs::write(scratch.root().join(".git/config"), "[core]").unwrap();
+        fs::write(scratch.root().join(".env"), "SECRET=1").unwrap();
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(".env", scratch.root().join("env")).unwrap();
+
+        let files = scratch.files();
+        for target in ["/.env", "/.git/config", "/%2eenv", "/sub/../.env", "/.git/"] {
+            assert_eq!(get(&files, target).status, Status::NotFound, "{}", target);
+        }
+        #[cfg(unix)]
+        assert_eq!(get(&files, "/env").status, Status::NotFound);
+
+        let files = scratch.files().show_hidden(true);
+        assert_eq!(get(&files, "/.env").status, Status::Ok);
+        assert_eq!(get(&files, "/.git/config").status, Status::Ok);
+        #[cfg(unix)]
+        assert_eq!(get(&files, "/env").status, Status::Ok);
+    }
+
+    #[test]
+    fn picks_sidecars_by_weight() {
+        let scratch = Scratch::new();
+        let files = precompressed(&scratch);
+
+        let response = get_encoded(&files, "/app.js", "gzip, br");
+        assert_eq!(
+            response.get_header("Content-Encoding").as_deref(),
+            Some("br")
+        );
+        assert_eq!(body(response), b"brotli");
+
+        let response = get_encoded(&files, "/app.js", "br;q=0.5, gzip");
+        assert_eq!(
+            response.get_header("Content-Encoding").as_deref(),
+            Some("gzip")
+        );
+        assert_eq!(body(response), b"gzip");
+    }
+
+    #[test]
+    fn falls_back_to_the_original() {
+        let scratch = Scratch::new();
+        let files = precompressed(&scratch);
+
+        for accept_encoding in ["gzip;q=0", "identity"] {
+            let response = get_encoded(&files, "/app.js", accept_encoding);
+            assert_eq!(response.get_header("Content-Encoding"), None);
+            assert_eq!(
+                response.get_header("Vary").as_deref(),
+                Some("Accept-Encoding")
+            );
+            assert_eq!(body(response), b"console.log(1)");
+        }
+    }
+
+    #[test]
+    fn sidecars_keep_the_original_type() {
+        let scratch = Scratch::new();
+        let files = precompressed(&scratch);
+
+        let response = get_encoded(&files, "/app.js", "gzip");
+        assert_eq!(
+            response.get_header("Content-Type").as_deref(),
+            Some("text/javascript; charset=utf-8")
+        );
+        assert_eq!(
+            response.get_header("Vary").as_deref(),
+            Some("Accept-Encoding")
+        );
+    }
+
+    #[test]
+    fn sidecars_have_their_own_etag() {
+        let scratch = Scratch::new();
+        let files = precompressed(&scratch);
+
+        let identity = get(&files, "/app.js").get_header("ETag").unwrap();
+        let gzip = get_encoded(&files, "/app.js", "gzip")
+            .get_header("ETag")
+            .unwrap();
+        let br = get_encoded(&files, "/app.js", "br")
+            .get_header("ETag")
+            .unwrap();
+
+        assert_ne!(identity, gzip);
+        assert_ne!(gzip, br);
+        assert!(gzip.ends_with("-gzip\""), "{}", gzip);
+        assert!(br.ends_with("-br\""), "{}", br);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn ignores_sidecars_linking_out_of_the_root() {
+        let scratch = Scratch::new();
+        let files = scratch.files().precompressed(true);
+        std::os::unix::fs::symlink(
+            scratch.base.join("outside.txt"),
+            scratch.root().join("sub/a.txt.gz"),
+        )
+        .unwrap();
+
+        let response = get_encoded(&files, "/sub/a.txt", "gzip");
+        assert_eq!(response.get_header("Content-Encoding"), None);
+        assert_eq!(body(response), b"a");
+    }
+
+    #[test]
+    fn missing_root_is_not_found() {
+        let scratch = Scratch::new();
+        let files = StaticFiles::new(scratch.base.join("missing"));
+
+        assert_eq!(files.resolve("/"), Err(Status::NotFound));
+        assert_eq!(get(&files, "/a.txt").status, Status::NotFound);
+    }
+
+    #[test]
+    fn redirects_directories_to_the_trailing_slash() {
+        let scratch = Scratch::new();
+        let files = scratch.files();
+
+        let response = get(&files, "/sub");
+        assert_eq!(response.status, Status::MovedPermanently);
+        assert_eq!(response.get_header("Location").as_deref(), Some("/sub/"));
+
+        let response = get(&files, "/sub?page=2");
+        assert_eq!(
+            response.get_header("Location").as_deref(),
+            Some("/sub/?page=2")
+        );
+
+        // No index and no listings
+        assert_eq!(get(&files, "/sub/").status, Status::NotFound);
+    }
+
+    #[test]
+    fn serves_files_with_metadata_headers() {
+        let scratch = Scratch::new();
+        let response = get(&scratch.files(), "/sub/a.txt");
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(
+            response.get_header("Content-Type").as_deref(),
+            Some("text/plain; charset=utf-8")
+        );
+        assert_eq!(response.body.length(), Some(1));
+        assert!(response.get_header("ETag").is_some());
+        assert!(response.get_header("Last-Modified").is_some());
+    }
+
+    fn listing(scratch: &Scratch, target: &str, accept: &str) -> String {
+        fs::create_dir_all(scratch.root().join("a-dir")).unwrap();
+        fs::write(scratch.root().join("b.txt"), "bb").unwrap();
+        fs::write(scratch.root().join("<img src=x>.txt"), "").unwrap();
+        fs::write(scratch.root().join(".hidden"), "").unwrap();
+
+        let files = scratch.files().listings(true);
+        let response = files.call(&mut test_request("GET", target, &[("Accept", accept)]));
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(response.get_header("Vary").as_deref(), Some("Accept"));
+        String::from_utf8(body(response)).unwrap()
+    }
+
+    #[test]
+    fn lists_directories_before_files() {
+        let scratch = Scratch::new();
+        let html = listing(&scratch, "/", "text/html");
+
+        let order: Vec<usize> = ["a-dir/", "sub/", "&lt;img", "b.txt"]
+            .iter()
+            .map(|name| html.find(name).unwrap())
+            .collect();
+        assert!(order.windows(2).all(|it| it[0] < it[1]), "{}", html);
+        assert!(!html.contains(".hidden"));
+    }
+
+    #[test]
+    fn escapes_names_in_listings() {
+        let scratch = Scratch::new();
+        let html = listing(&scratch, "/", "text/html");
+
+        assert!(html.contains("<a href=\"%3Cimg%20src%3Dx%3E.txt\">&lt;img src=x&gt;.txt</a>"));
+        assert!(!html.contains("<img"));
+    }
+
+    #[test]
+    fn links_to_the_parent_below_the_root_only() {
+        let scratch = Scratch::new();
+        assert!(!listing(&scratch, "/", "text/html").contains("href=\"../\""));
+        assert!(listing(&scratch, "/sub/", "text/html").contains("href=\"../\""));
+    }
+
+    #[test]
+    fn lists_as_json() {
+        let scratch = Scratch::new();
+        let json = listing(&scratch, "/", "application/json");
+
+        assert!(json.starts_with("{\"path\":\"/\",\"entries\":[{\"name\":\"a-dir\""));
+        assert!(json.contains("{\"name\":\"a-dir\",\"type\":\"directory\",\"size\":null,"));
+        assert!(json.contains("{\"name\":\"b.txt\",\"type\":\"file\",\"size\":2,"));
+        assert!(json.contains("{\"name\":\"<img src=x>.txt\",\"type\":\"file\",\"size\":0,"));
+        assert!(json.ends_with("}]}\n"));
+    }
+}